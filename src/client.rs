@@ -0,0 +1,158 @@
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    event_client, with_retry, BatchReport, Config, Error, Event, Host, KeyPair, RetryPolicy,
+};
+
+/// Typed client for the API of the server daemon. Every request carries the bearer token and
+/// failed requests are retried according to the client's retry policy.
+#[derive(Debug, Clone)]
+pub struct MeshClient {
+    /// Scheme, host and port of the server, eg. `https://mesh.example.com:64001`
+    base_url: String,
+    token: String,
+    retry: RetryPolicy,
+    client: reqwest::Client,
+}
+
+impl MeshClient {
+    /// Client for the server at `base_url`. Requests are made once; use `with_retry` to retry
+    /// failures.
+    pub fn new(base_url: &str, token: &str) -> crate::Result<Self> {
+        Ok(MeshClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            retry: RetryPolicy::no_retry(),
+            client: event_client()?,
+        })
+    }
+
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        MeshClient { retry, ..self }
+    }
+
+    /// Check the server is up
+    pub async fn ping(&self) -> crate::Result<()> {
+        self.request(Method::GET, "/ping", None).await?;
+        Ok(())
+    }
+
+    /// Network config of the server, with its secrets blanked
+    pub async fn info(&self) -> crate::Result<Config> {
+        self.get("/").await
+    }
+
+    /// Tell the server the host is online, adding it to the network if it is new
+    pub async fn connect(&self, host: &Host) -> crate::Result<()> {
+        let body = serde_json::to_vec(host)?;
+        self.request(Method::POST, "/connect", Some(body)).await?;
+        Ok(())
+    }
+
+    /// Tell the server the host is going offline
+    pub async fn disconnect(&self, host: &Host) -> crate::Result<()> {
+        let body = serde_json::to_vec(host)?;
+        self.request(Method::POST, "/disconnect", Some(body))
+            .await?;
+        Ok(())
+    }
+
+    /// Every host in the network, with their secrets blanked
+    pub async fn discover(&self) -> crate::Result<Vec<Host>> {
+        self.get("/discover").await
+    }
+
+    /// Record an event on the server, applying the change it describes
+    pub async fn send_event(&self, event: &Event) -> crate::Result<()> {
+        let body = serde_json::to_vec(event)?;
+        self.request(Method::POST, "/events", Some(body)).await?;
+        Ok(())
+    }
+
+    /// Record several events in one request, oldest first. The report lists the events the
+    /// server accepted and the ones it rejected.
+    pub async fn send_events(&self, events: &[Event]) -> crate::Result<BatchReport> {
+        let mut events: Vec<&Event> = events.iter().collect();
+        events.sort_by_key(|event| event.created_at());
+        let body = serde_json::to_vec(&events)?;
+        let report = self
+            .request(Method::POST, "/events/batch", Some(body))
+            .await?;
+        Ok(serde_json::from_str(&report)?)
+    }
+
+    /// Events recorded by the server, newest first
+    pub async fn list_events(&self) -> crate::Result<Vec<Event>> {
+        self.get("/events").await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> crate::Result<T> {
+        let body = self.request(Method::GET, path, None).await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Make a request, retrying failures, and return the body of the response
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> crate::Result<String> {
+        let url = format!("{}{}", self.base_url, path);
+        let action = format!("{} {}", method, url);
+        let (method, url, body, action) = (&method, &url, &body, &action);
+        with_retry(&self.retry, action, || async move {
+            let mut request = self
+                .client
+                .request(method.clone(), url)
+                .header(AUTHORIZATION, format!("Bearer {}", self.token));
+            if let Some(body) = body {
+                request = request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+            }
+            let response = request.send().await?;
+            let status = response.status();
+            let text = response.text().await?;
+            if !status.is_success() {
+                return Err(Error::Server {
+                    action: action.to_owned(),
+                    status: status.as_u16(),
+                    body: text,
+                });
+            }
+            Ok(text)
+        })
+        .await
+    }
+}
+
+/// Join the network run by the server at `url`. The local machine gets new keys and the next
+/// free address in the network, is announced to the server with `/connect`, and the returned
+/// config peers with every host from `/discover`. Use an `https://` url to reach a server
+/// behind TLS.
+pub async fn join(url: &str, token: &str) -> crate::Result<Config> {
+    let client = MeshClient::new(url, token)?;
+    let mut config = client.info().await?;
+
+    let keys = KeyPair::generate(false)?;
+    let mut host = Host::local()?;
+    host.private_key = keys.private_key;
+    host.public_key = keys.public_key;
+    host.wireguard_address = config.next_available_address()?;
+
+    // the server only ever gets to see the public half of the keys
+    client.connect(&host.without_secrets()).await?;
+    let roster = client.discover().await?;
+
+    config.remote_hosts = roster
+        .into_iter()
+        .filter(|remote| remote.wireguard_address != host.wireguard_address)
+        .map(|remote| (remote.wireguard_address, remote))
+        .collect();
+    config.host = host;
+    config.api_token = token.to_string();
+    Ok(config)
+}
@@ -0,0 +1,114 @@
+/// Lines of context kept around each change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Keep,
+    Remove,
+    Add,
+}
+
+/// Line by line edit script turning `old` into `new`, from their longest common subsequence.
+/// Quadratic, which is fine for the config sized files this is used on.
+fn edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Edit, &'a str)> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0_usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            out.push((Edit::Keep, old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            // removals first, like diff -u
+            out.push((Edit::Remove, old[i]));
+            i += 1;
+        } else {
+            out.push((Edit::Add, new[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Unified diff of two texts with `diff -u` style headers. Empty when they are the same.
+pub fn unified(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let script = edits(&old_lines, &new_lines);
+    if script.iter().all(|(edit, _)| *edit == Edit::Keep) {
+        return String::new();
+    }
+
+    // group changes closer than twice the context into the same hunk
+    let changed: Vec<usize> = script
+        .iter()
+        .enumerate()
+        .filter(|(_, (edit, _))| *edit != Edit::Keep)
+        .map(|(i, _)| i)
+        .collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for i in changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(script.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunks {
+        // line numbers where the hunk starts, counted from the edits before it
+        let old_start = script[..start]
+            .iter()
+            .filter(|(edit, _)| *edit != Edit::Add)
+            .count();
+        let new_start = script[..start]
+            .iter()
+            .filter(|(edit, _)| *edit != Edit::Remove)
+            .count();
+        let hunk = &script[start..end];
+        let old_len = hunk.iter().filter(|(edit, _)| *edit != Edit::Add).count();
+        let new_len = hunk
+            .iter()
+            .filter(|(edit, _)| *edit != Edit::Remove)
+            .count();
+        // empty ranges point at the line before them, as in diff -u
+        let old_start = if old_len == 0 {
+            old_start
+        } else {
+            old_start + 1
+        };
+        let new_start = if new_len == 0 {
+            new_start
+        } else {
+            new_start + 1
+        };
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_len, new_start, new_len
+        ));
+        for (edit, line) in hunk {
+            let prefix = match edit {
+                Edit::Keep => ' ',
+                Edit::Remove => '-',
+                Edit::Add => '+',
+            };
+            out.push(prefix);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
@@ -0,0 +1,182 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use ipnet::IpNet;
+
+/// Errors returned by the library, so embedders can tell failures apart. The CLI turns them
+/// into messages at its boundary.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid host name \"{name}\": {reason}")]
+    InvalidName { name: String, reason: String },
+    #[error("host with name \"{0}\" already exists")]
+    NameCollision(String),
+    #[error("address {address} is already used by host \"{host}\"")]
+    AddressCollision { address: IpAddr, host: String },
+    #[error("host \"{host}\" has the same public key as host \"{existing}\"")]
+    PublicKeyCollision { host: String, existing: String },
+    #[error("address {address} is outside of subnet {subnet}")]
+    OutOfSubnet { address: IpAddr, subnet: IpNet },
+    #[error("no addresses left in subnet {0}")]
+    SubnetFull(IpNet),
+    #[error("no host named \"{0}\"")]
+    HostNotFound(String),
+    /// A network from another file could not be merged, with every collision found
+    #[error("unable to merge networks: {}", .0.join(", "))]
+    MergeConflict(Vec<String>),
+    #[error("subnets {0} and {1} overlap without being the same")]
+    OverlappingSubnets(IpNet, IpNet),
+    #[error("private key must be 32 base64-encoded bytes")]
+    InvalidKey,
+    #[error("global id {0:#x} is wider than 40 bits")]
+    GlobalIdTooWide(u64),
+    #[error("invalid prefix length")]
+    InvalidPrefixLen(#[from] ipnet::PrefixLenError),
+    #[error("invalid network \"{0}\"")]
+    InvalidNetwork(String),
+    #[error("invalid interface name \"{0}\", use up to 15 letters, digits or _=+.-")]
+    InvalidInterfaceName(String),
+    #[error("invalid endpoint \"{endpoint}\": {reason}")]
+    InvalidEndpoint {
+        endpoint: String,
+        reason: &'static str,
+    },
+    #[error("invalid tag \"{tag}\": {reason}")]
+    InvalidTag { tag: String, reason: &'static str },
+    #[error("mtu {mtu} is outside the valid range {min}-{max}")]
+    InvalidMtu { mtu: u32, min: u32, max: u32 },
+    /// Host built without a name
+    #[error("host name is required")]
+    MissingHostName,
+    #[error("host \"{0}\" needs a wireguard address")]
+    MissingAddress(String),
+    /// Command line argument that is required but was not given
+    #[error("{0} argument not provided")]
+    MissingArgument(&'static str),
+    #[error("invalid {name} argument \"{value}\"")]
+    InvalidArgument { name: &'static str, value: String },
+    #[error("unknown output format \"{0}\"")]
+    UnknownOutputFormat(String),
+    #[error("unknown log format \"{0}\"")]
+    UnknownLogFormat(String),
+
+    /// Peers whose allowed ips overlap, since wireguard can only send a network to one peer
+    #[error("allowed ips of \"{host}\" ({network}) and \"{other}\" ({other_network}) overlap")]
+    OverlappingAllowedIps {
+        host: String,
+        network: IpNet,
+        other: String,
+        other_network: IpNet,
+    },
+    #[error("hub \"{0}\" is not in the network")]
+    HubNotFound(String),
+    #[error("clients can only route through one gateway, found another in \"{0}\"")]
+    MultipleGateways(String),
+
+    #[error("{0} already exists, use --force to overwrite it")]
+    AlreadyExists(String),
+    #[error("{path} has no network \"{network}\"")]
+    NetworkNotFound { path: String, network: String },
+    #[error("{path} holds several networks, pick one with --network: {}", .networks.join(", "))]
+    NetworkNotChosen { path: String, networks: Vec<String> },
+    #[error("config version {found} is not supported, this build reads up to {supported}")]
+    UnsupportedVersion {
+        found: String,
+        supported: &'static str,
+    },
+    /// Config that parses but does not have the layout of a config, eg. a list at the top
+    #[error("malformed config: {0}")]
+    MalformedConfig(String),
+    #[error("timed out after {}s waiting for another process to release {path}", .timeout.as_secs())]
+    LockTimeout { path: String, timeout: Duration },
+
+    /// Line of a wg-quick config that is neither a section header nor a `key = value` pair
+    #[error("line {0}: expected key = value")]
+    WgConfSyntax(usize),
+    #[error("invalid {key} \"{value}\"")]
+    InvalidWgValue { key: String, value: String },
+    #[error("no [{0}] section")]
+    MissingSection(&'static str),
+    #[error("[{section}] has no {key}")]
+    MissingWgKey {
+        section: &'static str,
+        key: &'static str,
+    },
+    #[error("peer \"{name}\" has no address in {subnet}")]
+    PeerOutsideSubnet { name: String, subnet: IpNet },
+    #[error("unexpected wg dump line \"{0}\"")]
+    InvalidWgDump(String),
+    /// Output of `ip` or `ifconfig` that does not describe an interface
+    #[error("unable to parse interface: {0}")]
+    InvalidInterface(String),
+
+    #[error("unable to resolve {0}")]
+    Unresolvable(String),
+    #[error("no response from STUN server {server} after {attempts} attempts")]
+    StunTimeout { server: String, attempts: usize },
+    #[error("invalid STUN response: {0}")]
+    InvalidStunResponse(&'static str),
+
+    /// External tool such as `wg` or `wg-quick` exiting with an error
+    #[error("{command} failed with {status}: {stderr}")]
+    CommandFailed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("interface {interface} is already up, run `wgmesh down` first: {stderr}")]
+    InterfaceUp { interface: String, stderr: String },
+
+    /// Request the server answered with an error status
+    #[error("{action} failed with {status}: {body}")]
+    Server {
+        action: String,
+        status: u16,
+        body: String,
+    },
+    #[error("giving up {action} after {attempts} attempt(s): {source}")]
+    RetriesExhausted {
+        action: String,
+        attempts: u32,
+        source: Box<Error>,
+    },
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error("can not write to {path}: {source}")]
+    LogFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    InvalidLogLevel(#[from] log::ParseLevelError),
+    #[error(transparent)]
+    Logger(#[from] log::SetLoggerError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    TomlDe(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlSer(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Fmt(#[from] std::fmt::Error),
+    #[error("output is not valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    Clock(#[from] std::time::SystemTimeError),
+    #[error(transparent)]
+    Uuid(#[from] uuid::Error),
+    #[error(transparent)]
+    Qr(#[from] qrcode::types::QrError),
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -1,25 +1,29 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::net::{IpAddr, Ipv6Addr};
-use std::process::{Command, Stdio};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::process::Command;
 use std::str::FromStr;
+use std::time::Duration;
 
 use chrono::offset::Utc;
-use chrono::DateTime;
+use chrono::{DateTime, NaiveDateTime};
 use ipnet::{IpNet, Ipv6Net};
 use lazy_static::lazy_static;
 use rand;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
-use crate::uuidv1;
+use crate::{wgconf, Error};
 
 lazy_static! {
     pub static ref IFACE_ADDR_RE: Regex =
-        Regex::new(r"inet (\d+\.\d+\.\d+\.\d+/\d+)|inet6 (([0-9a-f]:*)+/\d+)").unwrap();
+        Regex::new(r"inet (\d+\.\d+\.\d+\.\d+/\d+)|inet6 ([0-9a-f:]+/\d+)").unwrap();
     pub static ref IFACE_NAME: Regex = Regex::new(r"^\d+: ([0-9a-zA-Z\-@]+)").unwrap();
     pub static ref IFACE_STATE: Regex = Regex::new(r"state (\w+)").unwrap();
     pub static ref IFACE_MAC: Regex = Regex::new(r"link/\w+ (([0-9a-f]{2}:?){6})").unwrap();
+    /// DNS label: letters, digits and inner hyphens, at most 63 characters
+    pub static ref HOST_NAME: Regex =
+        Regex::new(r"^[0-9a-zA-Z]([0-9a-zA-Z\-]{0,61}[0-9a-zA-Z])?$").unwrap();
 }
 
 /// Create a random ipv6 address in the unique local scope. Follows RFC 4193 reccomendations.
@@ -35,14 +39,14 @@ pub fn generate_ipv6(
     global_id: Option<u64>,
     subnet_id: Option<u16>,
     iface_id: Option<u64>,
-) -> anyhow::Result<Ipv6Addr> {
+) -> crate::Result<Ipv6Addr> {
     let base_prefix: u16 = 0xfc00;
     let global_id: u64 = global_id.unwrap_or_default();
-    if global_id >= 40_u64.pow(2) {
-        return Err(anyhow::anyhow!("global_id may only be 40 bits wide"));
+    if global_id >= 1 << 40 {
+        return Err(Error::GlobalIdTooWide(global_id));
     }
     let subnet_id: u16 = subnet_id.unwrap_or_default();
-    let iface_id: u64 = iface_id.unwrap_or_else(|| rand::random());
+    let iface_id: u64 = iface_id.unwrap_or_else(rand::random);
     Ok(Ipv6Addr::new(
         base_prefix + ((global_id >> 32) as u16),
         (global_id >> 16) as u16,
@@ -55,7 +59,389 @@ pub fn generate_ipv6(
     ))
 }
 
-pub fn local_hostname() -> anyhow::Result<String> {
+/// PostUp and PostDown commands that forward traffic from the wireguard interface and
+/// masquerade it out of `out_iface`. `%i` is replaced with the interface name by wg-quick.
+pub fn nat_masquerade_rules(out_iface: &str) -> (Vec<String>, Vec<String>) {
+    let rules = |action: &str| -> Vec<String> {
+        ["iptables", "ip6tables"]
+            .iter()
+            .flat_map(|cmd| {
+                vec![
+                    format!("{} {} FORWARD -i %i -j ACCEPT", cmd, action),
+                    format!("{} {} FORWARD -o %i -j ACCEPT", cmd, action),
+                    format!(
+                        "{} -t nat {} POSTROUTING -o {} -j MASQUERADE",
+                        cmd, action, out_iface
+                    ),
+                ]
+            })
+            .collect()
+    };
+    (rules("-A"), rules("-D"))
+}
+
+/// Check that an endpoint is a `host:port` pair wireguard can connect to. IPv6 addresses must be
+/// wrapped in brackets.
+pub fn validate_endpoint(endpoint: &str) -> crate::Result<()> {
+    if endpoint.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    let invalid = |reason| Error::InvalidEndpoint {
+        endpoint: endpoint.to_owned(),
+        reason,
+    };
+    let (host, port) = endpoint
+        .rsplit_once(':')
+        .ok_or_else(|| invalid("missing a port, expected host:port"))?;
+    if host.is_empty() {
+        return Err(invalid("missing a host"));
+    }
+    if host.contains(':') {
+        return Err(invalid("ambiguous, write IPv6 endpoints as [address]:port"));
+    }
+    port.parse::<u16>().map_err(|_| invalid("invalid port"))?;
+    Ok(())
+}
+
+/// Append `port` to an endpoint given without one, eg. `vpn.example.com` becomes
+/// `vpn.example.com:51820`. Bare IPv6 addresses are wrapped in brackets.
+pub fn endpoint_with_port(endpoint: &str, port: u16) -> String {
+    if endpoint.parse::<SocketAddr>().is_ok() {
+        return endpoint.to_owned();
+    }
+    if let Ok(address) = endpoint.parse::<IpAddr>() {
+        return SocketAddr::new(address, port).to_string();
+    }
+    if !endpoint.contains(':') || (endpoint.starts_with('[') && endpoint.ends_with(']')) {
+        return format!("{}:{}", endpoint, port);
+    }
+    endpoint.to_owned()
+}
+
+/// Check that a host name is a DNS label, since names end up in rendered file names, hosts
+/// files and zone files
+pub fn validate_name(name: &str) -> crate::Result<()> {
+    if HOST_NAME.is_match(name) {
+        return Ok(());
+    }
+    let invalid = |reason: String| {
+        Err(Error::InvalidName {
+            name: name.to_owned(),
+            reason,
+        })
+    };
+    if let Some((position, c)) = name
+        .chars()
+        .enumerate()
+        .find(|(_, c)| !c.is_ascii_alphanumeric() && *c != '-')
+    {
+        return invalid(format!(
+            "invalid character '{}' at position {}",
+            c,
+            position + 1
+        ));
+    }
+    if name.is_empty() {
+        return invalid(String::from("name is empty"));
+    }
+    if name.len() > 63 {
+        return invalid(String::from("name is longer than 63 characters"));
+    }
+    invalid(String::from("name can not start or end with '-'"))
+}
+
+/// 64 bit FNV-1a hash of the hostname. Unlike the std hasher the output is stable across Rust
+/// versions, which keeps derived addresses stable too.
+pub(crate) fn hash_hostname(hostname: &str) -> u64 {
+    hostname.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// STUN server queried when no other one is given
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// Requests sent before giving up on a STUN server. UDP may drop any one of them.
+const STUN_ATTEMPTS: usize = 3;
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Ask a STUN server (RFC 5389) which address our traffic appears to come from, revealing the
+/// public address of a host behind NAT
+pub fn discover_public_endpoint(stun_server: &str) -> crate::Result<SocketAddr> {
+    let server = stun_server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::Unresolvable(stun_server.to_owned()))?;
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(STUN_TIMEOUT))?;
+    let transaction_id: [u8; 12] = rand::random();
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0_u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    let mut buf = [0; 512];
+    for _ in 0..STUN_ATTEMPTS {
+        socket.send_to(&request, server)?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) if from == server => {
+                if let Some(addr) = parse_stun_response(&buf[..len], &transaction_id)? {
+                    return Ok(addr);
+                }
+            }
+            Ok(_) => continue,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(Error::StunTimeout {
+        server: stun_server.to_owned(),
+        attempts: STUN_ATTEMPTS,
+    })
+}
+
+/// Pull the mapped address out of a STUN binding response. Returns `None` for packets that are
+/// not a response to our request.
+fn parse_stun_response(
+    packet: &[u8],
+    transaction_id: &[u8; 12],
+) -> crate::Result<Option<SocketAddr>> {
+    let read_u16 = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    if packet.len() < 20
+        || read_u16(&packet[0..2]) != STUN_BINDING_RESPONSE
+        || packet[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || &packet[8..20] != transaction_id
+    {
+        return Ok(None);
+    }
+    let length = (read_u16(&packet[2..4]) as usize).min(packet.len() - 20);
+    let mut attrs = &packet[20..20 + length];
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = read_u16(&attrs[0..2]);
+        let len = read_u16(&attrs[2..4]) as usize;
+        let value = attrs
+            .get(4..4 + len)
+            .ok_or(Error::InvalidStunResponse("truncated attribute"))?;
+        match kind {
+            // preferred, since NATs that rewrite addresses in payloads can not mangle it
+            STUN_XOR_MAPPED_ADDRESS => {
+                return Ok(Some(decode_stun_address(value, Some(transaction_id))?))
+            }
+            STUN_MAPPED_ADDRESS => mapped = Some(decode_stun_address(value, None)?),
+            _ => {}
+        }
+        // attributes are padded to a multiple of 4 bytes
+        let padded = 4 + len.div_ceil(4) * 4;
+        attrs = attrs.get(padded..).unwrap_or(&[]);
+    }
+    mapped
+        .map(Some)
+        .ok_or(Error::InvalidStunResponse("no mapped address"))
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS attribute. Pass the transaction id for the XOR variant.
+fn decode_stun_address(value: &[u8], xor: Option<&[u8; 12]>) -> crate::Result<SocketAddr> {
+    if value.len() < 4 {
+        return Err(Error::InvalidStunResponse("truncated address"));
+    }
+    let mut key = STUN_MAGIC_COOKIE.to_be_bytes().to_vec();
+    if let Some(transaction_id) = xor {
+        key.extend_from_slice(transaction_id);
+    }
+    let unmask = |bytes: &[u8]| -> Vec<u8> {
+        match xor {
+            Some(_) => bytes.iter().zip(&key).map(|(b, k)| b ^ k).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+    let port = unmask(&value[2..4]);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = match (value[1], value.len()) {
+        (0x01, 8) => {
+            let ip = unmask(&value[4..8]);
+            IpAddr::from([ip[0], ip[1], ip[2], ip[3]])
+        }
+        (0x02, 20) => {
+            let ip = unmask(&value[4..20]);
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&ip);
+            IpAddr::from(octets)
+        }
+        _ => return Err(Error::InvalidStunResponse("unknown address family")),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Parse a `key=value` tag. Keys may only contain letters, digits and `-_./`.
+pub fn parse_tag(tag: &str) -> crate::Result<(String, String)> {
+    let invalid = |reason| Error::InvalidTag {
+        tag: tag.to_owned(),
+        reason,
+    };
+    let (key, value) = tag
+        .split_once('=')
+        .ok_or_else(|| invalid("not in key=value form"))?;
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c));
+    if !valid_key {
+        return Err(invalid("invalid key"));
+    }
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Parse the networks of a wg-quick list, eg. `Address` or `AllowedIPs`. Plain addresses are
+/// single host networks.
+fn parse_networks(values: &[&str]) -> crate::Result<Vec<IpNet>> {
+    values
+        .iter()
+        .map(|value| {
+            value
+                .parse()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| Error::InvalidNetwork(value.to_string()))
+        })
+        .collect()
+}
+
+/// Peer of a wireguard interface, as configured in the running kernel or expected from the
+/// config. Compared by `Config::drift`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WgPeer {
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<IpNet>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// Value of a `wg show dump` column, which marks missing values with `(none)` or `off`
+fn dump_value(value: &str) -> Option<&str> {
+    match value {
+        "(none)" | "off" | "" => None,
+        value => Some(value),
+    }
+}
+
+/// Columns of each peer line of `wg show <iface> dump`. The first line describes the interface
+/// itself, every following line is a tab separated peer.
+fn dump_peers(dump: &str) -> crate::Result<Vec<Vec<&str>>> {
+    dump.lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let columns: Vec<&str> = line.split('\t').collect();
+            if columns.len() < 8 {
+                return Err(Error::InvalidWgDump(line.to_owned()));
+            }
+            Ok(columns)
+        })
+        .collect()
+}
+
+/// Parse the peers of `wg show <iface> dump`
+pub fn parse_wg_dump(dump: &str) -> crate::Result<Vec<WgPeer>> {
+    dump_peers(dump)?
+        .into_iter()
+        .map(|columns| {
+            let invalid = || Error::InvalidWgDump(columns.join("\t"));
+            let mut allowed_ips = dump_value(columns[3])
+                .map(|ips| ips.split(',').map(|ip| ip.parse()).collect())
+                .unwrap_or_else(|| Ok(Vec::<IpNet>::new()))
+                .map_err(|_| invalid())?;
+            allowed_ips.sort();
+            Ok(WgPeer {
+                public_key: columns[0].to_owned(),
+                preshared_key: dump_value(columns[1]).map(String::from),
+                endpoint: dump_value(columns[2]).map(String::from),
+                allowed_ips,
+                persistent_keepalive: dump_value(columns[7])
+                    .map(|keepalive| keepalive.parse::<u16>())
+                    .transpose()
+                    .map_err(|_| invalid())?,
+            })
+        })
+        .collect()
+}
+
+/// Traffic counters of a peer of a running interface
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerStat {
+    pub public_key: String,
+    /// `None` until the first handshake
+    pub latest_handshake: Option<DateTime<Utc>>,
+    pub transfer_rx: u64,
+    pub transfer_tx: u64,
+}
+
+/// Parse the traffic counters of the peers in `wg show <iface> dump`
+pub fn parse_wg_stats(dump: &str) -> crate::Result<Vec<PeerStat>> {
+    dump_peers(dump)?
+        .into_iter()
+        .map(|columns| {
+            let invalid = || Error::InvalidWgDump(columns.join("\t"));
+            let handshake: i64 = columns[4].parse().map_err(|_| invalid())?;
+            Ok(PeerStat {
+                public_key: columns[0].to_owned(),
+                latest_handshake: Some(handshake)
+                    .filter(|seconds| *seconds > 0)
+                    .map(|seconds| {
+                        DateTime::from_utc(NaiveDateTime::from_timestamp(seconds, 0), Utc)
+                    }),
+                transfer_rx: columns[5].parse().map_err(|_| invalid())?,
+                transfer_tx: columns[6].parse().map_err(|_| invalid())?,
+            })
+        })
+        .collect()
+}
+
+/// Traffic counters of every peer of the interface. A missing interface, or no `wg` tool at
+/// all, has no peers rather than being an error, so callers polling for stats keep working
+/// while the interface is down.
+pub fn wg_stats(iface_name: &str) -> crate::Result<Vec<PeerStat>> {
+    if !crate::interface_is_up(iface_name) {
+        return Ok(Vec::new());
+    }
+    parse_wg_stats(&wg_dump(iface_name)?)
+}
+
+/// Output of `wg show <iface> dump`
+pub fn wg_dump(iface_name: &str) -> crate::Result<String> {
+    let output = Command::new("wg")
+        .args(["show", iface_name, "dump"])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            command: format!("wg show {} dump", iface_name),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Pick a random 40 bit global id for a unique local network
+pub fn random_global_id() -> u64 {
+    rand::random::<u64>() & ((1 << 40) - 1)
+}
+
+pub fn local_hostname() -> crate::Result<String> {
     Ok(
         String::from_utf8(Command::new("hostname").output()?.stdout)?
             .trim()
@@ -63,64 +449,291 @@ pub fn local_hostname() -> anyhow::Result<String> {
     )
 }
 
+/// Part a host plays in routing traffic for the mesh
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum HostRole {
+    /// Regular member of the mesh. Routes all traffic through the gateway if there is one.
+    #[default]
+    Client,
+    /// Forwards and masquerades mesh traffic out of `out_interface` to the internet
+    Gateway { out_interface: String },
+    /// Forwards traffic between hosts that can not reach each other directly
+    Relay,
+    /// Resolves names for the mesh. Its address is pushed to every host as a DNS server.
+    DnsServer,
+}
+
+/// Whether a host is currently heard from by the server daemon
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum HostStatus {
+    Online,
+    /// Disconnected or not heard from within the heartbeat timeout
+    #[default]
+    Offline,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Host {
     pub name: String,
     pub last_seen: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub status: HostStatus,
+    /// Primary mesh address. Hosts are keyed by it in the config.
     pub wireguard_address: IpNet,
+    /// Secondary mesh addresses, eg. an IPv6 address for a dual stack host with an IPv4 primary
+    #[serde(default)]
+    pub extra_addresses: Vec<IpNet>,
     pub public_key: String,
     pub private_key: String,
     interfaces: Vec<Interface>,
+    /// Extra subnets routed through this host in addition to its wireguard address, eg. the LAN
+    /// of the site a gateway connects to the mesh
+    #[serde(default, alias = "routed_subnets")]
+    pub allowed_subnets: Vec<IpNet>,
+    /// Seconds between keepalive packets sent to this host. Needed for hosts behind NAT.
+    #[serde(default)]
+    pub persistent_keepalive: Option<u16>,
+    /// MTU of the wireguard interface on this host. wg-quick picks one when unset.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    /// Commands wg-quick runs after bringing the interface up, in order
+    #[serde(default)]
+    pub post_up: Vec<String>,
+    /// Commands wg-quick runs after taking the interface down, in order
+    #[serde(default)]
+    pub post_down: Vec<String>,
+    #[serde(default)]
+    pub role: HostRole,
+    /// Address peers connect to, as `host:port`. Hosts without one are assumed to be roaming or
+    /// behind NAT.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Port wireguard listens on on this host, overriding the network's listen port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen_port: Option<u16>,
+    /// Address a hostname endpoint last resolved to, kept current by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_endpoint: Option<SocketAddr>,
+    /// Symmetric key mixed into the handshake with the config's local host. Both sides of the
+    /// link must use the same key.
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+    /// Roaming host the server removes once it has not connected for the host expiry
+    #[serde(default)]
+    pub dynamic: bool,
+    /// Free form labels for grouping hosts, eg. `datacenter=ams1`
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Interface {
     name: String,
-    mac: String,
+    /// Loopback and tunnel interfaces have no MAC address
+    #[serde(default)]
+    mac: Option<String>,
     state: String,
     addresses: Vec<IpNet>,
 }
 
+/// Interface as printed by `ip -j addr show`
+#[derive(Deserialize, Debug)]
+struct IpJsonInterface {
+    ifname: String,
+    /// Link layer address. Tunnels have none, or an IP address instead of a MAC.
+    #[serde(default)]
+    address: Option<String>,
+    #[serde(default)]
+    operstate: Option<String>,
+    #[serde(default)]
+    addr_info: Vec<IpJsonAddress>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IpJsonAddress {
+    local: IpAddr,
+    prefixlen: u8,
+}
+
+fn is_mac(address: &str) -> bool {
+    let octets: Vec<&str> = address.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && u8::from_str_radix(octet, 16).is_ok())
+}
+
 impl Interface {
-    pub fn local() -> anyhow::Result<Vec<Self>> {
-        let cmd = Command::new("ip").args(&["addr", "show"]).output()?;
-        let output = String::from_utf8(cmd.stdout)?;
+    /// Interfaces of the local machine. Reads the JSON output of `ip -j addr show`, falling back
+    /// to scraping the text output on iproute2 versions without JSON support. macOS has no `ip`
+    /// so `ifconfig` is read there instead.
+    pub fn local() -> crate::Result<Vec<Self>> {
+        if cfg!(target_os = "macos") {
+            let cmd = Command::new("ifconfig").output()?;
+            return Interface::from_ifconfig(&String::from_utf8(cmd.stdout)?);
+        }
+        let cmd = Command::new("ip").args(["-j", "addr", "show"]).output()?;
+        if cmd.status.success() {
+            match Interface::from_ip_json(&String::from_utf8(cmd.stdout)?) {
+                Ok(interfaces) => return Ok(interfaces),
+                Err(err) => log::debug!("unable to parse ip -j output, using text: {}", err),
+            }
+        }
+        Interface::local_from_text()
+    }
 
-        let interface_strings: Vec<String> =
-            output
-                .lines()
-                .fold(Vec::new(), |mut acc: Vec<String>, line| {
-                    if IFACE_NAME.is_match(&line) {
-                        acc.push(line.into());
-                    } else {
-                        let iface = acc.pop().unwrap();
-                        acc.push(String::from(format!("{}\n{}", iface, line)));
-                    }
-                    acc
+    /// Parse the output of `ip -j addr show`
+    pub fn from_ip_json(json: &str) -> crate::Result<Vec<Self>> {
+        let interfaces: Vec<IpJsonInterface> = serde_json::from_str(json)?;
+        interfaces
+            .into_iter()
+            .map(|iface| -> crate::Result<Self> {
+                let addresses = iface
+                    .addr_info
+                    .iter()
+                    .map(|addr| IpNet::new(addr.local, addr.prefixlen))
+                    .collect::<Result<Vec<IpNet>, _>>()?;
+                Ok(Interface {
+                    name: iface.ifname,
+                    mac: iface.address.filter(|address| is_mac(address)),
+                    state: iface.operstate.unwrap_or_else(|| String::from("UNKNOWN")),
+                    addresses,
+                })
+            })
+            .collect()
+    }
+
+    /// Parse the output of BSD style `ifconfig`, as found on macOS
+    pub fn from_ifconfig(output: &str) -> crate::Result<Vec<Self>> {
+        let mut interfaces: Vec<Interface> = Vec::new();
+        for line in output.lines() {
+            if !line.starts_with(char::is_whitespace) {
+                // header line, eg. `en0: flags=8863<UP,BROADCAST,RUNNING> mtu 1500`
+                let name = line
+                    .split(':')
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| Error::InvalidInterface(line.to_owned()))?;
+                interfaces.push(Interface {
+                    name: name.to_owned(),
+                    mac: None,
+                    state: String::from("UNKNOWN"),
+                    addresses: Vec::new(),
                 });
+                continue;
+            }
+            let iface = match interfaces.last_mut() {
+                Some(iface) => iface,
+                None => continue,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["ether", mac, ..] if is_mac(mac) => iface.mac = Some((*mac).to_owned()),
+                ["inet", addr, "netmask", mask, ..] => {
+                    let invalid = || Error::InvalidInterface(line.trim().to_owned());
+                    let mask = u32::from_str_radix(mask.trim_start_matches("0x"), 16)
+                        .map_err(|_| invalid())?;
+                    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+                    iface
+                        .addresses
+                        .push(IpNet::new(addr, mask.count_ones() as u8)?);
+                }
+                ["inet6", addr, "prefixlen", prefix, ..] => {
+                    // link local addresses carry a `%<iface>` zone suffix
+                    let invalid = || Error::InvalidInterface(line.trim().to_owned());
+                    let addr: IpAddr = addr
+                        .split('%')
+                        .next()
+                        .unwrap_or(addr)
+                        .parse()
+                        .map_err(|_| invalid())?;
+                    iface
+                        .addresses
+                        .push(IpNet::new(addr, prefix.parse().map_err(|_| invalid())?)?);
+                }
+                ["status:", "active"] => iface.state = String::from("UP"),
+                ["status:", _] => iface.state = String::from("DOWN"),
+                _ => {}
+            }
+        }
+        Ok(interfaces)
+    }
+
+    fn local_from_text() -> crate::Result<Vec<Self>> {
+        let cmd = Command::new("ip").args(["addr", "show"]).output()?;
+        let output = String::from_utf8(cmd.stdout)?;
+
+        // each interface starts with a numbered header line followed by indented detail lines
+        let interface_strings = output
+            .lines()
+            .fold(Vec::new(), |mut acc: Vec<String>, line| {
+                if IFACE_NAME.is_match(line) {
+                    acc.push(line.into());
+                } else if let Some(iface) = acc.last_mut() {
+                    iface.push('\n');
+                    iface.push_str(line);
+                } else {
+                    log::warn!(
+                        "ignoring ip addr output before the first interface: {}",
+                        line
+                    );
+                }
+                acc
+            });
 
         Ok(interface_strings
             .iter()
-            .map(|s| Interface::from_str(&s).unwrap())
+            .filter_map(|s| match Interface::from_str(s) {
+                Ok(iface) => Some(iface),
+                Err(err) => {
+                    log::warn!("skipping unparsable interface: {}", err);
+                    None
+                }
+            })
             .collect())
     }
+
+    /// MAC address as raw bytes. `None` if it does not parse or is all zeros, as on loopback and
+    /// tunnel interfaces.
+    pub fn mac_bytes(&self) -> Option<[u8; 6]> {
+        let mut out = [0; 6];
+        let mut octets = self.mac.as_ref()?.split(':');
+        for byte in out.iter_mut() {
+            *byte = u8::from_str_radix(octets.next()?, 16).ok()?;
+        }
+        if octets.next().is_some() || out == [0; 6] {
+            return None;
+        }
+        Some(out)
+    }
+}
+
+/// MAC address of the first non-loopback interface that has one
+pub fn primary_mac(interfaces: &[Interface]) -> Option<[u8; 6]> {
+    interfaces
+        .iter()
+        .filter(|iface| iface.name != "lo")
+        .find_map(Interface::mac_bytes)
 }
 
 impl FromStr for Interface {
-    type Err = anyhow::Error;
+    type Err = Error;
 
-    fn from_str(data: &str) -> anyhow::Result<Self> {
+    fn from_str(data: &str) -> crate::Result<Self> {
         let lines: Vec<&str> = data.lines().collect();
+        let header = lines
+            .first()
+            .ok_or_else(|| Error::InvalidInterface(String::from("empty description")))?;
         let name = IFACE_NAME
-            .captures(lines[0])
+            .captures(header)
             .and_then(|cap| cap.get(1))
             .map(|m| m.as_str().to_owned())
-            .ok_or(anyhow::anyhow!("unable to parse interface name"))?;
+            .ok_or_else(|| Error::InvalidInterface(header.to_string()))?;
         let state = IFACE_STATE
-            .captures(lines[0])
+            .captures(header)
             .and_then(|cap| cap.get(1))
             .map(|m| m.as_str().to_owned())
-            .ok_or(anyhow::anyhow!("unable to parse interface state"))?;
+            .ok_or_else(|| Error::InvalidInterface(header.to_string()))?;
         let addresses: Vec<IpNet> = lines
             .iter()
             .skip(2)
@@ -132,11 +745,11 @@ impl FromStr for Interface {
                     .and_then(|c| c.as_str().parse().ok())
             })
             .collect();
-        let mac_cap = IFACE_MAC.captures(lines[1]);
-        let mac = mac_cap
+        let mac = lines
+            .get(1)
+            .and_then(|line| IFACE_MAC.captures(line))
             .and_then(|cap| cap.get(1))
-            .map(|m| m.as_str().to_owned())
-            .ok_or(anyhow::anyhow!("unable to parse MAC address"))?;
+            .map(|m| m.as_str().to_owned());
         Ok(Interface {
             name,
             mac,
@@ -147,18 +760,247 @@ impl FromStr for Interface {
 }
 
 impl Host {
+    /// Start building a host, see `HostBuilder`
+    pub fn builder() -> HostBuilder {
+        HostBuilder::default()
+    }
+
     /// Return the host object for the local system
-    pub fn local() -> anyhow::Result<Self> {
+    pub fn local() -> crate::Result<Self> {
         let name = local_hostname()?;
         Ok(Host {
             name,
             last_seen: None,
+            status: HostStatus::default(),
             wireguard_address: IpNet::V6(Ipv6Net::new(generate_ipv6(None, None, None)?, 64)?),
             public_key: String::new(),
             private_key: String::new(),
             interfaces: Interface::local()?,
+            allowed_subnets: Vec::new(),
+            persistent_keepalive: None,
+            mtu: None,
+            post_up: Vec::new(),
+            post_down: Vec::new(),
+            role: HostRole::default(),
+            endpoint: None,
+            resolved_endpoint: None,
+            listen_port: None,
+            preshared_key: None,
+            tags: HashMap::new(),
+            extra_addresses: Vec::new(),
+            dynamic: false,
+        })
+    }
+
+    /// Add hook commands forwarding traffic from the mesh and masquerading it out of the given
+    /// interface, making this host a NAT gateway
+    pub fn with_nat_masquerade(mut self, out_iface: &str) -> Self {
+        let (post_up, post_down) = nat_masquerade_rules(out_iface);
+        self.post_up.extend(post_up);
+        self.post_down.extend(post_down);
+        self
+    }
+
+    /// Derive an IPv6 address in the network from the hostname, so the same host always lands
+    /// on the same address
+    pub fn allocate_address(global_id: u64, hostname: &str) -> crate::Result<IpNet> {
+        let address = generate_ipv6(Some(global_id), None, Some(hash_hostname(hostname)))?;
+        Ok(IpNet::V6(Ipv6Net::new(address, 64)?))
+    }
+
+    pub fn is_gateway(&self) -> bool {
+        matches!(self.role, HostRole::Gateway { .. })
+    }
+
+    /// Every mesh address of the host, primary first
+    pub fn addresses(&self) -> Vec<IpNet> {
+        let mut out = vec![self.wireguard_address];
+        out.extend(self.extra_addresses.iter().cloned());
+        out
+    }
+
+    /// Networks peers should route to this host: its own wireguard addresses plus any extra
+    /// allowed subnets
+    pub fn allowed_ips(&self) -> Vec<IpNet> {
+        let mut out: Vec<IpNet> = self
+            .addresses()
+            .iter()
+            .map(|address| IpNet::from(address.addr()))
+            .collect();
+        out.extend(self.allowed_subnets.iter().cloned());
+        out
+    }
+
+    /// Overwrite the settings given on the command line, leaving the rest of the host as is
+    pub fn update_from_matches(&mut self, m: &clap::ArgMatches) -> crate::Result<()> {
+        let parsed = Host::try_from(m)?;
+        if m.is_present("wireguard_address") {
+            self.wireguard_address = parsed.wireguard_address;
+        }
+        if m.is_present("public_key") {
+            self.public_key = parsed.public_key;
+        }
+        if m.is_present("private_key") {
+            self.private_key = parsed.private_key;
+        }
+        if m.is_present("interfaces") {
+            self.interfaces = parsed.interfaces;
+        }
+        if m.is_present("dynamic") {
+            self.dynamic = true;
+        } else if m.is_present("no_dynamic") {
+            self.dynamic = false;
+        }
+        if m.is_present("extra_addresses") {
+            self.extra_addresses = parsed.extra_addresses;
+        }
+        if m.is_present("allowed_subnets") {
+            self.allowed_subnets = parsed.allowed_subnets;
+        }
+        if m.is_present("keepalive") {
+            self.persistent_keepalive = parsed.persistent_keepalive;
+        }
+        if m.is_present("mtu") {
+            self.mtu = parsed.mtu;
+        }
+        if m.is_present("post_up") {
+            self.post_up = parsed.post_up;
+        }
+        if m.is_present("post_down") {
+            self.post_down = parsed.post_down;
+        }
+        if m.is_present("role") {
+            self.role = parsed.role;
+        } else if let HostRole::Gateway { out_interface } = &mut self.role {
+            // a gateway can move to another interface without repeating its role
+            if let Some(new_interface) = m.value_of("out_interface") {
+                *out_interface = new_interface.into();
+            }
+        }
+        if m.is_present("endpoint") {
+            self.endpoint = parsed.endpoint;
+            self.resolved_endpoint = None;
+        }
+        if m.is_present("preshared_key") {
+            self.preshared_key = parsed.preshared_key;
+        }
+        if m.is_present("wireguard_port") {
+            self.listen_port = parsed.listen_port;
+        }
+        for key in m.values_of("remove_tags").into_iter().flatten() {
+            self.tags.remove(key);
+        }
+        self.tags.extend(parsed.tags);
+        Ok(())
+    }
+
+    /// Whether the host has every one of the given tags
+    pub fn has_tags(&self, tags: &[(String, String)]) -> bool {
+        tags.iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+
+    /// Endpoint given as a hostname rather than an IP address, which the server re-resolves
+    pub fn endpoint_hostname(&self) -> Option<&str> {
+        self.endpoint
+            .as_deref()
+            .filter(|endpoint| endpoint.parse::<SocketAddr>().is_err())
+    }
+
+    /// Read the host from the `[Interface]` section of a wg-quick config, the inverse of
+    /// `Config::render_config`. The config does not name the host, so its name is left empty.
+    pub fn from_wg_conf(text: &str) -> crate::Result<Self> {
+        let sections = wgconf::parse(text)?;
+        let interface = sections
+            .iter()
+            .find(|section| section.name == "interface")
+            .ok_or(Error::MissingSection("Interface"))?;
+        Host::from_wg_interface(interface)
+    }
+
+    pub(crate) fn from_wg_interface(interface: &wgconf::Section) -> crate::Result<Self> {
+        let private_key = interface.value("PrivateKey").ok_or(Error::MissingWgKey {
+            section: "Interface",
+            key: "PrivateKey",
+        })?;
+        let mut addresses = parse_networks(&interface.list("Address"))?.into_iter();
+        Ok(Host {
+            public_key: crate::generate_public_key(private_key)?,
+            private_key: private_key.to_owned(),
+            wireguard_address: addresses.next().ok_or(Error::MissingWgKey {
+                section: "Interface",
+                key: "Address",
+            })?,
+            extra_addresses: addresses.collect(),
+            mtu: interface.parsed("MTU")?,
+            post_up: interface
+                .values("PostUp")
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            post_down: interface
+                .values("PostDown")
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ..Host::default()
         })
     }
+
+    /// Read a remote host from a `[Peer]` section. Its name is taken from the comment
+    /// `render_config` writes, falling back to the start of its public key. Single addresses
+    /// in `subnet` are the host's own, other allowed IPs are subnets routed through it.
+    pub(crate) fn from_wg_peer(peer: &wgconf::Section, subnet: IpNet) -> crate::Result<Self> {
+        let public_key = peer.value("PublicKey").ok_or(Error::MissingWgKey {
+            section: "Peer",
+            key: "PublicKey",
+        })?;
+        let name = match &peer.comment {
+            Some(comment) => comment.clone(),
+            None => public_key
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .take(8)
+                .collect(),
+        };
+        let (own, routed): (Vec<IpNet>, Vec<IpNet>) = parse_networks(&peer.list("AllowedIPs"))?
+            .into_iter()
+            // default routes belong to a gateway, whose role can not be told from the config
+            .filter(|net| net.prefix_len() > 0)
+            .partition(|net| net.prefix_len() == net.max_prefix_len() && subnet.contains(net));
+        let mut addresses = own
+            .into_iter()
+            .map(|address| IpNet::new(address.addr(), subnet.prefix_len()))
+            .collect::<Result<Vec<IpNet>, _>>()?
+            .into_iter();
+        let endpoint = peer.value("Endpoint").map(String::from);
+        if let Some(endpoint) = &endpoint {
+            validate_endpoint(endpoint)?;
+        }
+        Ok(Host {
+            wireguard_address: addresses.next().ok_or_else(|| Error::PeerOutsideSubnet {
+                name: name.clone(),
+                subnet,
+            })?,
+            extra_addresses: addresses.collect(),
+            name,
+            public_key: public_key.to_owned(),
+            preshared_key: peer.value("PresharedKey").map(String::from),
+            allowed_subnets: routed,
+            endpoint,
+            persistent_keepalive: peer.parsed("PersistentKeepalive")?,
+            ..Host::default()
+        })
+    }
+
+    /// Copy of the host with its private and preshared keys blanked, safe to hand to peers
+    pub fn without_secrets(&self) -> Self {
+        Host {
+            private_key: String::new(),
+            preshared_key: None,
+            ..self.clone()
+        }
+    }
 }
 
 impl Default for Host {
@@ -166,43 +1008,723 @@ impl Default for Host {
         Host {
             name: String::new(),
             last_seen: None,
+            status: HostStatus::default(),
             wireguard_address: IpNet::V6(
                 Ipv6Net::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 64).unwrap(),
             ),
             public_key: String::new(),
             private_key: String::new(),
             interfaces: Vec::new(),
+            allowed_subnets: Vec::new(),
+            persistent_keepalive: None,
+            mtu: None,
+            post_up: Vec::new(),
+            post_down: Vec::new(),
+            role: HostRole::default(),
+            endpoint: None,
+            resolved_endpoint: None,
+            listen_port: None,
+            preshared_key: None,
+            tags: HashMap::new(),
+            extra_addresses: Vec::new(),
+            dynamic: false,
         }
     }
 }
 
+/// Builds a `Host` for library users without spelling out every field. Only the name and
+/// wireguard address are required, everything else starts out like `Host::default`.
+#[derive(Debug, Clone, Default)]
+pub struct HostBuilder {
+    name: Option<String>,
+    wireguard_address: Option<IpNet>,
+    host: Host,
+}
+
+impl HostBuilder {
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn wireguard_address(mut self, address: IpNet) -> Self {
+        self.wireguard_address = Some(address);
+        self
+    }
+
+    pub fn extra_address(mut self, address: IpNet) -> Self {
+        self.host.extra_addresses.push(address);
+        self
+    }
+
+    pub fn public_key(mut self, public_key: &str) -> Self {
+        self.host.public_key = public_key.to_owned();
+        self
+    }
+
+    pub fn private_key(mut self, private_key: &str) -> Self {
+        self.host.private_key = private_key.to_owned();
+        self
+    }
+
+    pub fn preshared_key(mut self, preshared_key: &str) -> Self {
+        self.host.preshared_key = Some(preshared_key.to_owned());
+        self
+    }
+
+    /// Route an extra subnet through the host, see `Host::allowed_subnets`
+    pub fn allowed_subnet(mut self, subnet: IpNet) -> Self {
+        self.host.allowed_subnets.push(subnet);
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.host.endpoint = Some(endpoint.to_owned());
+        self
+    }
+
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.host.listen_port = Some(port);
+        self
+    }
+
+    pub fn persistent_keepalive(mut self, seconds: u16) -> Self {
+        self.host.persistent_keepalive = Some(seconds);
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        self.host.mtu = Some(mtu);
+        self
+    }
+
+    pub fn post_up(mut self, command: &str) -> Self {
+        self.host.post_up.push(command.to_owned());
+        self
+    }
+
+    pub fn post_down(mut self, command: &str) -> Self {
+        self.host.post_down.push(command.to_owned());
+        self
+    }
+
+    pub fn role(mut self, role: HostRole) -> Self {
+        self.host.role = role;
+        self
+    }
+
+    pub fn dynamic(mut self, dynamic: bool) -> Self {
+        self.host.dynamic = dynamic;
+        self
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.host.tags.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Finish the host. Fails without a name or wireguard address, or with an invalid endpoint.
+    /// The endpoint may leave out its port, `Config::add_host` fills in the host's listen port.
+    pub fn build(self) -> crate::Result<Host> {
+        let name = self
+            .name
+            .filter(|name| !name.is_empty())
+            .ok_or(Error::MissingHostName)?;
+        let wireguard_address = self
+            .wireguard_address
+            .ok_or_else(|| Error::MissingAddress(name.clone()))?;
+        if let Some(endpoint) = &self.host.endpoint {
+            validate_endpoint(&endpoint_with_port(endpoint, crate::DEFAULT_LISTEN_PORT))?;
+        }
+        Ok(Host {
+            name,
+            wireguard_address,
+            ..self.host
+        })
+    }
+}
+
 impl TryFrom<&clap::ArgMatches> for Host {
-    type Error = anyhow::Error;
+    type Error = Error;
 
-    fn try_from(m: &clap::ArgMatches) -> anyhow::Result<Self> {
+    fn try_from(m: &clap::ArgMatches) -> crate::Result<Self> {
+        // the values of an argument that failed to parse, for the error
+        let invalid = |name: &'static str| Error::InvalidArgument {
+            name,
+            value: m
+                .values_of(name)
+                .map(|values| values.collect::<Vec<_>>().join(","))
+                .unwrap_or_default(),
+        };
         Ok(Host {
             name: m
                 .value_of("name")
-                .ok_or(anyhow::anyhow!("name argument not provided"))?
+                .ok_or(Error::MissingArgument("name"))?
                 .into(),
-            wireguard_address: m
-                .value_of("wireguard_address")
-                .and_then(|s| s.parse().ok())
-                .ok_or(anyhow::anyhow!("invalid wireguard address argument"))?,
+            // without an address argument the host gets the default address, callers should
+            // assign it one from the config
+            wireguard_address: match m.value_of("wireguard_address") {
+                Some(address) => address.parse().map_err(|_| invalid("wireguard_address"))?,
+                None => Host::default().wireguard_address,
+            },
             public_key: m
                 .value_of("public_key")
                 .map(String::from)
-                .unwrap_or_else(|| String::new()),
+                .unwrap_or_default(),
             private_key: m
                 .value_of("private_key")
                 .map(String::from)
-                .unwrap_or_else(|| String::new()),
+                .unwrap_or_default(),
             last_seen: None,
+            status: HostStatus::default(),
             interfaces: m
                 .value_of("interfaces")
                 .iter()
                 .filter_map(|i| i.parse().ok())
                 .collect(),
+            allowed_subnets: m
+                .values_of("allowed_subnets")
+                .map(|values| values.map(|s| s.parse()).collect())
+                .unwrap_or_else(|| Ok(Vec::new()))
+                .map_err(|_| invalid("allowed_subnets"))?,
+            persistent_keepalive: m
+                .value_of("keepalive")
+                .map(|s| s.parse())
+                .transpose()
+                .map_err(|_| invalid("keepalive"))?,
+            mtu: m
+                .value_of("mtu")
+                .map(|s| s.parse())
+                .transpose()
+                .map_err(|_| invalid("mtu"))?,
+            post_up: m
+                .values_of("post_up")
+                .map(|values| values.map(String::from).collect())
+                .unwrap_or_default(),
+            post_down: m
+                .values_of("post_down")
+                .map(|values| values.map(String::from).collect())
+                .unwrap_or_default(),
+            role: match m.value_of("role") {
+                None | Some("client") => HostRole::Client,
+                Some("relay") => HostRole::Relay,
+                Some("dns-server") => HostRole::DnsServer,
+                Some("gateway") => HostRole::Gateway {
+                    out_interface: m
+                        .value_of("out_interface")
+                        .ok_or(Error::MissingArgument("out_interface"))?
+                        .into(),
+                },
+                Some(role) => {
+                    return Err(Error::InvalidArgument {
+                        name: "role",
+                        value: role.to_owned(),
+                    })
+                }
+            },
+            // `auto` is resolved by the caller with `discover_public_endpoint`. A missing port
+            // is filled in by `Config::add_host`.
+            endpoint: m
+                .value_of("endpoint")
+                .filter(|e| *e != "auto")
+                .map(|e| {
+                    validate_endpoint(&endpoint_with_port(e, crate::DEFAULT_LISTEN_PORT))
+                        .map(|_| e.to_owned())
+                })
+                .transpose()?,
+            resolved_endpoint: None,
+            listen_port: m
+                .value_of("wireguard_port")
+                .map(|s| s.parse())
+                .transpose()
+                .map_err(|_| invalid("wireguard_port"))?,
+            preshared_key: m.value_of("preshared_key").map(String::from),
+            dynamic: m.is_present("dynamic"),
+            extra_addresses: m
+                .values_of("extra_addresses")
+                .map(|values| values.map(|s| s.parse()).collect())
+                .unwrap_or_else(|| Ok(Vec::new()))
+                .map_err(|_| invalid("extra_addresses"))?,
+            tags: m
+                .values_of("tags")
+                .map(|values| values.map(parse_tag).collect())
+                .unwrap_or_else(|| Ok(HashMap::new()))?,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_ipv6_accepts_40_bit_global_ids() {
+        let max = (1_u64 << 40) - 1;
+
+        let address = generate_ipv6(Some(max), Some(0xabcd), Some(0x1122_3344_5566_7788)).unwrap();
+
+        assert_eq!(
+            address,
+            "fcff:ffff:ffff:abcd:1122:3344:5566:7788"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+        let ula: Ipv6Net = "fc00::/7".parse().unwrap();
+        assert!(ula.contains(&address));
+        assert!(matches!(
+            generate_ipv6(Some(1 << 40), None, None),
+            Err(Error::GlobalIdTooWide(id)) if id == 1 << 40
+        ));
+    }
+
+    #[test]
+    fn generate_ipv6_packs_the_global_id_after_the_prefix() {
+        let address = generate_ipv6(Some(0x12_3456_789a), Some(1), Some(2)).unwrap();
+
+        assert_eq!(address, "fc12:3456:789a:1::2".parse::<Ipv6Addr>().unwrap());
+    }
+
+    fn interface(name: &str, mac: Option<&str>) -> Interface {
+        Interface {
+            name: name.to_owned(),
+            mac: mac.map(str::to_owned),
+            state: String::from("UP"),
+            addresses: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn primary_mac_skips_loopback_and_interfaces_without_a_mac() {
+        let interfaces = vec![
+            interface("lo", Some("00:00:00:00:00:00")),
+            interface("wg0", None),
+            interface("eth0", Some("52:54:00:12:34:ab")),
+            interface("eth1", Some("52:54:00:ff:ff:ff")),
+        ];
+
+        assert_eq!(
+            primary_mac(&interfaces),
+            Some([0x52, 0x54, 0x00, 0x12, 0x34, 0xab])
+        );
+        assert_eq!(primary_mac(&interfaces[..2]), None);
+        assert_eq!(primary_mac(&[interface("eth0", Some("52:54:00"))]), None);
+    }
+
+    #[test]
+    fn interface_parses_loopback_and_tunnels_without_a_mac() {
+        let lo = "\
+1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN group default qlen 1000
+    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00
+    inet 127.0.0.1/8 scope host lo
+       valid_lft forever preferred_lft forever
+    inet6 ::1/128 scope host
+       valid_lft forever preferred_lft forever";
+        let wg0 = "\
+4: wg0: <POINTOPOINT,NOARP,UP,LOWER_UP> mtu 1420 qdisc noqueue state UNKNOWN group default qlen 1000
+    link/none
+    inet 10.42.0.1/24 scope global wg0
+       valid_lft forever preferred_lft forever";
+
+        let lo: Interface = lo.parse().unwrap();
+        let wg0: Interface = wg0.parse().unwrap();
+
+        assert_eq!(lo.name, "lo");
+        assert_eq!(lo.state, "UNKNOWN");
+        assert_eq!(
+            lo.addresses,
+            vec![
+                "127.0.0.1/8".parse::<IpNet>().unwrap(),
+                "::1/128".parse().unwrap()
+            ]
+        );
+        assert_eq!(lo.mac_bytes(), None);
+        assert_eq!(wg0.name, "wg0");
+        assert_eq!(wg0.mac, None);
+        assert_eq!(
+            wg0.addresses,
+            vec!["10.42.0.1/24".parse::<IpNet>().unwrap()]
+        );
+        assert!("".parse::<Interface>().is_err());
+        assert!("not an interface".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn interfaces_parse_from_ip_json() {
+        // `ip -j addr show`, trimmed to the fields iproute2 always prints
+        let json = r#"[
+            {"ifindex":1,"ifname":"lo","flags":["LOOPBACK","UP","LOWER_UP"],"mtu":65536,
+             "operstate":"UNKNOWN","link_type":"loopback","address":"00:00:00:00:00:00",
+             "addr_info":[
+                {"family":"inet","local":"127.0.0.1","prefixlen":8,"scope":"host"},
+                {"family":"inet6","local":"::1","prefixlen":128,"scope":"host"}]},
+            {"ifindex":2,"ifname":"eth0","flags":["BROADCAST","MULTICAST","UP","LOWER_UP"],
+             "mtu":1500,"operstate":"UP","link_type":"ether","address":"52:54:00:12:34:56",
+             "addr_info":[
+                {"family":"inet","local":"192.168.1.10","prefixlen":24,"scope":"global"},
+                {"family":"inet6","local":"fe80::5054:ff:fe12:3456","prefixlen":64,
+                 "scope":"link"}]},
+            {"ifindex":4,"ifname":"wg0","flags":["POINTOPOINT","NOARP","UP","LOWER_UP"],
+             "mtu":1420,"operstate":"UNKNOWN","link_type":"none",
+             "addr_info":[{"family":"inet","local":"10.42.0.1","prefixlen":24}]}
+        ]"#;
+
+        let interfaces = Interface::from_ip_json(json).unwrap();
+
+        let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["lo", "eth0", "wg0"]);
+        let eth0 = &interfaces[1];
+        assert_eq!(eth0.mac.as_deref(), Some("52:54:00:12:34:56"));
+        assert_eq!(eth0.state, "UP");
+        assert_eq!(
+            eth0.addresses,
+            vec![
+                "192.168.1.10/24".parse::<IpNet>().unwrap(),
+                "fe80::5054:ff:fe12:3456/64".parse().unwrap()
+            ]
+        );
+        assert_eq!(interfaces[0].addresses.len(), 2);
+        assert_eq!(interfaces[2].mac, None);
+        assert_eq!(primary_mac(&interfaces), eth0.mac_bytes());
+        assert!(Interface::from_ip_json("not json").is_err());
+    }
+
+    #[test]
+    fn interfaces_parse_from_macos_ifconfig() {
+        let output = "\
+lo0: flags=8049<UP,LOOPBACK,RUNNING,MULTICAST> mtu 16384
+\toptions=1203<RXCSUM,TXCSUM,TXSTATUS,SW_TIMESTAMP>
+\tinet 127.0.0.1 netmask 0xff000000
+\tinet6 ::1 prefixlen 128
+\tinet6 fe80::1%lo0 prefixlen 64 scopeid 0x1
+en0: flags=8863<UP,BROADCAST,SMART,RUNNING,SIMPLEX,MULTICAST> mtu 1500
+\toptions=400<CHANNEL_IO>
+\tether a4:83:e7:12:34:56
+\tinet6 fe80::1c2b:3d4e:5f60:7182%en0 prefixlen 64 secured scopeid 0x6
+\tinet 192.168.1.23 netmask 0xffffff00 broadcast 192.168.1.255
+\tnd6 options=201<PERFORMNUD,DAD>
+\tmedia: autoselect
+\tstatus: active
+utun0: flags=8051<UP,POINTOPOINT,RUNNING,MULTICAST> mtu 1380
+\tinet6 fe80::a1b2:c3d4:e5f6:1%utun0 prefixlen 64 scopeid 0xd
+";
+
+        let interfaces = Interface::from_ifconfig(output).unwrap();
+
+        let names: Vec<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["lo0", "en0", "utun0"]);
+        let en0 = &interfaces[1];
+        assert_eq!(en0.mac.as_deref(), Some("a4:83:e7:12:34:56"));
+        assert_eq!(en0.state, "UP");
+        assert_eq!(
+            en0.addresses,
+            vec![
+                "fe80::1c2b:3d4e:5f60:7182/64".parse::<IpNet>().unwrap(),
+                "192.168.1.23/24".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            interfaces[0].addresses,
+            vec![
+                "127.0.0.1/8".parse::<IpNet>().unwrap(),
+                "::1/128".parse().unwrap(),
+                "fe80::1/64".parse().unwrap()
+            ]
+        );
+        assert_eq!(interfaces[2].mac, None);
+    }
+
+    #[test]
+    fn hosts_are_filtered_by_tags() {
+        let tagged = |name: &str, tags: &[(&str, &str)]| {
+            let mut builder = Host::builder()
+                .name(name)
+                .wireguard_address("10.42.0.2/24".parse().unwrap());
+            for (key, value) in tags {
+                builder = builder.tag(key, value);
+            }
+            builder.build().unwrap()
+        };
+        let hosts = [
+            tagged("web1", &[("env", "prod"), ("dc", "ams")]),
+            tagged("web2", &[("env", "prod"), ("dc", "fra")]),
+            tagged("dev1", &[("env", "dev"), ("dc", "ams")]),
+            tagged("bare", &[]),
+        ];
+        let matching = |filters: &[&str]| -> Vec<&str> {
+            let filters: Vec<(String, String)> =
+                filters.iter().map(|f| parse_tag(f).unwrap()).collect();
+            hosts
+                .iter()
+                .filter(|host| host.has_tags(&filters))
+                .map(|host| host.name.as_str())
+                .collect()
+        };
+
+        assert_eq!(matching(&["env=prod"]), vec!["web1", "web2"]);
+        assert_eq!(matching(&["env=prod", "dc=ams"]), vec!["web1"]);
+        assert_eq!(matching(&["dc=ams"]), vec!["web1", "dev1"]);
+        assert!(matching(&["env=staging"]).is_empty());
+        assert_eq!(matching(&[]).len(), 4);
+    }
+
+    #[test]
+    fn malformed_tags_are_rejected() {
+        assert_eq!(
+            parse_tag("team=a=b").unwrap(),
+            (String::from("team"), String::from("a=b"))
+        );
+        assert_eq!(parse_tag("note=").unwrap().1, "");
+        for tag in &["no-equals", "=value", "bad key=value", "k!y=v"] {
+            assert!(
+                matches!(parse_tag(tag), Err(Error::InvalidTag { .. })),
+                "{:?} should be rejected",
+                tag
+            );
+        }
+    }
+
+    /// Answer one binding request with the address it came from, as a STUN server would
+    fn mock_stun_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 20);
+            assert_eq!(buf[0..2], STUN_BINDING_REQUEST.to_be_bytes());
+            let ip = match from.ip() {
+                IpAddr::V4(ip) => ip.octets(),
+                IpAddr::V6(_) => unreachable!(),
+            };
+            let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+            let mut response = Vec::new();
+            response.extend_from_slice(&STUN_BINDING_RESPONSE.to_be_bytes());
+            response.extend_from_slice(&12_u16.to_be_bytes());
+            response.extend_from_slice(&buf[4..20]);
+            response.extend_from_slice(&STUN_XOR_MAPPED_ADDRESS.to_be_bytes());
+            response.extend_from_slice(&8_u16.to_be_bytes());
+            response.extend_from_slice(&[0, 0x01]);
+            response.extend_from_slice(&(from.port() ^ 0x2112).to_be_bytes());
+            response.extend(ip.iter().zip(&cookie).map(|(b, k)| b ^ k));
+            socket.send_to(&response, from).unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn public_endpoint_is_discovered_from_a_stun_server() {
+        let server = mock_stun_server();
+        let endpoint = discover_public_endpoint(&server.to_string()).unwrap();
+        assert_eq!(endpoint.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_ne!(endpoint.port(), 0);
+        assert_ne!(endpoint.port(), server.port());
+    }
+
+    #[test]
+    fn silent_stun_server_times_out() {
+        // bound but never answering
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+        match discover_public_endpoint(&server) {
+            Err(Error::StunTimeout {
+                server: s,
+                attempts,
+            }) => {
+                assert_eq!(s, server);
+                assert_eq!(attempts, STUN_ATTEMPTS);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stun_responses_to_other_requests_are_ignored() {
+        let transaction_id = [7; 12];
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&STUN_BINDING_RESPONSE.to_be_bytes());
+        packet.extend_from_slice(&12_u16.to_be_bytes());
+        packet.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        packet.extend_from_slice(&[8; 12]);
+        packet.extend_from_slice(&STUN_MAPPED_ADDRESS.to_be_bytes());
+        packet.extend_from_slice(&8_u16.to_be_bytes());
+        packet.extend_from_slice(&[0, 0x01, 0xca, 0x6c, 203, 0, 113, 7]);
+        assert_eq!(parse_stun_response(&packet, &transaction_id).unwrap(), None);
+
+        packet[8..20].copy_from_slice(&transaction_id);
+        assert_eq!(
+            parse_stun_response(&packet, &transaction_id).unwrap(),
+            Some("203.0.113.7:51820".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn minimal_build_fills_in_defaults() {
+        let host = Host::builder()
+            .name("beta")
+            .wireguard_address("10.42.0.2/24".parse().unwrap())
+            .build()
+            .unwrap();
+
+        assert_eq!(host.name, "beta");
+        assert_eq!(host.wireguard_address, "10.42.0.2/24".parse().unwrap());
+        assert_eq!(host.last_seen, None);
+        assert!(host.interfaces.is_empty());
+        assert_eq!(host.status, HostStatus::default());
+        assert_eq!(host.endpoint, None);
+    }
+
+    #[test]
+    fn full_build_sets_every_field() {
+        let host = Host::builder()
+            .name("gateway")
+            .wireguard_address("10.42.0.2/24".parse().unwrap())
+            .extra_address("fc12::2/64".parse().unwrap())
+            .public_key("public")
+            .private_key("private")
+            .preshared_key("preshared")
+            .allowed_subnet("192.168.1.0/24".parse().unwrap())
+            .endpoint("gw.example.com:51821")
+            .listen_port(51821)
+            .persistent_keepalive(25)
+            .mtu(1380)
+            .post_up("iptables -A FORWARD -i %i -j ACCEPT")
+            .post_down("iptables -D FORWARD -i %i -j ACCEPT")
+            .role(HostRole::Relay)
+            .dynamic(true)
+            .tag("site", "office")
+            .build()
+            .unwrap();
+
+        assert_eq!(host.name, "gateway");
+        assert_eq!(host.extra_addresses, vec!["fc12::2/64".parse().unwrap()]);
+        assert_eq!(host.public_key, "public");
+        assert_eq!(host.private_key, "private");
+        assert_eq!(host.preshared_key.as_deref(), Some("preshared"));
+        assert_eq!(
+            host.allowed_subnets,
+            vec!["192.168.1.0/24".parse().unwrap()]
+        );
+        assert_eq!(host.endpoint.as_deref(), Some("gw.example.com:51821"));
+        assert_eq!(host.listen_port, Some(51821));
+        assert_eq!(host.persistent_keepalive, Some(25));
+        assert_eq!(host.mtu, Some(1380));
+        assert_eq!(host.post_up.len(), 1);
+        assert_eq!(host.post_down.len(), 1);
+        assert_eq!(host.role, HostRole::Relay);
+        assert!(host.dynamic);
+        assert_eq!(host.tags["site"], "office");
+    }
+
+    #[test]
+    fn build_requires_a_name_and_address() {
+        let address: IpNet = "10.42.0.2/24".parse().unwrap();
+
+        assert!(matches!(
+            Host::builder().wireguard_address(address).build(),
+            Err(Error::MissingHostName)
+        ));
+        assert!(matches!(
+            Host::builder().name("").wireguard_address(address).build(),
+            Err(Error::MissingHostName)
+        ));
+        assert!(matches!(
+            Host::builder().name("beta").build(),
+            Err(Error::MissingAddress(name)) if name == "beta"
+        ));
+        assert!(matches!(
+            Host::builder()
+                .name("beta")
+                .wireguard_address(address)
+                .endpoint("not an endpoint:port")
+                .build(),
+            Err(Error::InvalidEndpoint { .. })
+        ));
+    }
+
+    // `wg show wg0 dump` of an interface with a peer that has handshaken and one that has not
+    const WG_DUMP: &str = "\
+oK56DE9Ue9zK76rAc8pBl6opph+1v36lm7cXXsQKrQM=\tnQvxLh4D2I8gwQvAsTpLmt6e8f4XNOr7aaOcCLtXK1c=\t51820\toff
+xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\t(none)\t198.51.100.2:51820\t10.42.0.2/32,192.168.1.0/24\t1700000000\t123456\t654321\t25
+TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=\t(none)\t(none)\t10.42.0.3/32\t0\t0\t0\toff
+";
+
+    #[test]
+    fn stats_parse_from_a_wg_dump() {
+        let stats = parse_wg_stats(WG_DUMP).unwrap();
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[0].public_key,
+            "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+        );
+        assert_eq!(
+            stats[0].latest_handshake.map(|t| t.timestamp()),
+            Some(1_700_000_000)
+        );
+        assert_eq!(stats[0].transfer_rx, 123_456);
+        assert_eq!(stats[0].transfer_tx, 654_321);
+        assert_eq!(stats[1].latest_handshake, None);
+        assert_eq!(stats[1].transfer_rx, 0);
+    }
+
+    #[test]
+    fn interfaces_without_peers_have_no_stats() {
+        let interface_only = WG_DUMP.lines().next().unwrap();
+
+        assert!(parse_wg_stats(interface_only).unwrap().is_empty());
+        assert!(parse_wg_stats("").unwrap().is_empty());
+        assert!(wg_stats("wgmesh-missing").unwrap().is_empty());
+        assert!(matches!(
+            parse_wg_stats(&format!("{}\ntruncated\tline\n", interface_only)),
+            Err(Error::InvalidWgDump(_))
+        ));
+    }
+
+    #[test]
+    fn dns_safe_names_are_accepted() {
+        let longest = "a".repeat(63);
+        for name in &["beta", "host-2", "B", "0day", longest.as_str()] {
+            assert!(validate_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_names_point_at_the_problem() {
+        let too_long = "a".repeat(64);
+        for (name, reason) in &[
+            ("bad_name", "invalid character '_' at position 4"),
+            ("two words", "invalid character ' ' at position 4"),
+            ("dotted.name", "invalid character '.' at position 7"),
+            ("", "name is empty"),
+            (too_long.as_str(), "name is longer than 63 characters"),
+            ("-leading", "name can not start or end with '-'"),
+            ("trailing-", "name can not start or end with '-'"),
+        ] {
+            match validate_name(name) {
+                Err(Error::InvalidName {
+                    name: invalid,
+                    reason: actual,
+                }) => {
+                    assert_eq!(invalid, *name);
+                    assert_eq!(actual, *reason, "{}", name);
+                }
+                other => panic!("{:?} was not rejected: {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn endpoints_without_a_port_get_the_given_one() {
+        for (endpoint, expected) in &[
+            ("vpn.example.com", "vpn.example.com:51821"),
+            ("vpn.example.com:4500", "vpn.example.com:4500"),
+            ("198.51.100.2", "198.51.100.2:51821"),
+            ("198.51.100.2:4500", "198.51.100.2:4500"),
+            ("2001:db8::2", "[2001:db8::2]:51821"),
+            ("[2001:db8::2]", "[2001:db8::2]:51821"),
+            ("[2001:db8::2]:4500", "[2001:db8::2]:4500"),
+        ] {
+            assert_eq!(endpoint_with_port(endpoint, 51821), *expected);
+        }
+    }
+}
@@ -1,27 +1,65 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt::Write as _;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "wg-binary")]
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow;
 use chrono::{DateTime, Utc};
 use clap::Arg;
-use ipnet::IpNet;
-use log;
-use rand;
+use fs2::FileExt;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use serde::{Deserialize, Serialize};
-use simple_logger::SimpleLogger;
 use uuid::v1::{Context, Timestamp};
 use uuid::Uuid;
 
+pub mod client;
+pub mod diff;
+pub mod error;
 pub mod host;
+pub mod logging;
+pub mod openapi;
+pub mod qr;
 pub mod server;
+pub mod wgconf;
 
-pub use host::Host;
+pub use error::{Error, Result};
+pub use host::{Host, HostBuilder, HostRole, HostStatus, WgPeer};
+pub use logging::{configure_logging, LogFile, LogFormat};
 
+/// Name of the wireguard interface created by the rendered config, unless the config names
+/// another one
+pub const INTERFACE_NAME: &str = "wg0";
+/// Schema version written to new and migrated configs
+pub const CONFIG_VERSION: &str = "v1";
+/// Port wireguard listens on when the config does not specify one
+pub const DEFAULT_LISTEN_PORT: u16 = 51820;
+
+/// How long to wait for a peer to accept a connection when sending an event
+const EVENT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a peer to respond to an event before giving up
+const EVENT_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Keepalive interval for relays that do not set one, so the relayed paths stay open through NAT
+const DEFAULT_RELAY_KEEPALIVE: u16 = 25;
+
+/// How long to wait for another process to release the config lock
+pub const CONFIG_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to retry a contended config lock
+pub(crate) const CONFIG_LOCK_RETRY: Duration = Duration::from_millis(100);
+
+const MIN_IPV4_MTU: u32 = 576;
+const MIN_IPV6_MTU: u32 = 1280;
+const MAX_MTU: u32 = 65535;
+
+/// Change to the network, recorded by the server and sent between peers
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct Event {
+pub struct Event {
     id: Uuid,
     created_at: DateTime<Utc>,
     data: EventData,
@@ -29,8 +67,30 @@ struct Event {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum EventData {
-    Connect { host: Host },
-    Disconnect { host: Host },
+    Connect {
+        host: Host,
+    },
+    Disconnect {
+        host: Host,
+    },
+    /// The hostname endpoint of a host resolved to a new address
+    EndpointChanged {
+        host: Host,
+    },
+}
+
+impl EventData {
+    /// Names accepted by `kind`
+    pub const KINDS: &'static [&'static str] = &["connect", "disconnect", "endpoint_changed"];
+
+    /// Lower case name of the variant, used to filter events
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EventData::Connect { .. } => "connect",
+            EventData::Disconnect { .. } => "disconnect",
+            EventData::EndpointChanged { .. } => "endpoint_changed",
+        }
+    }
 }
 
 impl Event {
@@ -50,36 +110,536 @@ impl Event {
         Event::new(EventData::Disconnect { host })
     }
 
-    pub async fn send(self, address: &str) -> anyhow::Result<()> {
-        unimplemented!()
+    pub fn endpoint_changed(host: Host) -> Self {
+        Event::new(EventData::EndpointChanged { host })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn data(&self) -> &EventData {
+        &self.data
+    }
+
+    /// Copy of the event with the keys of its host blanked, see `Host::without_secrets`. Hosts
+    /// send their own preshared key in a connect event, but events are kept and listed for
+    /// every client.
+    pub fn without_secrets(&self) -> Self {
+        let host = match &self.data {
+            EventData::Connect { host }
+            | EventData::Disconnect { host }
+            | EventData::EndpointChanged { host } => host.without_secrets(),
+        };
+        let data = match self.data {
+            EventData::Connect { .. } => EventData::Connect { host },
+            EventData::Disconnect { .. } => EventData::Disconnect { host },
+            EventData::EndpointChanged { .. } => EventData::EndpointChanged { host },
+        };
+        Event {
+            data,
+            ..self.clone()
+        }
+    }
+
+    /// POST the event as JSON to the `/events` endpoint of the peer at `address`, authorized
+    /// with the peer's api token. Makes a single attempt; use `send_with_retry` to ride out
+    /// transient failures.
+    pub async fn send(self, address: &str, token: &str) -> Result<()> {
+        self.send_with_retry(address, token, RetryPolicy::no_retry())
+            .await
+    }
+
+    /// POST the event to the peer at `address`, retrying failed attempts with exponential backoff
+    /// according to `policy`
+    pub async fn send_with_retry(
+        self,
+        address: &str,
+        token: &str,
+        policy: RetryPolicy,
+    ) -> Result<()> {
+        client::MeshClient::new(&format!("http://{}", address), token)?
+            .with_retry(policy)
+            .send_event(&self)
+            .await
+    }
+
+    /// POST several events in a single request to the `/events/batch` endpoint of the peer at
+    /// `address`. Events are sent oldest first. The returned report lists the events the peer
+    /// accepted and the ones it rejected.
+    pub async fn send_batch(events: &[Event], address: &str, token: &str) -> Result<BatchReport> {
+        client::MeshClient::new(&format!("http://{}", address), token)?
+            .send_events(events)
+            .await
     }
 }
 
-pub fn configure_logging(log_level: &str) -> anyhow::Result<()> {
-    let level = log::LevelFilter::from_str(log_level)?;
-    let logger = SimpleLogger::new().with_level(level);
-    logger.init()?;
-    Ok(())
+/// Run `attempt` until it succeeds, retrying failures with exponential backoff according to
+/// `policy`. `action` describes the attempt in log and error messages.
+pub(crate) async fn with_retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    action: &str,
+    mut attempt: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut count = 1;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(err) if count >= max_attempts => {
+                return Err(Error::RetriesExhausted {
+                    action: action.to_owned(),
+                    attempts: count,
+                    source: Box::new(err),
+                });
+            }
+            Err(err) => {
+                let delay = policy.delay(count);
+                log::warn!(
+                    "attempt {} {} failed, retrying in {:?}: {}",
+                    count,
+                    action,
+                    delay,
+                    err
+                );
+                actix_web::rt::time::delay_for(delay).await;
+                count += 1;
+            }
+        }
+    }
+}
+
+/// Client used to deliver events to peers
+pub(crate) fn event_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(EVENT_CONNECT_TIMEOUT)
+        .timeout(EVENT_REQUEST_TIMEOUT)
+        .build()?)
+}
+
+/// How subcommands print their results, picked with the global `--output` flag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Aligned columns for people
+    Table,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            other => Err(Error::UnknownOutputFormat(other.to_owned())),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Format from a subcommand's matches. `--json` and `--yaml` flags on the subcommand take
+    /// precedence over the global `--output`.
+    pub fn from_matches(m: &clap::ArgMatches) -> Result<Self> {
+        if m.is_present("json") {
+            Ok(OutputFormat::Json)
+        } else if m.is_present("yaml") {
+            Ok(OutputFormat::Yaml)
+        } else {
+            m.value_of("output").unwrap_or("table").parse()
+        }
+    }
+
+    /// Serialize `value` for the machine readable formats, or lay it out with `table` for the
+    /// table format
+    pub fn render<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+        table: impl FnOnce(&T) -> String,
+    ) -> Result<String> {
+        Ok(match self {
+            OutputFormat::Table => table(value),
+            OutputFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+            OutputFormat::Yaml => serde_yaml::to_string(value)?,
+        })
+    }
+}
+
+/// Fields whose values are never printed in a diff
+const SECRET_FIELDS: &[&str] = &["api_token", "private_key", "preshared_key"];
+/// Host fields tracked by the server at runtime, left out of diffs
+const RUNTIME_FIELDS: &[&str] = &["last_seen", "status", "resolved_endpoint"];
+
+/// A field that differs between two configs or hosts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Changed fields of a host present in both configs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HostDiff {
+    pub name: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// Differences between two configs, as returned by `Config::diff`. Hosts are matched by name.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub changed_fields: Vec<FieldChange>,
+    pub added_hosts: Vec<String>,
+    pub removed_hosts: Vec<String>,
+    pub modified_hosts: Vec<HostDiff>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed_fields.is_empty()
+            && self.added_hosts.is_empty()
+            && self.removed_hosts.is_empty()
+            && self.modified_hosts.is_empty()
+    }
+}
+
+/// Compare the fields of two serialized structs, sorted by field name
+fn diff_fields(
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    skip: &[&str],
+) -> Vec<FieldChange> {
+    let empty = serde_json::Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let mut fields: Vec<&String> = old.keys().chain(new.keys()).collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter(|field| !skip.contains(&field.as_str()))
+        .filter_map(|field| {
+            let old_value = old.get(field).unwrap_or(&serde_json::Value::Null);
+            let new_value = new.get(field).unwrap_or(&serde_json::Value::Null);
+            if old_value == new_value {
+                return None;
+            }
+            let show = |value: &serde_json::Value| {
+                if SECRET_FIELDS.contains(&field.as_str()) {
+                    String::from("<redacted>")
+                } else {
+                    value.to_string()
+                }
+            };
+            Some(FieldChange {
+                field: field.clone(),
+                old: show(old_value),
+                new: show(new_value),
+            })
+        })
+        .collect()
+}
+
+/// Freshly generated wireguard keys, as printed by the `genkey` subcommand
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyPair {
+    pub private_key: String,
+    pub public_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+}
+
+impl KeyPair {
+    /// Generate a private key and its public key, plus a preshared key if asked for
+    pub fn generate(with_preshared_key: bool) -> Result<Self> {
+        let private_key = generate_private_key()?;
+        Ok(KeyPair {
+            public_key: generate_public_key(&private_key)?,
+            private_key,
+            preshared_key: if with_preshared_key {
+                Some(generate_preshared_key()?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Kind of config change recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    AddHost,
+    EditHost,
+    RemoveHost,
+}
+
+/// Line of the audit log, see `Config::audit`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub operation: AuditOperation,
+    /// Name of the host that was changed
+    pub host: String,
+    /// Who made the change: `user@hostname` from the CLI, `api:<address>` for API clients,
+    /// `host:<name>` for hosts joining through the server and `server` for expired hosts
+    pub actor: String,
+}
+
+/// Actor of changes made on this machine, as `user@hostname`
+pub fn local_actor() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| String::from("unknown"));
+    let hostname = host::local_hostname().unwrap_or_else(|_| String::from("localhost"));
+    format!("{}@{}", user, hostname)
+}
+
+/// Server response to a batch of events, listing which event ids it stored
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BatchReport {
+    pub accepted: Vec<Uuid>,
+    pub rejected: Vec<Uuid>,
+}
+
+impl BatchReport {
+    pub fn is_complete(&self) -> bool {
+        self.rejected.is_empty()
+    }
+}
+
+/// How `Event::send_with_retry` backs off between failed attempts
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles after every failed attempt.
+    pub base_delay: Duration,
+    /// Upper bound on the delay between attempts
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Make a single attempt and return its result
+    pub fn no_retry() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Delay to wait after the given (1 based) failed attempt. Jittered between half and all of
+    /// the exponential delay so peers retrying at once do not stay in lockstep.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        delay.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+    }
+}
+
+impl std::default::Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+        }
+    }
 }
 
-fn timestamp() -> anyhow::Result<u64> {
+fn timestamp() -> Result<u64> {
     use std::time::SystemTime;
     let now = SystemTime::now();
     Ok(now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
 }
 
 // FIXME: make private again
-/// Create a v1 uuid. If no node_id is passed uses the local machine's hostname instead
-pub fn uuidv1(node_id: Option<&str>) -> anyhow::Result<Uuid> {
-    let context = Context::new(rand::random());
-    let mut node_id = match node_id {
+/// Create a v1 uuid. If no node_id is passed uses the local machine's hostname instead.
+///
+/// The node id field of a v1 uuid is exactly 6 bytes, so the name is hashed down to fit. The same
+/// name always produces the same node id regardless of its length.
+pub fn uuidv1(node_id: Option<&str>) -> Result<Uuid> {
+    let node_id = match node_id {
         Some(node_id) => String::from(node_id),
         None => host::local_hostname()?,
     };
-    let padding = " ".repeat(0_isize.max(6 - (node_id.len() as isize)) as usize);
-    node_id.push_str(&padding);
-    let ts = Timestamp::from_unix(context, timestamp()?, 0);
-    Ok(Uuid::new_v1(ts, node_id.as_bytes())?)
+    Ok(Uuid::new_v1(v1_timestamp()?, &node_id_bytes(&node_id))?)
+}
+
+/// Create a v1 uuid using the MAC address of the primary network interface as the node id, as
+/// v1 uuids were designed around. Falls back to the hostname based node id of `uuidv1` when no
+/// interface has a usable MAC.
+pub fn uuidv1_from_mac() -> Result<Uuid> {
+    let mac = host::Interface::local()
+        .ok()
+        .and_then(|interfaces| host::primary_mac(&interfaces));
+    match mac {
+        Some(mac) => Ok(Uuid::new_v1(v1_timestamp()?, &mac)?),
+        None => uuidv1(None),
+    }
+}
+
+fn v1_timestamp() -> Result<Timestamp> {
+    let context = Context::new(rand::random());
+    Ok(Timestamp::from_unix(context, timestamp()?, 0))
+}
+
+/// Low 6 bytes of the name's hash, used as the node id of v1 uuids
+fn node_id_bytes(name: &str) -> [u8; 6] {
+    let hash = host::hash_hostname(name).to_be_bytes();
+    let mut out = [0; 6];
+    out.copy_from_slice(&hash[2..]);
+    out
+}
+
+/// `wg-quick <action> <path>`. The interface is named after the config file.
+pub fn wg_quick_command(action: &str, config_path: &Path) -> std::process::Command {
+    let mut cmd = std::process::Command::new("wg-quick");
+    cmd.arg(action).arg(config_path);
+    cmd
+}
+
+/// `wg syncconf <iface> <path>`, which replaces the peers of a running interface with the ones
+/// in the file without taking it down. The file must not hold wg-quick only settings such as
+/// `Address`, strip them with `wg-quick strip` first.
+pub fn wg_syncconf_command(iface_name: &str, config_path: &Path) -> std::process::Command {
+    let mut cmd = std::process::Command::new("wg");
+    cmd.arg("syncconf").arg(iface_name).arg(config_path);
+    cmd
+}
+
+/// Whether wireguard has an interface with the name, ie. `wg show <iface>` succeeds
+pub fn interface_is_up(iface_name: &str) -> bool {
+    std::process::Command::new("wg")
+        .arg("show")
+        .arg(iface_name)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Run a command, failing with its stderr if it does not succeed. Returns its stdout.
+fn run_command(mut cmd: std::process::Command) -> Result<Vec<u8>> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            command: format!("{:?}", cmd),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// Host settings shared by the `add-host` and `edit-host` subcommands
+fn host_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("interfaces")
+            .short('i')
+            .long("interface")
+            .multiple(true),
+        Arg::new("wireguard_address")
+            .short('a')
+            .long("wireguard-address")
+            .takes_value(true),
+        Arg::new("extra_addresses")
+            .long("extra-address")
+            .about("Secondary wireguard address, eg. an IPv6 address for a dual stack host")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        Arg::new("dynamic")
+            .long("dynamic")
+            .about("Roaming host removed by the server once it stops connecting"),
+        Arg::new("public_key")
+            .short('u')
+            .long("public-key")
+            .takes_value(true),
+        Arg::new("private_key")
+            .short('k')
+            .long("private-key")
+            .takes_value(true),
+        Arg::new("allowed_subnets")
+            .short('s')
+            .long("allowed-subnet")
+            .visible_alias("route")
+            .about("Extra subnet routed through this host, eg. a site's LAN")
+            .takes_value(true)
+            .multiple(true),
+        Arg::new("keepalive")
+            .long("keepalive")
+            .about("Seconds between keepalive packets, 0 to disable")
+            .takes_value(true),
+        Arg::new("mtu")
+            .long("mtu")
+            .about("MTU of the host's wireguard interface")
+            .takes_value(true),
+        Arg::new("post_up")
+            .long("post-up")
+            .about("Command to run after the interface is brought up")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        Arg::new("post_down")
+            .long("post-down")
+            .about("Command to run after the interface is taken down")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        Arg::new("role")
+            .long("role")
+            .about("Routing role of the host")
+            .takes_value(true)
+            .possible_values(&["client", "gateway", "relay", "dns-server"]),
+        Arg::new("out_interface")
+            .long("out-interface")
+            .about("Interface a gateway masquerades mesh traffic out of")
+            .takes_value(true),
+        Arg::new("endpoint")
+            .short('e')
+            .long("endpoint")
+            .about(
+                "Address peers connect to the host on, as host:port. The port defaults to the \
+                 host's listen port. `auto` discovers this machine's public address with STUN.",
+            )
+            .takes_value(true),
+        Arg::new("preshared_key")
+            .long("preshared-key")
+            .about("Preshared key for the link between this host and the local host")
+            .takes_value(true),
+        Arg::new("tags")
+            .long("tag")
+            .about("Label the host with a key=value tag")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        Arg::new("stun_server")
+            .long("stun-server")
+            .about("STUN server used to discover the endpoint with `--endpoint auto`")
+            .takes_value(true)
+            .default_value(host::DEFAULT_STUN_SERVER),
+        Arg::new("wireguard_port")
+            .short('p')
+            .long("wireguard-port")
+            .visible_alias("port")
+            .about(
+                "Port wireguard listens on on the host, instead of the network's listen port. \
+                 Also the port of an endpoint given without one.",
+            )
+            .takes_value(true),
+    ]
 }
 
 /// Build the command line interface
@@ -93,6 +653,24 @@ pub fn cli() -> clap::App<'static> {
                 .long("config")
                 .default_value("network.yaml"),
         )
+        .arg(
+            Arg::new("network")
+                .short('n')
+                .long("network")
+                .env("WGMESH_NETWORK")
+                .global(true)
+                .takes_value(true)
+                .about("Network to use from a config file holding several"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .global(true)
+                .possible_values(&["table", "json", "yaml"])
+                .default_value("table")
+                .about("Format results are printed in"),
+        )
         .arg(
             Arg::new("log_level")
                 .long("log-level")
@@ -100,148 +678,4104 @@ pub fn cli() -> clap::App<'static> {
                 .takes_value(true)
                 .default_value("info"),
         )
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .about("Write logs as text or as one JSON object per line"),
+        )
+        .arg(
+            Arg::new("log_file")
+                .long("log-file")
+                .takes_value(true)
+                .about("Also write logs to this file, rotating it once it grows too large"),
+        )
+        .arg(
+            Arg::new("log_file_size")
+                .long("log-file-size")
+                .default_value("10")
+                .about("Megabytes a log file grows to before it is rotated"),
+        )
+        .arg(
+            Arg::new("log_file_keep")
+                .long("log-file-keep")
+                .default_value("5")
+                .about("Number of rotated log files to keep"),
+        )
+        .subcommand(
+            clap::App::new("init")
+                .about("Create a new network config with this machine as its host")
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .about("Overwrite an existing config"),
+                ),
+        )
+        .subcommand(
+            clap::App::new("join")
+                .about("Join the network of a running server with this machine as its host")
+                .arg(
+                    Arg::new("url")
+                        .required(true)
+                        .about("Address of the server"),
+                )
+                .arg(
+                    Arg::new("token")
+                        .long("token")
+                        .env("WGMESH_TOKEN")
+                        .required(true)
+                        .takes_value(true)
+                        .about("Bearer token of the server"),
+                )
+                .arg(
+                    Arg::new("directory")
+                        .long("directory")
+                        .default_value("/etc/wireguard")
+                        .about("Directory to render the wireguard config to"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .about("Overwrite an existing config"),
+                ),
+        )
+        .subcommand(
+            clap::App::new("import")
+                .about("Create a network config from an existing wg-quick config")
+                .arg(
+                    Arg::new("directory")
+                        .long("directory")
+                        .default_value("/etc/wireguard")
+                        .about("Directory holding the wireguard config"),
+                )
+                .arg(
+                    Arg::new("interface")
+                        .long("interface")
+                        .default_value(INTERFACE_NAME)
+                        .about("Wireguard interface whose config is imported"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .about("Overwrite an existing config"),
+                ),
+        )
+        .subcommand(
+            clap::App::new("genkey")
+                .about("Print a new wireguard key pair")
+                .arg(
+                    Arg::new("preshared_key")
+                        .long("preshared-key")
+                        .about("Also print a preshared key"),
+                )
+                .arg(
+                    Arg::new("signing")
+                        .long("signing")
+                        .conflicts_with("preshared_key")
+                        .about("Print an ed25519 key pair for the server's --signing-key instead"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .conflicts_with("yaml")
+                        .about("Print the keys as JSON"),
+                )
+                .arg(
+                    Arg::new("yaml")
+                        .long("yaml")
+                        .about("Print the keys as YAML"),
+                ),
+        )
         .subcommand(
             clap::App::new("add-host")
                 .about("Add a host to the config")
                 .long_about("Add a host to the config")
                 .arg(Arg::new("name"))
+                .args(host_args()),
+        )
+        .subcommand(
+            clap::App::new("edit-host")
+                .about("Change settings of a host in the config, leaving the rest alone")
+                .arg(Arg::new("name").required(true))
+                .args(host_args())
                 .arg(
-                    Arg::new("interfaces")
-                        .short('i')
-                        .long("interface")
-                        .multiple(true),
+                    Arg::new("no_dynamic")
+                        .long("no-dynamic")
+                        .about("Keep the host even when it stops connecting")
+                        .conflicts_with("dynamic"),
                 )
                 .arg(
-                    Arg::new("wireguard_address")
-                        .short('a')
-                        .long("wireguard-address")
-                        .takes_value(true),
+                    Arg::new("remove_tags")
+                        .long("remove-tag")
+                        .about("Remove the tag with this key from the host")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                ),
+        )
+        .subcommand(
+            clap::App::new("remove-host")
+                .about("Remove host from the config")
+                .arg(Arg::new("name").required(true)),
+        )
+        .subcommand(
+            clap::App::new("validate")
+                .about("Check the config for problems. Exits non-zero if any are found."),
+        )
+        .subcommand(
+            clap::App::new("diff")
+                .about("Show how another config differs from this one")
+                .arg(Arg::new("other").required(true)),
+        )
+        .subcommand(
+            clap::App::new("status")
+                .about("Show how the peers of the running interface differ from the config"),
+        )
+        .subcommand(
+            clap::App::new("list-hosts")
+                .about("List the remote hosts in the config")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .about("Print the hosts as JSON"),
                 )
                 .arg(
-                    Arg::new("public_key")
-                        .short('u')
-                        .long("public-key")
-                        .takes_value(true),
+                    Arg::new("filter")
+                        .long("filter")
+                        .about("Only list hosts tagged key=value")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                ),
+        )
+        .subcommand(
+            clap::App::new("set-dns")
+                .about("Set the DNS servers used by the mesh")
+                .arg(
+                    Arg::new("dns")
+                        .long("dns")
+                        .about("Address of a DNS server. Omit to clear the DNS servers")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                ),
+        )
+        .subcommand(
+            clap::App::new("set-audit-log")
+                .about("Record every added, edited and removed host in a JSON lines file")
+                .arg(Arg::new("path").required_unless_present("disable"))
+                .arg(
+                    Arg::new("disable")
+                        .long("disable")
+                        .conflicts_with("path")
+                        .about("Stop recording changes"),
+                ),
+        )
+        .subcommand(
+            clap::App::new("set-interface")
+                .about("Set the wireguard interface the network is brought up on")
+                .arg(Arg::new("name").required(true)),
+        )
+        .subcommand(
+            clap::App::new("render")
+                .about("Render wireguard script from the config")
+                .arg(Arg::new("directory").required_unless_present("stdout"))
+                .arg(
+                    Arg::new("stdout")
+                        .long("stdout")
+                        .about("Write the rendered files to stdout instead of a directory"),
                 )
                 .arg(
-                    Arg::new("private_key")
-                        .short('k')
-                        .long("private-key")
-                        .takes_value(true),
+                    Arg::new("systemd")
+                        .long("systemd")
+                        .about("Also render a wg-quick@ systemd unit for the interface"),
                 )
                 .arg(
-                    Arg::new("wireguard_port")
-                        .short('p')
-                        .long("wireguard-port")
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .conflicts_with("stdout")
+                        .about(
+                            "Print a diff against the files in the directory instead of writing",
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::App::new("up")
+                .about("Render the local config and bring the interface up with wg-quick"),
+        )
+        .subcommand(clap::App::new("apply").about(
+            "Bring the interface up, or update the peers of a running one without restarting it",
+        ))
+        .subcommand(clap::App::new("down").about("Take the interface down with wg-quick"))
+        .subcommand(
+            clap::App::new("render-qr")
+                .about("Render a host's wireguard config as a QR code")
+                .arg(Arg::new("name").required(true))
+                .arg(
+                    Arg::new("png")
+                        .long("png")
+                        .about("Write the QR code to a PNG file instead of the terminal")
                         .takes_value(true),
                 ),
         )
         .subcommand(
-            clap::App::new("remove-host")
-                .about("Remove host from the config")
-                .arg(Arg::new("name")),
+            clap::App::new("export")
+                .about("Export the hosts of the network for other tools")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .possible_values(&["ansible", "hosts", "zone"])
+                        .default_value("ansible")
+                        .about(
+                            "ansible prints a YAML inventory grouped by role and tag, hosts \
+                             prints /etc/hosts entries, zone prints a DNS zone file",
+                        ),
+                )
+                .arg(
+                    Arg::new("domain")
+                        .long("domain")
+                        .default_value("mesh")
+                        .about("Domain the names in /etc/hosts entries and zone files are under"),
+                ),
         )
-        .subcommand(clap::App::new("render").about("Render wireguard script from the config"))
         .subcommand(
-            clap::App::new("server").about("Start server daemon").arg(
-                Arg::new("bind")
-                    .long("bind")
-                    .short('b')
-                    .default_value("0.0.0.0:64001"),
-            ),
+            clap::App::new("topology")
+                .about("Show which hosts peer with each other")
+                .arg(
+                    Arg::new("dot")
+                        .long("dot")
+                        .about("Print a Graphviz graph instead of a list of links"),
+                ),
+        )
+        .subcommand(
+            clap::App::new("server")
+                .about("Start server daemon")
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .short('b')
+                        .default_value("0.0.0.0:64001"),
+                )
+                .arg(
+                    Arg::new("heartbeat_timeout")
+                        .long("heartbeat-timeout")
+                        .default_value("90")
+                        .about("Seconds without a connect before a host is marked offline"),
+                )
+                .arg(
+                    Arg::new("host_expiry")
+                        .long("host-expiry")
+                        .default_value("86400")
+                        .about(
+                            "Seconds without a connect before a dynamic host is removed, 0 to \
+                             keep them",
+                        ),
+                )
+                .arg(
+                    Arg::new("save_interval")
+                        .long("save-interval")
+                        .default_value("60")
+                        .about("Seconds between saving the server state to disk"),
+                )
+                .arg(
+                    Arg::new("resolve_interval")
+                        .long("resolve-interval")
+                        .default_value("300")
+                        .about("Seconds between resolving hostname endpoints again, 0 to never"),
+                )
+                .arg(
+                    Arg::new("token")
+                        .long("token")
+                        .env("WGMESH_TOKEN")
+                        .takes_value(true)
+                        .about("Bearer token required by the API. Defaults to the config's token."),
+                )
+                .arg(
+                    Arg::new("signing_key")
+                        .long("signing-key")
+                        .env("WGMESH_SIGNING_KEY")
+                        .takes_value(true)
+                        .about(
+                            "Ed25519 key, from `genkey --signing`, to sign the served config and \
+                             host list with",
+                        ),
+                ),
         )
 }
 
+/// Which hosts peer with each other
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum Topology {
+    /// Every host peers with every other host
+    #[default]
+    FullMesh,
+    /// Every host only peers with the `hub` host, which peers with everyone and forwards traffic
+    /// between them. Keeps configs small in large networks.
+    HubAndSpoke { hub: String },
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     version: String,
     network_id: Uuid,
     subnet: IpNet,
+    /// RFC 4193 global id shared by the IPv6 addresses of every host in the network. Older
+    /// configs without one get a random id when they are loaded.
+    #[serde(default = "host::random_global_id")]
+    global_id: u64,
+    #[serde(default = "default_listen_port")]
+    listen_port: u16,
+    /// Resolvers pushed to every host in the mesh
+    #[serde(default)]
+    dns: Vec<IpAddr>,
+    /// Bearer token clients must present to the server daemon. Older configs without one get a
+    /// random token when they are loaded.
+    #[serde(default = "generate_api_token")]
+    api_token: String,
+    #[serde(default)]
+    topology: Topology,
+    /// Reach peers that can not be connected to directly through a relay. Applies to hosts
+    /// without an endpoint, whose peers without an endpoint are routed via the first relay
+    /// that has one.
+    #[serde(default)]
+    prefer_relay_for_nat: bool,
+    /// Wireguard interface the network is brought up on. Each network on a machine needs its
+    /// own.
+    #[serde(default = "default_interface_name")]
+    interface_name: String,
+    /// File each added, edited and removed host is appended to, one JSON object per line. No
+    /// log is kept when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    audit_log: Option<PathBuf>,
     host: Host,
     remote_hosts: HashMap<IpNet, Host>,
 }
 
-impl std::default::Default for Config {
-    fn default() -> Self {
-        let host = Host::local().unwrap();
-        Config {
-            version: String::from("v1"),
-            network_id: uuidv1(Some(&host.name)).unwrap(),
-            subnet: "10.42.0.0/24".parse().unwrap(),
-            host,
-            remote_hosts: HashMap::new(),
-        }
-    }
+fn default_listen_port() -> u16 {
+    DEFAULT_LISTEN_PORT
 }
 
-impl Config {
-    /// Load config from the given path
-    pub fn try_from_path(path: &str) -> anyhow::Result<Self> {
-        let file = File::open(path)?;
-        Ok(serde_yaml::from_reader(file)?)
-    }
+fn default_interface_name() -> String {
+    String::from(INTERFACE_NAME)
+}
 
-    /// Save the config to the given file path.
-    pub fn save(&self, path: &str) -> anyhow::Result<()> {
-        let file = File::create(path)?;
-        serde_yaml::to_writer(file, self)?;
-        Ok(())
+/// Check that an interface name is one wg-quick accepts: at most 15 characters, the kernel's
+/// limit, of letters, digits and `_=+.-`
+pub fn validate_interface_name(name: &str) -> Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_=+.-".contains(c));
+    if name.is_empty() || name.len() > 15 || !valid_chars {
+        return Err(Error::InvalidInterfaceName(name.to_owned()));
     }
+    Ok(())
+}
 
-    /// Render the config into wireguard setup scripts. Scripts will be placed in the given
-    /// directory. Any existing files will be overwritten.
-    pub fn render(&self, directory: &str) {
-        unimplemented!()
+/// Write a file only its owner can read, such as a rendered config holding a private key.
+/// An existing file is truncated and its permissions tightened.
+fn write_private<P: AsRef<Path>>(path: P, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        write_all(file, contents)
     }
+    #[cfg(not(unix))]
+    write_all(options.open(path)?, contents)
+}
 
-    /// Adds a host to the config. Can fail if a host with the same name or addresses already
-    /// exists.
-    pub fn add_host(&mut self, host: Host) -> anyhow::Result<()> {
-        for (_, existing_host) in self.remote_hosts.iter() {
-            if existing_host.name == host.name {
-                return Err(anyhow::anyhow!(
-                    "host with name \"{}\" already exists",
-                    host.name
-                ));
-            }
+fn write_all(mut file: File, contents: &[u8]) -> std::io::Result<()> {
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Write to a temporary file next to `path` and rename it into place so readers never see a
+/// partially written file. An existing file keeps its permissions, a new one is only readable by
+/// its owner since configs hold private keys.
+pub fn write_atomic(path: &str, contents: &[u8]) -> Result<()> {
+    // unique per process so concurrent writers never share a temporary file
+    let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+    let write = || -> Result<()> {
+        // a leftover from a crashed writer that had the same pid would keep its permissions
+        let _ = std::fs::remove_file(&tmp_path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
         }
-        self.remote_hosts.insert(host.wireguard_address, host);
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        // persist the rename itself, not just the file contents
+        let parent = match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
         Ok(())
-    }
+    };
+    // leave the original alone and do not litter the directory if anything fails
+    write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })
+}
 
-    /// Remove a host from the config by name
-    pub fn remove_host(&mut self, ip: &IpNet) {
-        self.remote_hosts.remove(ip);
-    }
+/// File format of a config, chosen by its extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
 
-    pub fn hosts_by_name<'a>(&'a self) -> HashMap<String, &'a Host> {
-        let mut out = HashMap::new();
-        for host in self.remote_hosts.values() {
-            out.insert(host.name.clone(), host);
+impl ConfigFormat {
+    /// `.toml` and `.json` files use those formats, anything else is YAML
+    pub fn from_path(path: &str) -> Self {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
         }
-        out
+    }
+
+    /// Parse into a generic value so migrations work the same for every format
+    fn parse(self, text: &str) -> Result<serde_yaml::Value> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+            ConfigFormat::Toml => toml::from_str(text)?,
+            ConfigFormat::Json => serde_json::from_str(text)?,
+        })
+    }
+
+    fn serialize<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+            // going through a toml value writes plain fields before tables, which toml requires
+            ConfigFormat::Toml => toml::to_string(&toml::Value::try_from(value)?)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(value)?,
+        })
     }
 }
 
-/// Equivalent to `wg pubkey < private_key`
-pub fn generate_public_key(private_key: &str) -> anyhow::Result<String> {
-    let mut cmd = Command::new("wg")
-        .arg("pubkey")
-        .stdin(Stdio::piped())
-        .spawn()?;
-    {
-        let stdin = cmd
-            .stdin
-            .as_mut()
-            .ok_or(anyhow::anyhow!("could not open process stdin"))?;
-        //.ok_or(Err("Could not open process stdin"))?;
-        stdin.write_all(private_key.as_bytes())?;
+/// Keys of a single host, as stored in a secrets file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HostSecrets {
+    #[serde(default)]
+    pub private_key: String,
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+}
+
+/// Secret half of a config split by `Config::save_with_secrets`, so the public half can be
+/// shared or committed. Host keys are looked up by host name.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Secrets {
+    #[serde(default)]
+    pub api_token: Option<String>,
+    #[serde(default)]
+    pub hosts: HashMap<String, HostSecrets>,
+}
+
+/// Advisory lock on a config file, held across load-modify-save sequences so the CLI and
+/// server do not clobber each other's changes. Released when dropped.
+///
+/// The lock is taken on a `.lock` file next to the config because saves replace the config
+/// file itself.
+#[derive(Debug)]
+pub struct ConfigLock {
+    file: File,
+}
+
+impl ConfigLock {
+    /// Take the lock if no other process holds it
+    pub fn try_acquire(config_path: &str) -> Result<Option<Self>> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(format!("{}.lock", config_path))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(ConfigLock { file })),
+            Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Wait up to `timeout` for the lock
+    pub fn acquire(config_path: &str, timeout: Duration) -> Result<Self> {
+        let started = std::time::Instant::now();
+        loop {
+            if let Some(lock) = Self::try_acquire(config_path)? {
+                return Ok(lock);
+            }
+            if started.elapsed() >= timeout {
+                return Err(Error::LockTimeout {
+                    path: config_path.to_owned(),
+                    timeout,
+                });
+            }
+            std::thread::sleep(CONFIG_LOCK_RETRY);
+        }
     }
-    Ok(String::from_utf8(cmd.wait_with_output()?.stdout)?)
 }
 
-/// Equivalent to `wg genkey`
-pub fn generate_private_key() -> anyhow::Result<String> {
-    let cmd = Command::new("wg").arg("genkey").output()?;
-    Ok(String::from_utf8(cmd.stdout)?)
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// Random url safe token for authenticating requests to the server daemon
+pub fn generate_api_token() -> String {
+    base64::encode_config(rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Subnet of new networks
+const DEFAULT_SUBNET: &str = "10.42.0.0/24";
+
+/// Empty network whose host has no name or keys, at the first address of the default subnet.
+/// Built entirely in memory, use `Config::new` for a network run by the local machine.
+impl std::default::Default for Config {
+    fn default() -> Self {
+        let subnet: IpNet = DEFAULT_SUBNET.parse().unwrap();
+        let mut host = Host::default();
+        host.wireguard_address =
+            IpNet::new(subnet.hosts().next().unwrap(), subnet.prefix_len()).unwrap();
+        Config {
+            version: String::from(CONFIG_VERSION),
+            network_id: Uuid::nil(),
+            subnet,
+            global_id: 0,
+            listen_port: DEFAULT_LISTEN_PORT,
+            dns: Vec::new(),
+            api_token: String::new(),
+            topology: Topology::default(),
+            prefer_relay_for_nat: false,
+            interface_name: default_interface_name(),
+            audit_log: None,
+            host,
+            remote_hosts: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Start a new network with the local machine as its host at a random address. Fails when
+    /// the hostname or interfaces of the machine can not be read.
+    pub fn new() -> Result<Self> {
+        let mut config = Config {
+            network_id: uuidv1_from_mac()?,
+            global_id: host::random_global_id(),
+            api_token: generate_api_token(),
+            host: Host::local()?,
+            ..Config::default()
+        };
+        config.host.wireguard_address = config.random_address()?;
+        Ok(config)
+    }
+
+    /// Start a new network with the local machine as its host and a freshly generated key pair,
+    /// saved to `path`. Refuses to replace an existing file unless `force` is set. With a
+    /// `network` name the new network is added to the file next to the ones already in it.
+    pub fn init(path: &str, network: Option<&str>, force: bool) -> Result<Self> {
+        let exists = match network {
+            Some(name) => network_names(path)?.iter().any(|existing| existing == name),
+            None => Path::new(path).exists(),
+        };
+        if !force && exists {
+            return Err(Error::AlreadyExists(network.unwrap_or(path).to_owned()));
+        }
+        let mut config = Config::new()?;
+        config.host.private_key = generate_private_key()?;
+        config.host.public_key = generate_public_key(&config.host.private_key)?;
+        config.save_network(path, network)?;
+        Ok(config)
+    }
+
+    /// Adopt an existing deployment by reading the wg-quick config of the named interface in
+    /// `directory`, as `render` would write it. The local host is named after the machine and the
+    /// network's subnet is taken from its primary address. Peers are named by the comment above
+    /// their key, if any.
+    pub fn import_wg_conf(directory: &str, interface_name: &str) -> Result<Self> {
+        validate_interface_name(interface_name)?;
+        let path = Path::new(directory).join(format!("{}.conf", interface_name));
+        let sections = wgconf::parse(&std::fs::read_to_string(path)?)?;
+        let interface = sections
+            .iter()
+            .find(|section| section.name == "interface")
+            .ok_or(Error::MissingSection("Interface"))?;
+        let mut host = Host::from_wg_interface(interface)?;
+        host.name = host::local_hostname().unwrap_or_else(|_| String::from("localhost"));
+        let subnet = host.wireguard_address.trunc();
+        let global_id = match subnet {
+            // the unique local prefix holds the global id, see `host::generate_ipv6`
+            IpNet::V6(net) => {
+                let segments = net.addr().segments();
+                (u64::from(segments[0] & 0xff) << 32)
+                    | (u64::from(segments[1]) << 16)
+                    | u64::from(segments[2])
+            }
+            IpNet::V4(_) => host::random_global_id(),
+        };
+        let mut config = Config {
+            network_id: uuidv1_from_mac()?,
+            subnet,
+            global_id,
+            listen_port: interface
+                .parsed("ListenPort")?
+                .unwrap_or(DEFAULT_LISTEN_PORT),
+            // search domains are not kept, only resolver addresses
+            dns: interface
+                .list("DNS")
+                .iter()
+                .filter_map(|dns| dns.parse().ok())
+                .collect(),
+            api_token: generate_api_token(),
+            interface_name: interface_name.to_string(),
+            host,
+            ..Config::default()
+        };
+        for peer in sections.iter().filter(|section| section.name == "peer") {
+            config.add_host(Host::from_wg_peer(peer, subnet)?)?;
+        }
+        Ok(config)
+    }
+
+    /// Load config from the given path. Configs written by older versions are migrated to
+    /// `CONFIG_VERSION` and saved back to the path. Files holding several networks only load
+    /// when they hold exactly one, use `load_network` to pick one.
+    pub fn try_from_path(path: &str) -> Result<Self> {
+        Config::load_network(path, None)
+    }
+
+    /// Load the named network from a file holding several. The name can be left out when the
+    /// file holds a single network, in either format.
+    pub fn load_network(path: &str, network: Option<&str>) -> Result<Self> {
+        let mut document = read_document(path)?;
+        let name = resolve_network(path, &document, network)?;
+        let mut value = match &name {
+            Some(name) => document
+                .get_mut(NETWORKS_KEY)
+                .and_then(|networks| networks.get_mut(name.as_str()))
+                .map(|value| std::mem::replace(value, serde_yaml::Value::Null))
+                .ok_or_else(|| Error::NetworkNotFound {
+                    path: path.to_owned(),
+                    network: name.clone(),
+                })?,
+            None => document,
+        };
+        let migrated = Config::migrate(&mut value)?;
+        let config: Config = serde_yaml::from_value(value)?;
+        if migrated {
+            log::info!("migrated {} to config version {}", path, CONFIG_VERSION);
+            config.save_network(path, name.as_deref())?;
+        }
+        Ok(config)
+    }
+
+    /// Save the config as the named network of a file holding several, leaving the other
+    /// networks in it alone. A file holding a single network is converted, its network is kept
+    /// under `DEFAULT_NETWORK`. Without a name this is the same as `save`, unless the file
+    /// already holds networks, in which case its only one is replaced.
+    pub fn save_network(&self, path: &str, network: Option<&str>) -> Result<()> {
+        let mut document = if Path::new(path).exists() {
+            read_document(path)?
+        } else {
+            serde_yaml::Value::Mapping(serde_yaml::Mapping::new())
+        };
+        let name = match resolve_network(path, &document, network)? {
+            Some(name) => name,
+            None => return self.save(path),
+        };
+        if document.get(NETWORKS_KEY).is_none() {
+            let mut networks = serde_yaml::Mapping::new();
+            if Path::new(path).exists() {
+                log::info!(
+                    "moving the network in {} to \"{}\" to make room for \"{}\"",
+                    path,
+                    DEFAULT_NETWORK,
+                    name
+                );
+                networks.insert(DEFAULT_NETWORK.into(), document);
+            }
+            let mut converted = serde_yaml::Mapping::new();
+            converted.insert(NETWORKS_KEY.into(), serde_yaml::Value::Mapping(networks));
+            document = serde_yaml::Value::Mapping(converted);
+        }
+        if let Some(serde_yaml::Value::Mapping(networks)) = document.get_mut(NETWORKS_KEY) {
+            networks.insert(name.into(), serde_yaml::to_value(self)?);
+        }
+        let text = ConfigFormat::from_path(path).serialize(&document)?;
+        write_atomic(path, text.as_bytes())
+    }
+
+    /// Upgrade a raw config to the current schema one version at a time. Returns whether
+    /// anything changed. Fields added since a version with a serde default are filled in when the
+    /// migrated value is deserialized, so steps only handle changes defaults can not express.
+    fn migrate(value: &mut serde_yaml::Value) -> Result<bool> {
+        let mapping = value
+            .as_mapping_mut()
+            .ok_or_else(|| Error::MalformedConfig(String::from("config is not a mapping")))?;
+        let version_key = serde_yaml::Value::from("version");
+        let mut migrated = false;
+        loop {
+            // configs from before versioning have no version field at all
+            let version = match mapping.get(&version_key) {
+                None => "v0",
+                Some(version) => version.as_str().ok_or_else(|| {
+                    Error::MalformedConfig(String::from("config version is not a string"))
+                })?,
+            };
+            let next = match version {
+                CONFIG_VERSION => return Ok(migrated),
+                "v0" => "v1",
+                unknown => {
+                    return Err(Error::UnsupportedVersion {
+                        found: unknown.to_owned(),
+                        supported: CONFIG_VERSION,
+                    })
+                }
+            };
+            mapping.insert(version_key.clone(), serde_yaml::Value::from(next));
+            migrated = true;
+        }
+    }
+
+    /// Save the config to the given file path.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let text = ConfigFormat::from_path(path).serialize(self)?;
+        write_atomic(path, text.as_bytes())
+    }
+
+    /// Load the public config from `config_path` and fill in the keys stored in `secrets_path`.
+    /// A missing secrets file leaves the keys empty.
+    pub fn load_with_secrets(config_path: &str, secrets_path: &str) -> Result<Self> {
+        let mut config = Config::try_from_path(config_path)?;
+        if !Path::new(secrets_path).exists() {
+            log::warn!("{} does not exist, host keys are empty", secrets_path);
+        } else {
+            let text = std::fs::read_to_string(secrets_path)?;
+            let secrets: Secrets =
+                serde_yaml::from_value(ConfigFormat::from_path(secrets_path).parse(&text)?)?;
+            config.apply_secrets(&secrets);
+        }
+        // the server must never accept an empty token
+        if config.api_token.is_empty() {
+            log::warn!("no api token configured, generating a new one");
+            config.api_token = generate_api_token();
+        }
+        Ok(config)
+    }
+
+    /// Save the config with its keys and api token blanked to `config_path`, and the secrets
+    /// alone to `secrets_path`
+    pub fn save_with_secrets(&self, config_path: &str, secrets_path: &str) -> Result<()> {
+        let text = ConfigFormat::from_path(secrets_path).serialize(&self.secrets())?;
+        write_atomic(secrets_path, text.as_bytes())?;
+        self.without_secrets().save(config_path)
+    }
+
+    /// Secrets of the config, keyed by host name
+    pub fn secrets(&self) -> Secrets {
+        let hosts = std::iter::once(&self.host)
+            .chain(self.remote_hosts.values())
+            .map(|host| {
+                let secrets = HostSecrets {
+                    private_key: host.private_key.clone(),
+                    preshared_key: host.preshared_key.clone(),
+                };
+                (host.name.clone(), secrets)
+            })
+            .filter(|(_, secrets)| *secrets != HostSecrets::default())
+            .collect();
+        Secrets {
+            api_token: Some(self.api_token.clone()).filter(|token| !token.is_empty()),
+            hosts,
+        }
+    }
+
+    /// Fill in the keys of every host named in `secrets`
+    pub fn apply_secrets(&mut self, secrets: &Secrets) {
+        if let Some(api_token) = &secrets.api_token {
+            self.api_token = api_token.clone();
+        }
+        for host in std::iter::once(&mut self.host).chain(self.remote_hosts.values_mut()) {
+            if let Some(keys) = secrets.hosts.get(&host.name) {
+                host.private_key = keys.private_key.clone();
+                host.preshared_key = keys.preshared_key.clone();
+            }
+        }
+    }
+
+    /// Render the config into wireguard setup scripts. Scripts will be placed in the given
+    /// directory. Any existing files will be overwritten.
+    pub fn render(&self, directory: &str) -> Result<()> {
+        let path = Path::new(directory).join(format!("{}.conf", self.interface_name));
+        write_private(path, self.render_config()?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Render the local host's config and bring its interface up with `wg-quick up`
+    pub fn up(&self) -> Result<()> {
+        self.wg_quick("up")
+    }
+
+    /// Bring the interface up, or when it already is, update its peers in place with
+    /// `wg syncconf` so existing tunnels stay up. Interface settings such as its addresses and
+    /// hooks only change on the next `up`.
+    pub fn apply(&self) -> Result<()> {
+        if !interface_is_up(&self.interface_name) {
+            return self.up();
+        }
+        self.with_runtime_config(|path| {
+            let stripped = run_command(wg_quick_command("strip", path))?;
+            let stripped_path = path.with_extension("stripped.conf");
+            write_private(&stripped_path, &stripped)?;
+            run_command(wg_syncconf_command(&self.interface_name, &stripped_path))?;
+            Ok(())
+        })
+    }
+
+    /// Render the local host's config and take its interface down with `wg-quick down`
+    pub fn down(&self) -> Result<()> {
+        self.wg_quick("down")
+    }
+
+    /// Run `wg-quick` against a freshly rendered config
+    fn wg_quick(&self, action: &str) -> Result<()> {
+        let output =
+            self.with_runtime_config(|path| Ok(wg_quick_command(action, path).output()?))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if action == "up" && stderr.contains("already exists") {
+            return Err(Error::InterfaceUp {
+                interface: self.interface_name.clone(),
+                stderr: stderr.trim().to_owned(),
+            });
+        }
+        Err(Error::CommandFailed {
+            command: format!("wg-quick {}", action),
+            status: output.status,
+            stderr: stderr.trim().to_owned(),
+        })
+    }
+
+    /// Write the rendered config for the wireguard tools to read and run `f` with its path. It
+    /// holds the private key, so it goes to a new directory only the current user can read,
+    /// which is removed again afterwards. The directory gets a random name and must not exist
+    /// yet, so other users of the shared temp dir can not plant it to read or swap the config.
+    fn with_runtime_config<T>(&self, f: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        let directory = std::env::temp_dir().join(format!(
+            "wgmesh-{}-{:016x}",
+            self.network_id,
+            rand::random::<u64>()
+        ));
+        let mut builder = std::fs::DirBuilder::new();
+        // fails when the directory exists, rather than reusing whatever is there
+        builder.recursive(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&directory)?;
+        let path = directory.join(format!("{}.conf", self.interface_name));
+        let result = self
+            .render_config()
+            .and_then(|config| Ok(write_private(&path, config.as_bytes())?))
+            .and_then(|_| f(&path));
+        if let Err(err) = std::fs::remove_dir_all(&directory) {
+            log::warn!("unable to remove {}: {}", directory.display(), err);
+        }
+        result
+    }
+
+    /// Render a `wg-quick@` systemd unit for the given interface into the directory so that
+    /// `systemctl enable --now wg-quick@<iface_name>` brings the rendered config up at boot.
+    pub fn render_systemd(&self, directory: &str, iface_name: &str) -> Result<()> {
+        let path = Path::new(directory).join(systemd_unit_name(iface_name));
+        std::fs::write(path, render_systemd_unit(iface_name)?)?;
+        Ok(())
+    }
+
+    /// Write the rendered files to the writer instead of a directory. Each file is preceded by
+    /// a `# === <file name> ===` header line.
+    pub fn render_to_writer<W: Write>(&self, writer: &mut W, systemd: bool) -> Result<()> {
+        for (name, contents) in self.rendered_files(systemd)? {
+            writeln!(writer, "# === {} ===", name)?;
+            writer.write_all(contents.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Unified diff between the files in the directory and what `render` would write there,
+    /// without writing anything. Empty when rendering would change nothing.
+    pub fn render_diff(&self, directory: &str, systemd: bool) -> Result<String> {
+        let mut out = String::new();
+        for (name, contents) in self.rendered_files(systemd)? {
+            let path = Path::new(directory).join(&name);
+            let (existing, old_label) = match std::fs::read_to_string(&path) {
+                Ok(existing) => (existing, path.display().to_string()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    (String::new(), String::from("/dev/null"))
+                }
+                Err(err) => return Err(err.into()),
+            };
+            out.push_str(&diff::unified(
+                &existing,
+                &contents,
+                &old_label,
+                &path.display().to_string(),
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Names and contents of the files `render` writes
+    fn rendered_files(&self, systemd: bool) -> Result<Vec<(String, String)>> {
+        let mut files = vec![(
+            format!("{}.conf", self.interface_name),
+            self.render_config()?,
+        )];
+        if systemd {
+            files.push((
+                systemd_unit_name(&self.interface_name),
+                render_systemd_unit(&self.interface_name)?,
+            ));
+        }
+        Ok(files)
+    }
+
+    /// Render the wg-quick config of the named remote host as a QR code PNG written to the
+    /// given path. Useful for onboarding mobile clients.
+    pub fn render_qr(&self, host_name: &str, path: &str) -> Result<()> {
+        let config = self.for_host(host_name)?.render_config()?;
+        write_private(path, &qr::png(&config)?)?;
+        Ok(())
+    }
+
+    /// Render the wg-quick config of the named remote host as a QR code printable in a terminal
+    pub fn render_qr_terminal(&self, host_name: &str) -> Result<String> {
+        qr::terminal(&self.for_host(host_name)?.render_config()?)
+    }
+
+    /// The local and remote hosts sorted by name
+    fn all_hosts(&self) -> Vec<&Host> {
+        let mut hosts: Vec<&Host> = std::iter::once(&self.host)
+            .chain(self.remote_hosts.values())
+            .collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        hosts
+    }
+
+    /// Pairs of hosts that peer with each other under the config's topology, sorted by name
+    pub fn peerings(&self) -> Vec<(&Host, &Host)> {
+        let hosts = self.all_hosts();
+        let mut pairs = Vec::new();
+        for (i, host) in hosts.iter().enumerate() {
+            for other in hosts.iter().skip(i + 1) {
+                let linked = match &self.topology {
+                    Topology::FullMesh => true,
+                    Topology::HubAndSpoke { hub } => host.name == *hub || other.name == *hub,
+                };
+                if linked {
+                    pairs.push((*host, *other));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Names of the hosts outside the largest group of hosts connected by peerings, sorted
+    pub fn isolated_hosts(&self) -> Vec<&str> {
+        let hosts = self.all_hosts();
+        let mut links: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (a, b) in self.peerings() {
+            links.entry(&a.name).or_default().push(&b.name);
+            links.entry(&b.name).or_default().push(&a.name);
+        }
+        // flood fill from each host not yet reached to find the connected groups
+        let mut group_of: HashMap<&str, usize> = HashMap::new();
+        let mut sizes: Vec<usize> = Vec::new();
+        for host in &hosts {
+            if group_of.contains_key(host.name.as_str()) {
+                continue;
+            }
+            let group = sizes.len();
+            let mut size = 0;
+            let mut pending = vec![host.name.as_str()];
+            while let Some(name) = pending.pop() {
+                if group_of.insert(name, group).is_some() {
+                    continue;
+                }
+                size += 1;
+                pending.extend(links.get(name).into_iter().flatten());
+            }
+            sizes.push(size);
+        }
+        // ties go to the group found first, the one with the first host by name
+        let largest = (0..sizes.len())
+            .max_by(|a, b| sizes[*a].cmp(&sizes[*b]).then(b.cmp(a)))
+            .unwrap_or(0);
+        hosts
+            .into_iter()
+            .map(|host| host.name.as_str())
+            .filter(|name| group_of[name] != largest)
+            .collect()
+    }
+
+    /// Ansible YAML inventory of every host, grouped by role (`role_gateway`) and by tag
+    /// (`tag_datacenter_ams1`). Hosts are reached on the address of their endpoint, or their
+    /// wireguard address when they have none.
+    pub fn to_ansible_inventory(&self) -> Result<String> {
+        // ansible group names may only contain letters, digits and underscores
+        let group_name = |parts: &[&str]| -> String {
+            parts
+                .join("_")
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect::<String>()
+                .to_lowercase()
+        };
+        let mut hosts = BTreeMap::new();
+        let mut groups: BTreeMap<String, BTreeMap<String, serde_yaml::Value>> = BTreeMap::new();
+        for host in self.all_hosts() {
+            let ansible_host = match host.endpoint.as_deref().and_then(endpoint_address) {
+                Some(address) => address.to_string(),
+                None => host.wireguard_address.addr().to_string(),
+            };
+            let mut vars = BTreeMap::new();
+            vars.insert("ansible_host", ansible_host);
+            hosts.insert(host.name.clone(), vars);
+            let role = match host.role {
+                HostRole::Client => "client",
+                HostRole::Gateway { .. } => "gateway",
+                HostRole::Relay => "relay",
+                HostRole::DnsServer => "dns_server",
+            };
+            let mut names = vec![group_name(&["role", role])];
+            names.extend(
+                host.tags
+                    .iter()
+                    .map(|(key, value)| group_name(&["tag", key, value])),
+            );
+            for name in names {
+                groups
+                    .entry(name)
+                    .or_default()
+                    .insert(host.name.clone(), serde_yaml::Value::Null);
+            }
+        }
+        let children: BTreeMap<String, BTreeMap<&str, _>> = groups
+            .into_iter()
+            .map(|(name, members)| {
+                let mut group = BTreeMap::new();
+                group.insert("hosts", members);
+                (name, group)
+            })
+            .collect();
+        let mut all = BTreeMap::new();
+        all.insert("hosts", serde_yaml::to_value(hosts)?);
+        all.insert("children", serde_yaml::to_value(children)?);
+        let mut inventory = BTreeMap::new();
+        inventory.insert("all", all);
+        Ok(serde_yaml::to_string(&inventory)?)
+    }
+
+    /// `/etc/hosts` entries naming every address of every host, as `<name>.<domain>` and the
+    /// bare `<name>`
+    pub fn to_hosts_file(&self, domain: &str) -> String {
+        let domain = domain.trim_matches('.');
+        let mut out = format!("# wgmesh network {}\n", self.network_id);
+        for host in self.all_hosts() {
+            for address in host.addresses() {
+                if domain.is_empty() {
+                    out.push_str(&format!("{}\t{}\n", address.addr(), host.name));
+                } else {
+                    out.push_str(&format!(
+                        "{}\t{}.{} {}\n",
+                        address.addr(),
+                        host.name,
+                        domain,
+                        host.name
+                    ));
+                }
+            }
+        }
+        out
+    }
+
+    /// DNS zone file with an A or AAAA record for every address of every host under `domain`.
+    /// Host names are turned into valid DNS labels, hosts whose name has nothing usable left are
+    /// skipped. The SOA serial is a hash of the records, so it changes whenever they do.
+    pub fn to_zone_file(&self, domain: &str) -> String {
+        let domain = domain.trim_matches('.');
+        let mut records = String::new();
+        for host in self.all_hosts() {
+            let label = match dns_label(&host.name) {
+                Some(label) => label,
+                None => {
+                    log::warn!("\"{}\" is not usable as a DNS name, skipping it", host.name);
+                    continue;
+                }
+            };
+            for address in host.addresses() {
+                let kind = match address {
+                    IpNet::V4(_) => "A",
+                    IpNet::V6(_) => "AAAA",
+                };
+                records.push_str(&format!(
+                    "{:<24} IN {:<4} {}\n",
+                    label,
+                    kind,
+                    address.addr()
+                ));
+            }
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(&records, &mut hasher);
+        // serials are 32 bit and 0 is best avoided
+        let serial = (std::hash::Hasher::finish(&hasher) as u32).max(1);
+        let mut out = format!("$ORIGIN {}.\n$TTL 300\n", domain);
+        out.push_str(&format!(
+            "@ IN SOA ns.{}. hostmaster.{}. ({} 3600 600 604800 300)\n",
+            domain, domain, serial
+        ));
+        out.push_str(&records);
+        out
+    }
+
+    /// Graphviz graph of the network with a node per host and an edge per peering. Gateways,
+    /// relays and DNS servers are colored by role.
+    pub fn to_dot(&self) -> String {
+        // names are user input, keep them from breaking out of the quoted ids
+        let quote = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let hosts = self.all_hosts();
+        let mut out = String::from("graph wgmesh {\n    node [shape=box, style=filled];\n");
+        for host in hosts {
+            let color = match host.role {
+                HostRole::Client => "white",
+                HostRole::Gateway { .. } => "orange",
+                HostRole::Relay => "lightblue",
+                HostRole::DnsServer => "palegreen",
+            };
+            out.push_str(&format!(
+                "    \"{}\" [label=\"{}\\n{}\", fillcolor={}];\n",
+                quote(&host.name),
+                quote(&host.name),
+                host.wireguard_address,
+                color
+            ));
+        }
+        for (a, b) in self.peerings() {
+            out.push_str(&format!(
+                "    \"{}\" -- \"{}\";\n",
+                quote(&a.name),
+                quote(&b.name)
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Return the config as seen from the named remote host, with the local host as one of its
+    /// peers. Preshared keys are kept for the link between the two hosts so both sides render
+    /// the same key, the remaining peers' keys do not apply to the named host and are dropped.
+    /// Only the named host keeps its private key.
+    pub fn for_host(&self, name: &str) -> Result<Config> {
+        let address = self
+            .hosts_by_name()
+            .get(name)
+            .map(|host| host.wireguard_address)
+            .ok_or_else(|| Error::HostNotFound(name.to_string()))?;
+        let mut config = self.clone();
+        let host = config.remote_hosts.remove(&address).unwrap();
+        let mut local = std::mem::replace(&mut config.host, host);
+        for peer in config.remote_hosts.values_mut() {
+            peer.private_key = String::new();
+            peer.preshared_key = None;
+        }
+        local.private_key = String::new();
+        local.preshared_key = config.host.preshared_key.clone();
+        config.remote_hosts.insert(local.wireguard_address, local);
+        Ok(config)
+    }
+
+    /// Render the wg-quick config for the local host. Each remote host gets a peer section
+    /// routing only its own wireguard address.
+    pub fn render_config(&self) -> Result<String> {
+        let mut out = String::new();
+        writeln!(out, "[Interface]")?;
+        writeln!(out, "PrivateKey = {}", self.host.private_key)?;
+        writeln!(out, "Address = {}", join(&self.host.addresses()))?;
+        writeln!(
+            out,
+            "ListenPort = {}",
+            self.host.listen_port.unwrap_or(self.listen_port)
+        )?;
+        let dns = self.dns_servers();
+        if !dns.is_empty() {
+            writeln!(out, "DNS = {}", join(&dns))?;
+        }
+        if let Some(mtu) = self.host.mtu {
+            let min_mtu = match self.host.wireguard_address {
+                IpNet::V4(_) => MIN_IPV4_MTU,
+                IpNet::V6(_) => MIN_IPV6_MTU,
+            };
+            if mtu < min_mtu || mtu > MAX_MTU {
+                return Err(Error::InvalidMtu {
+                    mtu,
+                    min: min_mtu,
+                    max: MAX_MTU,
+                });
+            }
+            writeln!(out, "MTU = {}", mtu)?;
+        }
+        let (mut post_up, mut post_down) = match &self.host.role {
+            HostRole::Gateway { out_interface } => host::nat_masquerade_rules(out_interface),
+            _ => (Vec::new(), Vec::new()),
+        };
+        post_up.extend(self.host.post_up.iter().cloned());
+        post_down.extend(self.host.post_down.iter().cloned());
+        for command in post_up {
+            writeln!(out, "PostUp = {}", command)?;
+        }
+        for command in post_down {
+            writeln!(out, "PostDown = {}", command)?;
+        }
+        for (peer, allowed_ips) in self.peer_allowed_ips()? {
+            writeln!(out)?;
+            writeln!(out, "[Peer]")?;
+            writeln!(out, "# {}", peer.name)?;
+            writeln!(out, "PublicKey = {}", peer.public_key)?;
+            if let Some(preshared_key) = &peer.preshared_key {
+                writeln!(out, "PresharedKey = {}", preshared_key)?;
+            }
+            // prefer the address the server last resolved a hostname endpoint to
+            let endpoint = peer
+                .resolved_endpoint
+                .map(|endpoint| endpoint.to_string())
+                .or_else(|| peer.endpoint.clone());
+            if let Some(endpoint) = endpoint {
+                writeln!(out, "Endpoint = {}", endpoint)?;
+            }
+            writeln!(out, "AllowedIPs = {}", join(&allowed_ips))?;
+            let keepalive = match (peer.persistent_keepalive, &peer.role) {
+                (None, HostRole::Relay) => Some(DEFAULT_RELAY_KEEPALIVE),
+                (keepalive, _) => keepalive,
+            };
+            if let Some(keepalive) = keepalive.filter(|k| *k > 0) {
+                writeln!(out, "PersistentKeepalive = {}", keepalive)?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Compute the AllowedIPs of every remote host, sorted by host name. Fails if two hosts
+    /// would route the same network, see `routes_conflict`.
+    /// Clients route all traffic through the gateway, if the mesh has one. In a hub and spoke
+    /// network the spokes only peer with the hub, which routes the networks of every host.
+    /// With `prefer_relay_for_nat` a host behind NAT routes the peers it can not reach through
+    /// the relay instead.
+    fn peer_allowed_ips(&self) -> Result<Vec<(&Host, Vec<IpNet>)>> {
+        let mut peers: Vec<(&Host, Vec<IpNet>)> = self
+            .remote_hosts
+            .values()
+            .map(|host| (host, host.allowed_ips()))
+            .collect();
+        peers.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        for (i, (host, networks)) in peers.iter().enumerate() {
+            for (other, other_networks) in peers.iter().skip(i + 1) {
+                for net in networks {
+                    if let Some(other_net) = other_networks
+                        .iter()
+                        .find(|o| routes_conflict(host, net, other, o))
+                    {
+                        return Err(Error::OverlappingAllowedIps {
+                            host: host.name.clone(),
+                            network: *net,
+                            other: other.name.clone(),
+                            other_network: *other_net,
+                        });
+                    }
+                }
+            }
+        }
+        if let Topology::HubAndSpoke { hub } = &self.topology {
+            if self.host.name != *hub {
+                let all_networks: Vec<IpNet> = peers
+                    .iter()
+                    .flat_map(|(_, networks)| networks.iter().cloned())
+                    .collect();
+                peers.retain(|(host, _)| host.name == *hub);
+                match peers.first_mut() {
+                    Some((_, networks)) => *networks = all_networks,
+                    None => return Err(Error::HubNotFound(hub.clone())),
+                }
+            }
+        }
+        if self.prefer_relay_for_nat && self.host.endpoint.is_none() {
+            let relay = peers
+                .iter()
+                .find(|(host, _)| host.role == HostRole::Relay && host.endpoint.is_some())
+                .map(|(host, _)| host.name.clone());
+            if let Some(relay) = relay {
+                // neither side of the link has an endpoint, so no handshake can ever start
+                let mut relayed = Vec::new();
+                peers.retain(|(host, networks)| {
+                    let direct = host.endpoint.is_some() || host.name == relay || host.is_gateway();
+                    if !direct {
+                        relayed.extend(networks.iter().cloned());
+                    }
+                    direct
+                });
+                if let Some((_, networks)) = peers.iter_mut().find(|(host, _)| host.name == relay) {
+                    networks.extend(relayed);
+                }
+            }
+        }
+        if self.host.role == HostRole::Client {
+            let mut gateways = peers.iter_mut().filter(|(host, _)| host.is_gateway());
+            if let Some((_, networks)) = gateways.next() {
+                *networks = vec![
+                    Ipv4Net::new(Ipv4Addr::UNSPECIFIED, 0)?.into(),
+                    Ipv6Net::new(std::net::Ipv6Addr::UNSPECIFIED, 0)?.into(),
+                ];
+            }
+            if let Some((host, _)) = gateways.next() {
+                return Err(Error::MultipleGateways(host.name.clone()));
+            }
+        }
+        Ok(peers)
+    }
+
+    /// Resolvers for the local host: the configured ones followed by the remote hosts with the
+    /// DNS server role, sorted by name
+    fn dns_servers(&self) -> Vec<IpAddr> {
+        let mut dns = self.dns.clone();
+        let servers = self
+            .sorted_hosts()
+            .into_iter()
+            .filter(|host| host.role == HostRole::DnsServer)
+            .map(|host| host.wireguard_address.addr());
+        for server in servers {
+            if !dns.contains(&server) {
+                dns.push(server);
+            }
+        }
+        dns
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Change which hosts peer with each other
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+    }
+
+    /// File config changes are recorded in, see `audit`
+    pub fn audit_log(&self) -> Option<&Path> {
+        self.audit_log.as_deref()
+    }
+
+    /// Record changes in another file, or stop recording them with `None`
+    pub fn set_audit_log(&mut self, path: Option<PathBuf>) {
+        self.audit_log = path;
+    }
+
+    /// Append a change to the audit log, if the config keeps one. Entries are written in the
+    /// order they are recorded, so call this once the change has been made.
+    pub fn audit(&self, operation: AuditOperation, host: &str, actor: &str) -> Result<()> {
+        let path = match &self.audit_log {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let entry = AuditEntry {
+            timestamp: Utc::now(),
+            operation,
+            host: host.to_owned(),
+            actor: actor.to_owned(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        // a single write keeps lines from concurrent writers whole
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Wireguard interface the network is brought up on
+    pub fn interface_name(&self) -> &str {
+        &self.interface_name
+    }
+
+    /// Bring the network up on another interface. Fails if wg-quick would refuse the name.
+    pub fn set_interface_name(&mut self, name: &str) -> Result<()> {
+        validate_interface_name(name)?;
+        self.interface_name = name.to_owned();
+        Ok(())
+    }
+
+    pub fn prefer_relay_for_nat(&self) -> bool {
+        self.prefer_relay_for_nat
+    }
+
+    /// Route traffic between hosts without endpoints through a relay, see `peer_allowed_ips`
+    pub fn set_prefer_relay_for_nat(&mut self, prefer: bool) {
+        self.prefer_relay_for_nat = prefer;
+    }
+
+    /// Replace the resolvers pushed to the mesh. An empty list removes the DNS setting.
+    pub fn set_dns(&mut self, dns: Vec<IpAddr>) {
+        self.dns = dns;
+    }
+
+    /// Generate a random address for a host. IPv4 networks pick one from the subnet, IPv6
+    /// networks one under the network's unique local prefix.
+    pub fn random_address(&self) -> Result<IpNet> {
+        match self.subnet {
+            IpNet::V4(subnet) => {
+                let index = rand::random::<u128>() % self.subnet_capacity();
+                with_prefix(IpAddr::V4(ipv4_host(subnet, index)), subnet.prefix_len())
+            }
+            IpNet::V6(_) => {
+                let address = host::generate_ipv6(Some(self.global_id), None, None)?;
+                Ok(IpNet::V6(Ipv6Net::new(address, 64)?))
+            }
+        }
+    }
+
+    /// Derive the address of a host in the network from its name. Fails if the name hashes to
+    /// an address already taken by another host.
+    pub fn allocate_address(&self, hostname: &str) -> Result<IpNet> {
+        let address = match self.subnet {
+            IpNet::V4(subnet) => {
+                let index = host::hash_hostname(hostname) as u128 % self.subnet_capacity();
+                with_prefix(IpAddr::V4(ipv4_host(subnet, index)), subnet.prefix_len())?
+            }
+            IpNet::V6(_) => Host::allocate_address(self.global_id, hostname)?,
+        };
+        let existing = std::iter::once(&self.host).chain(self.remote_hosts.values());
+        for host in existing {
+            let taken = host.addresses().iter().any(|a| a.addr() == address.addr());
+            if host.name != hostname && taken {
+                return Err(Error::AddressCollision {
+                    address: address.addr(),
+                    host: host.name.clone(),
+                });
+            }
+        }
+        Ok(address)
+    }
+
+    /// Return the lowest address in the subnet not used by any host in the config
+    pub fn next_available_address(&self) -> Result<IpNet> {
+        let used: Vec<IpAddr> = std::iter::once(&self.host)
+            .chain(self.remote_hosts.values())
+            .flat_map(|host| host.addresses())
+            .map(|address| address.addr())
+            .collect();
+        // unlike IPv4, IPv6 subnets list their network address, the subnet-router anycast one
+        self.subnet
+            .hosts()
+            .filter(|address| *address != self.subnet.network())
+            .find(|address| !used.contains(address))
+            .map(|address| with_prefix(address, self.subnet.prefix_len()))
+            .transpose()?
+            .ok_or(Error::SubnetFull(self.subnet))
+    }
+
+    /// Number of host addresses in the subnet. The network and broadcast addresses of IPv4
+    /// subnets larger than a /31 are not usable.
+    pub fn subnet_capacity(&self) -> u128 {
+        match self.subnet {
+            IpNet::V4(net) => match net.prefix_len() {
+                32 => 1,
+                31 => 2,
+                len => (1_u128 << (32 - len)) - 2,
+            },
+            IpNet::V6(net) => 1_u128
+                .checked_shl(128 - net.prefix_len() as u32)
+                .unwrap_or(u128::MAX),
+        }
+    }
+
+    /// Hosts in the config, including the local host
+    fn host_count(&self) -> u128 {
+        self.remote_hosts.len() as u128 + 1
+    }
+
+    /// Adds a host to the config. Can fail if the name is not a DNS label, see
+    /// `host::validate_name`, or a host with the same name, addresses or public key already
+    /// exists.
+    pub fn add_host(&mut self, mut host: Host) -> Result<()> {
+        host::validate_name(&host.name)?;
+        for existing_host in std::iter::once(&self.host).chain(self.remote_hosts.values()) {
+            if existing_host.name == host.name {
+                return Err(Error::NameCollision(host.name));
+            }
+        }
+        let existing = std::iter::once(&self.host).chain(self.remote_hosts.values());
+        for existing_host in existing {
+            // hosts added without keys get them later, only real keys can collide
+            if !host.public_key.is_empty() && existing_host.public_key == host.public_key {
+                return Err(Error::PublicKeyCollision {
+                    host: host.name,
+                    existing: existing_host.name.clone(),
+                });
+            }
+            let taken = existing_host.addresses();
+            for address in host.addresses() {
+                if taken.iter().any(|t| t.addr() == address.addr()) {
+                    return Err(Error::AddressCollision {
+                        address: address.addr(),
+                        host: existing_host.name.clone(),
+                    });
+                }
+            }
+        }
+        if !self.subnet.contains(&host.wireguard_address.addr()) {
+            return Err(Error::OutOfSubnet {
+                address: host.wireguard_address.addr(),
+                subnet: self.subnet,
+            });
+        }
+        let capacity = self.subnet_capacity();
+        if self.host_count() >= capacity {
+            return Err(Error::SubnetFull(self.subnet));
+        }
+        // only small IPv4 subnets can realistically fill up
+        if (self.host_count() + 1) * 10 >= capacity.saturating_mul(9) {
+            log::warn!(
+                "subnet {} is nearly full, {} of {} addresses used",
+                self.subnet,
+                self.host_count() + 1,
+                capacity
+            );
+        }
+        // peers connect to the port the host's wireguard listens on unless told otherwise
+        if let Some(endpoint) = &host.endpoint {
+            let port = host.listen_port.unwrap_or(self.listen_port);
+            host.endpoint = Some(host::endpoint_with_port(endpoint, port));
+        }
+        self.remote_hosts.insert(host.wireguard_address, host);
+        Ok(())
+    }
+
+    /// Replace the host named `name` with `host`, moving it if its address changed. The new
+    /// address is checked like in `add_host`, and on failure the config is left unchanged.
+    pub fn update_host(&mut self, name: &str, host: Host) -> Result<()> {
+        let address = self
+            .hosts_by_name()
+            .get(name)
+            .map(|host| host.wireguard_address)
+            .ok_or_else(|| Error::HostNotFound(name.to_string()))?;
+        let previous = self.remote_hosts.remove(&address).unwrap();
+        if let Err(err) = self.add_host(host) {
+            self.remote_hosts.insert(address, previous);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Describe how `other` differs from this config
+    pub fn diff(&self, other: &Config) -> Result<ConfigDiff> {
+        let mut diff = ConfigDiff {
+            changed_fields: diff_fields(
+                &serde_json::to_value(self)?,
+                &serde_json::to_value(other)?,
+                &["host", "remote_hosts"],
+            ),
+            ..ConfigDiff::default()
+        };
+        let hosts = |config: &'_ Config| -> HashMap<String, Host> {
+            std::iter::once(&config.host)
+                .chain(config.remote_hosts.values())
+                .map(|host| (host.name.clone(), host.clone()))
+                .collect()
+        };
+        let (old_hosts, new_hosts) = (hosts(self), hosts(other));
+        let mut names: Vec<&String> = old_hosts.keys().chain(new_hosts.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            match (old_hosts.get(name), new_hosts.get(name)) {
+                (Some(_), None) => diff.removed_hosts.push(name.clone()),
+                (None, Some(_)) => diff.added_hosts.push(name.clone()),
+                (Some(old), Some(new)) => {
+                    let changes = diff_fields(
+                        &serde_json::to_value(old)?,
+                        &serde_json::to_value(new)?,
+                        RUNTIME_FIELDS,
+                    );
+                    if !changes.is_empty() {
+                        diff.modified_hosts.push(HostDiff {
+                            name: name.clone(),
+                            changes,
+                        });
+                    }
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+        Ok(diff)
+    }
+
+    /// Peers the rendered config gives the local host's interface, sorted by public key
+    pub fn expected_peers(&self) -> Result<Vec<WgPeer>> {
+        let mut peers: Vec<WgPeer> = self
+            .peer_allowed_ips()?
+            .into_iter()
+            .map(|(peer, mut allowed_ips)| {
+                allowed_ips.sort();
+                WgPeer {
+                    public_key: peer.public_key.clone(),
+                    preshared_key: peer.preshared_key.clone(),
+                    endpoint: peer
+                        .resolved_endpoint
+                        .map(|endpoint| endpoint.to_string())
+                        .or_else(|| peer.endpoint.clone()),
+                    allowed_ips,
+                    persistent_keepalive: match (peer.persistent_keepalive, &peer.role) {
+                        (None, HostRole::Relay) => Some(DEFAULT_RELAY_KEEPALIVE),
+                        (keepalive, _) => keepalive,
+                    }
+                    .filter(|keepalive| *keepalive > 0),
+                }
+            })
+            .collect();
+        peers.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+        Ok(peers)
+    }
+
+    /// Compare the peers of the running interface, as read with `host::parse_wg_dump`, to the
+    /// ones the config expects. Peers are matched by public key. Peers only on the interface are
+    /// listed as added, by public key, and peers missing from it as removed, by host name.
+    /// Roaming peers are expected to show up from wherever they connect, so their endpoints
+    /// are only compared when the config gives an IP address.
+    pub fn drift(&self, running: &[WgPeer]) -> Result<ConfigDiff> {
+        let names: HashMap<&str, &str> = self
+            .remote_hosts
+            .values()
+            .map(|host| (host.public_key.as_str(), host.name.as_str()))
+            .collect();
+        let running: HashMap<&str, &WgPeer> = running
+            .iter()
+            .map(|peer| (peer.public_key.as_str(), peer))
+            .collect();
+        let mut diff = ConfigDiff::default();
+        let expected = self.expected_peers()?;
+        for peer in &expected {
+            let name = names
+                .get(peer.public_key.as_str())
+                .copied()
+                .unwrap_or_default()
+                .to_string();
+            let actual = match running.get(peer.public_key.as_str()) {
+                Some(actual) => actual,
+                None => {
+                    diff.removed_hosts.push(name);
+                    continue;
+                }
+            };
+            let mut actual = (*actual).clone();
+            let fixed_endpoint = peer
+                .endpoint
+                .as_deref()
+                .map(|endpoint| endpoint.parse::<std::net::SocketAddr>().is_ok())
+                .unwrap_or(false);
+            if !fixed_endpoint {
+                actual.endpoint = peer.endpoint.clone();
+            }
+            let changes = diff_fields(
+                &serde_json::to_value(peer)?,
+                &serde_json::to_value(&actual)?,
+                &[],
+            );
+            if !changes.is_empty() {
+                diff.modified_hosts.push(HostDiff { name, changes });
+            }
+        }
+        let mut extra: Vec<String> = running
+            .keys()
+            .filter(|key| !expected.iter().any(|peer| peer.public_key == **key))
+            .map(|key| key.to_string())
+            .collect();
+        extra.sort();
+        diff.added_hosts = extra;
+        Ok(diff)
+    }
+
+    /// Check the config for mistakes that would break the rendered network. Returns every
+    /// problem found instead of stopping at the first one.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut addresses: HashMap<IpAddr, usize> = HashMap::new();
+        let mut public_keys: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut hosts: Vec<&Host> = std::iter::once(&self.host)
+            .chain(self.remote_hosts.values())
+            .collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        for host in hosts {
+            *names.entry(&host.name).or_insert(0) += 1;
+            if !host.public_key.trim().is_empty() {
+                public_keys
+                    .entry(&host.public_key)
+                    .or_default()
+                    .push(&host.name);
+            }
+            for address in host.addresses() {
+                *addresses.entry(address.addr()).or_insert(0) += 1;
+            }
+            if !self.subnet.contains(&host.wireguard_address.addr()) {
+                problems.push(format!(
+                    "host \"{}\": address {} is outside of subnet {}",
+                    host.name,
+                    host.wireguard_address.addr(),
+                    self.subnet
+                ));
+            }
+            if host.public_key.trim().is_empty() {
+                problems.push(format!("host \"{}\": public key is empty", host.name));
+            }
+            // the local host is named by the machine and only remote hosts are added by name
+            if !std::ptr::eq(host, &self.host) {
+                if let Err(err) = host::validate_name(&host.name) {
+                    problems.push(err.to_string());
+                }
+            }
+            if let Some(endpoint) = &host.endpoint {
+                if let Err(err) = host::validate_endpoint(endpoint) {
+                    problems.push(format!("host \"{}\": {}", host.name, err));
+                }
+            }
+        }
+        if let Topology::HubAndSpoke { hub } = &self.topology {
+            if !names.contains_key(hub.as_str()) {
+                problems.push(format!("hub \"{}\" is not in the network", hub));
+            }
+        }
+        if let Err(err) = validate_interface_name(&self.interface_name) {
+            problems.push(err.to_string());
+        }
+        let relay_reachable = self
+            .all_hosts()
+            .iter()
+            .any(|host| host.role == HostRole::Relay && host.endpoint.is_some());
+        if self.prefer_relay_for_nat && !relay_reachable {
+            problems.push(String::from(
+                "prefer_relay_for_nat is set but no relay has an endpoint",
+            ));
+        }
+        // wireguard can only route a network to one peer. Overlapping host addresses are
+        // reported as duplicates below, so only pairs involving a routed subnet are checked.
+        let all_hosts = self.all_hosts();
+        for (i, host) in all_hosts.iter().enumerate() {
+            for other in all_hosts.iter().skip(i + 1) {
+                for net in host.allowed_ips() {
+                    for other_net in other.allowed_ips() {
+                        let routed = host.allowed_subnets.contains(&net)
+                            || other.allowed_subnets.contains(&other_net);
+                        if routed && routes_conflict(host, &net, other, &other_net) {
+                            problems.push(format!(
+                                "hosts \"{}\" and \"{}\" route overlapping networks {} and {}",
+                                host.name, other.name, net, other_net
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        for name in self.isolated_hosts() {
+            problems.push(format!(
+                "host \"{}\": can not reach the rest of the network",
+                name
+            ));
+        }
+        if self.host_count() > self.subnet_capacity() {
+            problems.push(format!(
+                "{} hosts do not fit in subnet {}, it only has room for {}",
+                self.host_count(),
+                self.subnet,
+                self.subnet_capacity()
+            ));
+        }
+        let mut duplicate_names: Vec<_> = names.into_iter().filter(|(_, n)| *n > 1).collect();
+        duplicate_names.sort();
+        for (name, count) in duplicate_names {
+            problems.push(format!("{} hosts are named \"{}\"", count, name));
+        }
+        let mut duplicate_addresses: Vec<_> =
+            addresses.into_iter().filter(|(_, n)| *n > 1).collect();
+        duplicate_addresses.sort();
+        for (address, count) in duplicate_addresses {
+            problems.push(format!("{} hosts use address {}", count, address));
+        }
+        // hosts were visited sorted by name, so the names of each key are sorted too
+        let mut duplicate_keys: Vec<Vec<&str>> = public_keys
+            .into_values()
+            .filter(|names| names.len() > 1)
+            .collect();
+        duplicate_keys.sort();
+        for names in duplicate_keys {
+            problems.push(format!(
+                "hosts {} share a public key",
+                names
+                    .iter()
+                    .map(|name| format!("\"{}\"", name))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+        problems
+    }
+
+    /// Import the remote hosts of another network. Its subnet has to either be the same as this
+    /// one or not overlap with it. Every name or address collision is reported and nothing is
+    /// imported if there are any.
+    pub fn merge(&mut self, other: Config) -> Result<()> {
+        let overlapping = self.subnet.contains(&other.subnet.network())
+            || other.subnet.contains(&self.subnet.network());
+        if overlapping && self.subnet != other.subnet {
+            return Err(Error::OverlappingSubnets(self.subnet, other.subnet));
+        }
+        let mut incoming: Vec<Host> = other.remote_hosts.into_values().collect();
+        incoming.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut collisions = Vec::new();
+        for (i, host) in incoming.iter().enumerate() {
+            // check against this config and the hosts merged before it
+            let existing = std::iter::once(&self.host)
+                .chain(self.remote_hosts.values())
+                .chain(incoming[..i].iter());
+            for existing_host in existing {
+                if existing_host.name == host.name {
+                    collisions.push(format!("host name \"{}\" is already used", host.name));
+                }
+                let taken = existing_host.addresses();
+                for address in host.addresses() {
+                    if taken.iter().any(|t| t.addr() == address.addr()) {
+                        collisions.push(format!(
+                            "address {} of \"{}\" is already used by \"{}\"",
+                            address.addr(),
+                            host.name,
+                            existing_host.name
+                        ));
+                    }
+                }
+            }
+        }
+        if !collisions.is_empty() {
+            return Err(Error::MergeConflict(collisions));
+        }
+        for host in incoming {
+            self.remote_hosts.insert(host.wireguard_address, host);
+        }
+        Ok(())
+    }
+
+    /// Remove a host from the config by address
+    pub fn remove_host(&mut self, ip: &IpNet) {
+        self.remote_hosts.remove(ip);
+    }
+
+    /// Remove a host from the config by name. Fails if there is no host with the name.
+    pub fn remove_host_by_name(&mut self, name: &str) -> Result<()> {
+        let address = self
+            .hosts_by_name()
+            .get(name)
+            .map(|host| host.wireguard_address)
+            .ok_or_else(|| Error::HostNotFound(name.to_string()))?;
+        self.remove_host(&address);
+        Ok(())
+    }
+
+    pub fn network_id(&self) -> Uuid {
+        self.network_id
+    }
+
+    /// The host this config was generated for
+    pub fn local_host(&self) -> &Host {
+        &self.host
+    }
+
+    /// Port wireguard listens on for every host in the network
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    /// Token the server daemon requires in the `Authorization` header
+    pub fn api_token(&self) -> &str {
+        &self.api_token
+    }
+
+    /// Copy of the config with the api token and every host's private and preshared keys
+    /// blanked, safe to return from the API
+    pub fn without_secrets(&self) -> Self {
+        Config {
+            api_token: String::new(),
+            host: self.host.without_secrets(),
+            remote_hosts: self
+                .remote_hosts
+                .iter()
+                .map(|(address, host)| (*address, host.without_secrets()))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Base64 ed25519 signature of the config, letting clients check a config they fetched
+    /// was not changed on the way. Signs the canonical JSON of the config as it is, so sign
+    /// `without_secrets` when handing the config out.
+    pub fn sign(&self, signing_key: &str) -> Result<String> {
+        sign_canonical(self, signing_key)
+    }
+
+    /// Whether the signature was made for this config by the signing key of `public_key`
+    pub fn verify(&self, signature: &str, public_key: &str) -> bool {
+        verify_canonical(self, signature, public_key)
+    }
+
+    /// Remote hosts sorted by name
+    pub fn sorted_hosts(&self) -> Vec<&Host> {
+        let mut hosts: Vec<&Host> = self.remote_hosts.values().collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        hosts
+    }
+
+    pub fn hosts_by_name(&self) -> HashMap<String, &Host> {
+        let mut out = HashMap::new();
+        for host in self.remote_hosts.values() {
+            out.insert(host.name.clone(), host);
+        }
+        out
+    }
+}
+
+/// Turn a host name into a DNS label: lower case letters, digits and inner hyphens, at most
+/// 63 characters. `None` if nothing usable is left.
+fn dns_label(name: &str) -> Option<String> {
+    let label: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        None
+    } else {
+        Some(label.to_string())
+    }
+}
+
+/// Key of the named networks in a file holding several
+const NETWORKS_KEY: &str = "networks";
+/// Name a file's single network is kept under when another network is added to the file
+pub const DEFAULT_NETWORK: &str = "default";
+
+/// Whether wireguard can not tell which of two peers a network goes to. It routes by longest
+/// prefix, so a subnet routed through one peer may hold another peer's mesh address, eg. a
+/// gateway routing 10.0.0.0/8 next to the hosts of 10.42.0.0/24. Networks given to both peers,
+/// or routed subnets nested in each other, are ambiguous.
+fn routes_conflict(host: &Host, net: &IpNet, other: &Host, other_net: &IpNet) -> bool {
+    let nested = net.contains(other_net) || other_net.contains(net);
+    let both_routed =
+        host.allowed_subnets.contains(net) && other.allowed_subnets.contains(other_net);
+    net == other_net || (nested && both_routed)
+}
+
+/// Parse a config file into a generic value, in the format its extension calls for
+fn read_document(path: &str) -> Result<serde_yaml::Value> {
+    let text = std::fs::read_to_string(path)?;
+    ConfigFormat::from_path(path).parse(&text)
+}
+
+/// Names of the networks in a config file. A file with a single network has none, as does a
+/// missing file.
+pub fn network_names(path: &str) -> Result<Vec<String>> {
+    if !Path::new(path).exists() {
+        return Ok(Vec::new());
+    }
+    let document = read_document(path)?;
+    let mut names: Vec<String> = match document.get(NETWORKS_KEY) {
+        Some(serde_yaml::Value::Mapping(networks)) => networks
+            .iter()
+            .filter_map(|(name, _)| name.as_str().map(String::from))
+            .collect(),
+        _ => Vec::new(),
+    };
+    names.sort();
+    Ok(names)
+}
+
+/// Directory to render the chosen network of a config file to. Files holding several
+/// networks render each to its own subdirectory of `directory`, which is created if needed.
+pub fn network_directory(
+    directory: &str,
+    config_path: &str,
+    network: Option<&str>,
+) -> Result<String> {
+    if !Path::new(config_path).exists() {
+        return Ok(directory.to_string());
+    }
+    match resolve_network(config_path, &read_document(config_path)?, network)? {
+        Some(name) if !network_names(config_path)?.is_empty() => {
+            let path = Path::new(directory).join(name);
+            std::fs::create_dir_all(&path)?;
+            Ok(path.to_string_lossy().into_owned())
+        }
+        _ => Ok(directory.to_string()),
+    }
+}
+
+/// Name of the network to use from a config file. `None` for files with a single network and
+/// no name asked for. Files holding several networks only need a name when there is more than
+/// one to pick from.
+fn resolve_network(
+    path: &str,
+    document: &serde_yaml::Value,
+    network: Option<&str>,
+) -> Result<Option<String>> {
+    let networks = match document.get(NETWORKS_KEY) {
+        Some(serde_yaml::Value::Mapping(networks)) => networks,
+        Some(_) => {
+            return Err(Error::MalformedConfig(format!(
+                "{} in {} is not a mapping",
+                NETWORKS_KEY, path
+            )))
+        }
+        None => return Ok(network.map(String::from)),
+    };
+    if let Some(name) = network {
+        return Ok(Some(name.to_string()));
+    }
+    let mut names: Vec<&str> = networks
+        .iter()
+        .filter_map(|(name, _)| name.as_str())
+        .collect();
+    names.sort();
+    match names.as_slice() {
+        [name] => Ok(Some(name.to_string())),
+        _ => Err(Error::NetworkNotChosen {
+            path: path.to_owned(),
+            networks: names.iter().map(|name| name.to_string()).collect(),
+        }),
+    }
+}
+
+/// Host part of a `host:port` endpoint, without the brackets of an IPv6 address
+fn endpoint_address(endpoint: &str) -> Option<&str> {
+    endpoint
+        .rsplit_once(':')
+        .map(|(address, _)| address.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// Build a network from an address and prefix length
+fn with_prefix(address: IpAddr, prefix_len: u8) -> Result<IpNet> {
+    Ok(match address {
+        IpAddr::V4(address) => IpNet::V4(Ipv4Net::new(address, prefix_len)?),
+        IpAddr::V6(address) => IpNet::V6(Ipv6Net::new(address, prefix_len)?),
+    })
+}
+
+/// The `index`th usable host address of an IPv4 subnet, skipping the network address
+fn ipv4_host(subnet: Ipv4Net, index: u128) -> Ipv4Addr {
+    let first = match subnet.prefix_len() {
+        31 | 32 => u32::from(subnet.network()),
+        _ => u32::from(subnet.network()) + 1,
+    };
+    Ipv4Addr::from(first + index as u32)
+}
+
+/// Join values into a comma separated list as used by wg-quick
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Name of the `wg-quick@` unit instance for the interface, escaped the same way as
+/// `systemd-escape`
+pub fn systemd_unit_name(iface_name: &str) -> String {
+    format!("wg-quick@{}.service", systemd_escape(iface_name))
+}
+
+fn systemd_escape(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            '/' => out.push('-'),
+            '.' if i == 0 => out.push_str("\\x2e"),
+            c if c.is_ascii_alphanumeric() || c == ':' || c == '_' || c == '.' => out.push(c),
+            c => {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("\\x{:02x}", byte));
+                }
+            }
+        }
+    }
+    out
+}
+
+fn render_systemd_unit(iface_name: &str) -> Result<String> {
+    let mut out = String::new();
+    writeln!(out, "[Unit]")?;
+    writeln!(
+        out,
+        "Description=WireGuard mesh via wg-quick(8) for {}",
+        iface_name
+    )?;
+    writeln!(out, "After=network-online.target nss-lookup.target")?;
+    writeln!(out, "Wants=network-online.target nss-lookup.target")?;
+    writeln!(out)?;
+    writeln!(out, "[Service]")?;
+    writeln!(out, "Type=oneshot")?;
+    writeln!(out, "RemainAfterExit=yes")?;
+    writeln!(out, "ExecStart=/usr/bin/wg-quick up {}", iface_name)?;
+    writeln!(out, "ExecStop=/usr/bin/wg-quick down {}", iface_name)?;
+    writeln!(out)?;
+    writeln!(out, "[Install]")?;
+    writeln!(out, "WantedBy=multi-user.target")?;
+    Ok(out)
+}
+
+/// Equivalent to `wg pubkey < private_key`
+#[cfg(not(feature = "wg-binary"))]
+pub fn generate_public_key(private_key: &str) -> Result<String> {
+    let private_key = decode_private_key(private_key)?;
+    Ok(base64::encode(x25519_dalek::x25519(
+        private_key,
+        x25519_dalek::X25519_BASEPOINT_BYTES,
+    )))
+}
+
+/// Equivalent to `wg genkey`
+#[cfg(not(feature = "wg-binary"))]
+pub fn generate_private_key() -> Result<String> {
+    let mut key: [u8; 32] = rand::random();
+    // clamp the key the same way as curve25519 and `wg genkey`
+    key[0] &= 248;
+    key[31] &= 127;
+    key[31] |= 64;
+    Ok(base64::encode(key))
+}
+
+/// Equivalent to `wg genpsk`
+#[cfg(not(feature = "wg-binary"))]
+pub fn generate_preshared_key() -> Result<String> {
+    let key: [u8; 32] = rand::random();
+    Ok(base64::encode(key))
+}
+
+/// Equivalent to `wg pubkey < private_key`
+#[cfg(feature = "wg-binary")]
+pub fn generate_public_key(private_key: &str) -> Result<String> {
+    decode_private_key(private_key)?;
+    let mut cmd = Command::new("wg")
+        .arg("pubkey")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    {
+        let stdin = cmd.stdin.as_mut().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "could not open process stdin",
+            )
+        })?;
+        //.ok_or(Err("Could not open process stdin"))?;
+        stdin.write_all(private_key.trim().as_bytes())?;
+    }
+    Ok(String::from_utf8(cmd.wait_with_output()?.stdout)?
+        .trim()
+        .to_owned())
+}
+
+/// Equivalent to `wg genkey`
+#[cfg(feature = "wg-binary")]
+pub fn generate_private_key() -> Result<String> {
+    let cmd = Command::new("wg").arg("genkey").output()?;
+    Ok(String::from_utf8(cmd.stdout)?.trim().to_owned())
+}
+
+/// Equivalent to `wg genpsk`
+#[cfg(feature = "wg-binary")]
+pub fn generate_preshared_key() -> Result<String> {
+    let cmd = Command::new("wg").arg("genpsk").output()?;
+    Ok(String::from_utf8(cmd.stdout)?.trim().to_owned())
+}
+
+/// Decode a base64 wireguard private key, checking that it is the right length
+fn decode_private_key(private_key: &str) -> Result<[u8; 32]> {
+    base64::decode(private_key.trim())
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key.as_slice()).ok())
+        .ok_or(Error::InvalidKey)
+}
+
+/// Base64 ed25519 secret key the server signs configs with, see `Config::sign`
+pub fn generate_signing_key() -> String {
+    base64::encode(rand::random::<[u8; 32]>())
+}
+
+/// Base64 public key that verifies what the signing key signed
+pub fn signing_public_key(signing_key: &str) -> Result<String> {
+    Ok(base64::encode(
+        decode_signing_key(signing_key)?.public.as_bytes(),
+    ))
+}
+
+fn decode_signing_key(signing_key: &str) -> Result<ed25519_dalek::Keypair> {
+    let secret = ed25519_dalek::SecretKey::from_bytes(&decode_private_key(signing_key)?)
+        .map_err(|_| Error::InvalidKey)?;
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    Ok(ed25519_dalek::Keypair { secret, public })
+}
+
+/// Value serialized as JSON with sorted object keys and no whitespace, so the same value
+/// always gives the same bytes no matter the field or hash map order it was built with
+fn canonical_json<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(serde_json::to_value(value)?.to_string().into_bytes())
+}
+
+/// Base64 ed25519 signature of the canonical JSON of a value
+pub fn sign_canonical<T: Serialize>(value: &T, signing_key: &str) -> Result<String> {
+    use ed25519_dalek::Signer;
+    let keypair = decode_signing_key(signing_key)?;
+    Ok(base64::encode(
+        keypair.sign(&canonical_json(value)?).to_bytes(),
+    ))
+}
+
+/// Check a signature made by `sign_canonical` against the base64 public key. Malformed
+/// signatures and keys fail the check.
+pub fn verify_canonical<T: Serialize>(value: &T, signature: &str, public_key: &str) -> bool {
+    use ed25519_dalek::Verifier;
+    let public_key = base64::decode(public_key.trim())
+        .ok()
+        .and_then(|key| ed25519_dalek::PublicKey::from_bytes(&key).ok());
+    let signature = base64::decode(signature.trim())
+        .ok()
+        .and_then(|signature| ed25519_dalek::Signature::try_from(signature.as_slice()).ok());
+    match (public_key, signature, canonical_json(value)) {
+        (Some(public_key), Some(signature), Ok(message)) => {
+            public_key.verify(&message, &signature).is_ok()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory under the system temp dir, unique to the test and process
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wgmesh-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Remote host with a fresh key pair
+    pub(crate) fn host(name: &str, address: &str) -> Host {
+        let private_key = generate_private_key().unwrap();
+        Host::builder()
+            .name(name)
+            .wireguard_address(address.parse().unwrap())
+            .public_key(&generate_public_key(&private_key).unwrap())
+            .private_key(&private_key)
+            .build()
+            .unwrap()
+    }
+
+    /// IPv4 network whose local host is `alpha` at 10.42.0.1
+    pub(crate) fn config() -> Config {
+        Config {
+            host: host("alpha", "10.42.0.1/24"),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn render_writes_config_that_parses_back_into_the_peers() {
+        let mut config = config();
+        let beta = host("beta", "10.42.0.2/24");
+        config.add_host(beta.clone()).unwrap();
+        let dir = temp_dir("render");
+
+        config.render(dir.to_str().unwrap()).unwrap();
+
+        let path = dir.join("wg0.conf");
+        let sections = wgconf::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let interface = &sections[0];
+        assert_eq!(interface.name, "interface");
+        assert_eq!(
+            interface.value("PrivateKey"),
+            Some(config.host.private_key.as_str())
+        );
+        assert_eq!(interface.value("Address"), Some("10.42.0.1/24"));
+        let peers: Vec<_> = sections.iter().filter(|s| s.name == "peer").collect();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].comment.as_deref(), Some("beta"));
+        assert_eq!(peers[0].value("PublicKey"), Some(beta.public_key.as_str()));
+        assert_eq!(peers[0].list("AllowedIPs"), vec!["10.42.0.2/32"]);
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains(&beta.private_key));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn render_systemd_writes_wg_quick_unit() {
+        let config = config();
+        let dir = temp_dir("systemd");
+
+        config.render_systemd(dir.to_str().unwrap(), "wg0").unwrap();
+
+        assert_eq!(systemd_unit_name("wg0"), "wg-quick@wg0.service");
+        let unit = std::fs::read_to_string(dir.join("wg-quick@wg0.service")).unwrap();
+        assert!(unit.contains("ExecStart=/usr/bin/wg-quick up wg0"));
+        assert!(unit.contains("WantedBy=multi-user.target"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Peer sections of the rendered config, keyed by the host name in their comment
+    fn rendered_peers(config: &Config) -> HashMap<String, wgconf::Section> {
+        wgconf::parse(&config.render_config().unwrap())
+            .unwrap()
+            .into_iter()
+            .filter(|section| section.name == "peer")
+            .map(|section| (section.comment.clone().unwrap(), section))
+            .collect()
+    }
+
+    #[test]
+    fn render_emits_keepalive_only_for_enabled_peers() {
+        let mut config = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.persistent_keepalive = Some(25);
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.persistent_keepalive = Some(0);
+        config.add_host(beta).unwrap();
+        config.add_host(gamma).unwrap();
+
+        let peers = rendered_peers(&config);
+
+        assert_eq!(peers["beta"].value("PersistentKeepalive"), Some("25"));
+        assert_eq!(peers["gamma"].value("PersistentKeepalive"), None);
+    }
+
+    #[test]
+    fn gateway_renders_masquerade_rules_and_clients_route_through_it() {
+        let mut config = config();
+        config.host.role = HostRole::Gateway {
+            out_interface: String::from("eth0"),
+        };
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+
+        let rendered = wgconf::parse(&config.render_config().unwrap()).unwrap();
+        assert_eq!(
+            rendered[0].values("PostUp"),
+            vec![
+                "iptables -A FORWARD -i %i -j ACCEPT",
+                "iptables -A FORWARD -o %i -j ACCEPT",
+                "iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE",
+                "ip6tables -A FORWARD -i %i -j ACCEPT",
+                "ip6tables -A FORWARD -o %i -j ACCEPT",
+                "ip6tables -t nat -A POSTROUTING -o eth0 -j MASQUERADE",
+            ]
+        );
+        assert_eq!(
+            rendered[0].values("PostDown"),
+            vec![
+                "iptables -D FORWARD -i %i -j ACCEPT",
+                "iptables -D FORWARD -o %i -j ACCEPT",
+                "iptables -t nat -D POSTROUTING -o eth0 -j MASQUERADE",
+                "ip6tables -D FORWARD -i %i -j ACCEPT",
+                "ip6tables -D FORWARD -o %i -j ACCEPT",
+                "ip6tables -t nat -D POSTROUTING -o eth0 -j MASQUERADE",
+            ]
+        );
+
+        for client in &["beta", "gamma"] {
+            let client_config = config.for_host(client).unwrap();
+            let rendered = client_config.render_config().unwrap();
+            assert!(!rendered.contains("MASQUERADE"));
+            let peers = rendered_peers(&client_config);
+            assert_eq!(peers["alpha"].list("AllowedIPs"), vec!["0.0.0.0/0", "::/0"]);
+        }
+    }
+
+    #[test]
+    fn public_key_matches_rfc7748_vectors() {
+        // Alice and Bob from RFC 7748 section 6.1, base64 encoded the way wireguard prints keys
+        let vectors = [
+            (
+                "dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=",
+                "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo=",
+            ),
+            (
+                "XasIfmJKikt54X+Lg4AO5m87sSkmGLb9HC+LJ/+I4Os=",
+                "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08=",
+            ),
+        ];
+        for (private_key, public_key) in vectors.iter() {
+            assert_eq!(generate_public_key(private_key).unwrap(), *public_key);
+        }
+    }
+
+    #[test]
+    fn generated_private_key_is_clamped() {
+        for _ in 0..16 {
+            let key = base64::decode(generate_private_key().unwrap()).unwrap();
+            assert_eq!(key.len(), 32);
+            assert_eq!(key[0] & 7, 0);
+            assert_eq!(key[31] & 0xc0, 0x40);
+        }
+    }
+
+    #[test]
+    fn public_key_rejects_malformed_private_keys() {
+        let private_key = generate_private_key().unwrap();
+        assert!(!private_key.ends_with('\n'));
+        assert!(generate_public_key(&format!("{}\n", private_key)).is_ok());
+        let short = base64::encode([1_u8; 31]);
+        let long = base64::encode([1_u8; 33]);
+        for key in &["", "not base64!", short.as_str(), long.as_str()] {
+            assert!(
+                matches!(generate_public_key(key), Err(Error::InvalidKey)),
+                "{:?} should be rejected",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn preshared_key_is_rendered_on_both_sides_of_the_link() {
+        let mut config = config();
+        let preshared_key = generate_preshared_key().unwrap();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.preshared_key = Some(preshared_key.clone());
+        config.add_host(beta).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+
+        let alpha_peers = rendered_peers(&config);
+        let beta_peers = rendered_peers(&config.for_host("beta").unwrap());
+
+        assert_eq!(
+            alpha_peers["beta"].value("PresharedKey"),
+            Some(preshared_key.as_str())
+        );
+        assert_eq!(
+            beta_peers["alpha"].value("PresharedKey"),
+            Some(preshared_key.as_str())
+        );
+        assert_eq!(alpha_peers["gamma"].value("PresharedKey"), None);
+        assert_eq!(beta_peers["gamma"].value("PresharedKey"), None);
+    }
+
+    #[test]
+    fn allocate_address_is_deterministic_and_detects_collisions() {
+        let mut config = config();
+        let address = config.allocate_address("delta").unwrap();
+        assert_eq!(config.allocate_address("delta").unwrap(), address);
+        assert_eq!(
+            Host::allocate_address(7, "delta").unwrap(),
+            Host::allocate_address(7, "delta").unwrap()
+        );
+        assert_ne!(
+            Host::allocate_address(7, "delta").unwrap(),
+            Host::allocate_address(7, "epsilon").unwrap()
+        );
+
+        config
+            .add_host(host("squatter", &address.to_string()))
+            .unwrap();
+
+        assert!(matches!(
+            config.allocate_address("delta"),
+            Err(Error::AddressCollision { host, .. }) if host == "squatter"
+        ));
+    }
+
+    #[test]
+    fn add_host_rejects_collisions_and_addresses_outside_the_subnet() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+
+        assert!(matches!(
+            config.add_host(host("beta", "10.42.0.3/24")),
+            Err(Error::NameCollision(name)) if name == "beta"
+        ));
+        assert!(matches!(
+            config.add_host(host("alpha", "10.42.0.3/24")),
+            Err(Error::NameCollision(name)) if name == "alpha"
+        ));
+        assert!(matches!(
+            config.add_host(host("gamma", "10.42.0.2/24")),
+            Err(Error::AddressCollision { host, .. }) if host == "beta"
+        ));
+        assert!(matches!(
+            config.add_host(host("gamma", "10.42.0.1/24")),
+            Err(Error::AddressCollision { host, .. }) if host == "alpha"
+        ));
+        assert!(matches!(
+            config.add_host(host("gamma", "10.43.0.3/24")),
+            Err(Error::OutOfSubnet { .. })
+        ));
+        assert_eq!(config.remote_hosts.len(), 1);
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+    }
+
+    #[test]
+    fn uuidv1_node_id_is_six_bytes_of_any_name() {
+        let names = ["abc", "abcdef", "a-twenty-char-name-x"];
+        let mut node_ids = Vec::new();
+        for name in names.iter() {
+            let first = uuidv1(Some(name)).unwrap();
+            let second = uuidv1(Some(name)).unwrap();
+
+            assert_eq!(first.get_version_num(), 1);
+            assert_eq!(first.as_bytes()[10..], node_id_bytes(name));
+            assert_eq!(first.as_bytes()[10..], second.as_bytes()[10..]);
+            node_ids.push(node_id_bytes(name));
+        }
+        node_ids.sort_unstable();
+        node_ids.dedup();
+        assert_eq!(node_ids.len(), names.len());
+    }
+
+    #[test]
+    fn v0_config_is_migrated_on_load() {
+        let dir = temp_dir("migrate");
+        let path = dir.join("network.yml");
+        let path = path.to_str().unwrap();
+        // written before configs had a version, a global id, dns or an api token
+        let v0 = "\
+network_id: 00000000-0000-0000-0000-000000000000
+subnet: 10.42.0.0/24
+host:
+  name: alpha
+  last_seen: ~
+  wireguard_address: 10.42.0.1/24
+  public_key: alpha-public
+  private_key: alpha-private
+  interfaces: []
+remote_hosts:
+  10.42.0.2/24:
+    name: beta
+    last_seen: ~
+    wireguard_address: 10.42.0.2/24
+    public_key: beta-public
+    private_key: ''
+    interfaces: []
+";
+        std::fs::write(path, v0).unwrap();
+
+        let config = Config::try_from_path(path).unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.local_host().name, "alpha");
+        assert_eq!(config.hosts_by_name()["beta"].public_key, "beta-public");
+        assert_eq!(config.listen_port(), DEFAULT_LISTEN_PORT);
+        assert!(!config.api_token().is_empty());
+        assert!(config.global_id < 1 << 40);
+        // the upgraded file is written back and loads as is
+        let saved: serde_yaml::Value =
+            serde_yaml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(saved["version"].as_str(), Some(CONFIG_VERSION));
+        assert_eq!(
+            Config::try_from_path(path).unwrap().api_token(),
+            config.api_token()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn future_config_version_is_rejected() {
+        let dir = temp_dir("future-version");
+        let path = dir.join("network.yml");
+        let mut value = serde_yaml::to_value(config()).unwrap();
+        value["version"] = serde_yaml::Value::from("v99");
+        std::fs::write(&path, serde_yaml::to_string(&value).unwrap()).unwrap();
+
+        let err = Config::try_from_path(path.to_str().unwrap()).unwrap_err();
+
+        assert!(matches!(err, Error::UnsupportedVersion { found, .. } if found == "v99"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn validate_passes_a_clean_config() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+
+        assert_eq!(config.validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        // inserted directly, add_host would refuse all of them
+        let mut seeded = vec![
+            host("outside", "10.43.0.3/24"),
+            host("keyless", "10.42.0.4/24"),
+            host("beta", "10.42.0.5/24"),
+            host("twin", "10.42.0.2/32"),
+            host("portless", "10.42.0.6/24"),
+        ];
+        seeded[1].public_key = String::new();
+        seeded[4].endpoint = Some(String::from("no-port"));
+        for host in seeded {
+            config.remote_hosts.insert(host.wireguard_address, host);
+        }
+
+        let problems = config.validate();
+
+        for expected in &[
+            "host \"outside\": address 10.43.0.3 is outside of subnet 10.42.0.0/24",
+            "host \"keyless\": public key is empty",
+            "2 hosts are named \"beta\"",
+            "2 hosts use address 10.42.0.2",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem == expected),
+                "missing {:?} in {:?}",
+                expected,
+                problems
+            );
+        }
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("host \"portless\": invalid endpoint")));
+    }
+
+    #[test]
+    fn init_refuses_to_overwrite_without_force() {
+        let dir = temp_dir("init");
+        let path = dir.join("network.yml");
+        let path = path.to_str().unwrap();
+        config().save(path).unwrap();
+        let before = std::fs::read(path).unwrap();
+
+        let err = Config::init(path, None, false).unwrap_err();
+
+        assert!(matches!(err, Error::AlreadyExists(existing) if existing == path));
+        assert_eq!(std::fs::read(path).unwrap(), before);
+        config().save_network(path, Some("home")).unwrap();
+        assert!(matches!(
+            Config::init(path, Some("home"), false),
+            Err(Error::AlreadyExists(existing)) if existing == "home"
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Apply `edit-host beta` with the given flags the way the CLI does
+    fn edit_beta(config: &mut Config, flags: &[&str]) -> Result<()> {
+        let args = ["wgmesh", "edit-host", "beta"].iter().chain(flags);
+        let matches = cli().try_get_matches_from(args).unwrap();
+        let m = matches.subcommand_matches("edit-host").unwrap();
+        let mut beta = config.hosts_by_name()["beta"].clone();
+        beta.update_from_matches(m)?;
+        config.update_host("beta", beta)
+    }
+
+    #[test]
+    fn edit_host_changes_only_the_given_settings() {
+        let mut config = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.persistent_keepalive = Some(25);
+        beta.last_seen = Some(Utc::now());
+        beta.dynamic = true;
+        beta.tags.insert(String::from("env"), String::from("prod"));
+        beta.tags
+            .insert(String::from("team"), String::from("infra"));
+        config.add_host(beta.clone()).unwrap();
+
+        edit_beta(&mut config, &["--endpoint", "203.0.113.1:51820"]).unwrap();
+
+        let edited = config.hosts_by_name()["beta"].clone();
+        assert_eq!(edited.endpoint.as_deref(), Some("203.0.113.1:51820"));
+        assert_eq!(edited.public_key, beta.public_key);
+        assert_eq!(edited.private_key, beta.private_key);
+        assert_eq!(edited.wireguard_address, beta.wireguard_address);
+        assert_eq!(edited.persistent_keepalive, Some(25));
+        assert_eq!(edited.last_seen, beta.last_seen);
+        assert!(edited.dynamic);
+        assert_eq!(edited.tags, beta.tags);
+
+        edit_beta(
+            &mut config,
+            &["--no-dynamic", "--remove-tag", "env", "--tag", "owner=ops"],
+        )
+        .unwrap();
+
+        let edited = config.hosts_by_name()["beta"].clone();
+        assert!(!edited.dynamic);
+        assert_eq!(edited.tags.get("env"), None);
+        assert_eq!(edited.tags["team"], "infra");
+        assert_eq!(edited.tags["owner"], "ops");
+    }
+
+    #[test]
+    fn edit_host_moves_the_host_to_its_new_address() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+        let old: IpNet = "10.42.0.2/24".parse().unwrap();
+        let new: IpNet = "10.42.0.9/24".parse().unwrap();
+
+        edit_beta(&mut config, &["--wireguard-address", "10.42.0.9/24"]).unwrap();
+
+        assert!(!config.remote_hosts.contains_key(&old));
+        assert_eq!(config.remote_hosts[&new].name, "beta");
+        assert_eq!(config.remote_hosts.len(), 2);
+        // a taken address leaves the host where it was
+        let err = edit_beta(&mut config, &["--wireguard-address", "10.42.0.3/24"]).unwrap_err();
+        assert!(matches!(err, Error::AddressCollision { host, .. } if host == "gamma"));
+        assert_eq!(config.remote_hosts[&new].name, "beta");
+    }
+
+    #[test]
+    fn roles_change_what_is_rendered() {
+        let mut config = config();
+        let mut relay = host("relay", "10.42.0.2/24");
+        relay.role = HostRole::Relay;
+        let mut resolver = host("resolver", "10.42.0.3/24");
+        resolver.role = HostRole::DnsServer;
+        config.add_host(relay).unwrap();
+        config.add_host(resolver).unwrap();
+        config.add_host(host("client", "10.42.0.4/24")).unwrap();
+
+        let rendered = wgconf::parse(&config.render_config().unwrap()).unwrap();
+        let peers = rendered_peers(&config);
+
+        assert_eq!(rendered[0].list("DNS"), vec!["10.42.0.3"]);
+        assert_eq!(rendered[0].value("PostUp"), None);
+        assert_eq!(
+            peers["relay"].value("PersistentKeepalive"),
+            Some(DEFAULT_RELAY_KEEPALIVE.to_string().as_str())
+        );
+        assert_eq!(peers["resolver"].value("PersistentKeepalive"), None);
+        assert_eq!(peers["client"].value("PersistentKeepalive"), None);
+        // without a gateway every peer only routes its own address
+        assert_eq!(peers["client"].list("AllowedIPs"), vec!["10.42.0.4/32"]);
+        assert_eq!(Host::default().role, HostRole::Client);
+        // the resolver is pushed to the other hosts too
+        let client_config = config.for_host("client").unwrap();
+        let rendered = wgconf::parse(&client_config.render_config().unwrap()).unwrap();
+        assert_eq!(rendered[0].list("DNS"), vec!["10.42.0.3"]);
+    }
+
+    #[test]
+    fn diff_reports_a_changed_endpoint_and_a_removed_host() {
+        let mut old = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("198.51.100.2:51820"));
+        old.add_host(beta).unwrap();
+        old.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+
+        let mut new = old.clone();
+        new.remove_host_by_name("gamma").unwrap();
+        let beta = new
+            .remote_hosts
+            .values_mut()
+            .find(|host| host.name == "beta")
+            .unwrap();
+        beta.endpoint = Some(String::from("198.51.100.9:51820"));
+
+        assert_eq!(
+            old.diff(&new).unwrap(),
+            ConfigDiff {
+                changed_fields: vec![],
+                added_hosts: vec![],
+                removed_hosts: vec![String::from("gamma")],
+                modified_hosts: vec![HostDiff {
+                    name: String::from("beta"),
+                    changes: vec![FieldChange {
+                        field: String::from("endpoint"),
+                        old: String::from("\"198.51.100.2:51820\""),
+                        new: String::from("\"198.51.100.9:51820\""),
+                    }],
+                }],
+            }
+        );
+        assert!(old.diff(&old).unwrap().is_empty());
+    }
+
+    #[test]
+    fn render_diff_shows_only_the_changed_peer_line() {
+        let mut config = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("198.51.100.2:51820"));
+        config.add_host(beta).unwrap();
+        let dir = temp_dir("render-diff");
+        let dir = dir.to_str().unwrap();
+        config.render(dir).unwrap();
+        assert_eq!(config.render_diff(dir, false).unwrap(), "");
+
+        let beta = config
+            .remote_hosts
+            .values_mut()
+            .find(|host| host.name == "beta")
+            .unwrap();
+        beta.endpoint = Some(String::from("198.51.100.9:51820"));
+        let written = std::fs::read_to_string(Path::new(dir).join("wg0.conf")).unwrap();
+        let diff = config.render_diff(dir, false).unwrap();
+
+        let changed: Vec<&str> = diff
+            .lines()
+            .filter(|line| line.starts_with(['-', '+']))
+            .filter(|line| !line.starts_with("---") && !line.starts_with("+++"))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                "-Endpoint = 198.51.100.2:51820",
+                "+Endpoint = 198.51.100.9:51820"
+            ]
+        );
+        // nothing was written
+        assert_eq!(
+            std::fs::read_to_string(Path::new(dir).join("wg0.conf")).unwrap(),
+            written
+        );
+    }
+
+    #[test]
+    fn slash_30_subnet_holds_two_hosts() {
+        let mut config = Config {
+            subnet: "10.42.0.0/30".parse().unwrap(),
+            host: host("alpha", "10.42.0.1/30"),
+            ..Config::default()
+        };
+        assert_eq!(config.subnet_capacity(), 2);
+
+        // at capacity
+        config.add_host(host("beta", "10.42.0.2/30")).unwrap();
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+        assert!(matches!(
+            config.next_available_address(),
+            Err(Error::SubnetFull(_))
+        ));
+
+        // over capacity
+        let gamma = host("gamma", "10.42.0.3/30");
+        assert!(matches!(
+            config.add_host(gamma.clone()),
+            Err(Error::SubnetFull(subnet)) if subnet == config.subnet
+        ));
+        assert_eq!(config.remote_hosts.len(), 1);
+        config.remote_hosts.insert(gamma.wireguard_address, gamma);
+        assert!(config.validate().contains(&String::from(
+            "3 hosts do not fit in subnet 10.42.0.0/30, it only has room for 2"
+        )));
+    }
+
+    #[test]
+    fn ipv6_subnets_never_fill_up() {
+        let config = Config {
+            subnet: "fd00:1::/64".parse().unwrap(),
+            ..config()
+        };
+        assert_eq!(config.subnet_capacity(), 1 << 64);
+        let config = Config {
+            subnet: "fd00::/8".parse().unwrap(),
+            ..config
+        };
+        assert_eq!(config.subnet_capacity(), 1 << 120);
+    }
+
+    #[test]
+    fn ipv4_subnets_hand_out_ipv4_addresses() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+
+        assert_eq!(
+            config.next_available_address().unwrap(),
+            "10.42.0.3/24".parse::<IpNet>().unwrap()
+        );
+        for _ in 0..20 {
+            let address = config.random_address().unwrap();
+            assert!(matches!(address, IpNet::V4(_)), "{}", address);
+            assert!(config.subnet.contains(&address.addr()), "{}", address);
+        }
+        let address = config.allocate_address("gamma").unwrap();
+        assert!(matches!(address, IpNet::V4(_)), "{}", address);
+        assert!(config.subnet.contains(&address.addr()), "{}", address);
+    }
+
+    #[test]
+    fn ipv6_subnets_hand_out_ipv6_addresses() {
+        let config = Config {
+            subnet: "fc12:3456:789a::/48".parse().unwrap(),
+            global_id: 0x12_3456_789a,
+            host: host("alpha", "fc12:3456:789a::1/64"),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            config.next_available_address().unwrap(),
+            "fc12:3456:789a::2/48".parse::<IpNet>().unwrap()
+        );
+        for _ in 0..20 {
+            let address = config.random_address().unwrap();
+            assert!(matches!(address, IpNet::V6(_)), "{}", address);
+            assert!(config.subnet.contains(&address.addr()), "{}", address);
+        }
+        let address = config.allocate_address("gamma").unwrap();
+        assert!(matches!(address, IpNet::V6(_)), "{}", address);
+        assert!(config.subnet.contains(&address.addr()), "{}", address);
+    }
+
+    #[test]
+    fn dual_stack_hosts_render_both_addresses() {
+        let mut config = config();
+        config
+            .host
+            .extra_addresses
+            .push("fc12:3456:789a::1/64".parse().unwrap());
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.extra_addresses
+            .push("fc12:3456:789a::2/64".parse().unwrap());
+        config.add_host(beta).unwrap();
+        // the IPv6 address of a host can not be reused either
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma
+            .extra_addresses
+            .push("fc12:3456:789a::2/64".parse().unwrap());
+        assert!(matches!(
+            config.add_host(gamma),
+            Err(Error::AddressCollision { host, .. }) if host == "beta"
+        ));
+
+        let sections = wgconf::parse(&config.render_config().unwrap()).unwrap();
+        assert_eq!(
+            sections[0].list("Address"),
+            vec!["10.42.0.1/24", "fc12:3456:789a::1/64"]
+        );
+        assert_eq!(
+            rendered_peers(&config)["beta"].list("AllowedIPs"),
+            vec!["10.42.0.2/32", "fc12:3456:789a::2/128"]
+        );
+    }
+
+    #[test]
+    fn merge_imports_the_hosts_of_a_separate_network() {
+        let mut ours = config();
+        ours.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        let mut theirs = Config {
+            subnet: "10.43.0.0/24".parse().unwrap(),
+            host: host("delta", "10.43.0.1/24"),
+            ..Config::default()
+        };
+        theirs.add_host(host("epsilon", "10.43.0.2/24")).unwrap();
+        theirs.add_host(host("zeta", "10.43.0.3/24")).unwrap();
+
+        ours.merge(theirs).unwrap();
+
+        let mut names: Vec<&str> = ours
+            .remote_hosts
+            .values()
+            .map(|h| h.name.as_str())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["beta", "epsilon", "zeta"]);
+        assert_eq!(ours.host.name, "alpha");
+    }
+
+    #[test]
+    fn merge_reports_every_collision_and_imports_nothing() {
+        let mut ours = config();
+        ours.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        let mut theirs = config();
+        theirs.add_host(host("beta", "10.42.0.9/24")).unwrap();
+        theirs.add_host(host("gamma", "10.42.0.2/24")).unwrap();
+
+        match ours.merge(theirs) {
+            Err(Error::MergeConflict(collisions)) => assert_eq!(
+                collisions,
+                vec![
+                    "host name \"beta\" is already used",
+                    "address 10.42.0.2 of \"gamma\" is already used by \"beta\"",
+                ]
+            ),
+            other => panic!("expected a merge conflict, got {:?}", other),
+        }
+        assert_eq!(ours.remote_hosts.len(), 1);
+
+        let overlapping = Config {
+            subnet: "10.42.0.0/16".parse().unwrap(),
+            ..config()
+        };
+        assert!(matches!(
+            ours.merge(overlapping),
+            Err(Error::OverlappingSubnets(..))
+        ));
+    }
+
+    #[test]
+    fn interrupted_save_leaves_the_original_intact() {
+        let dir = temp_dir("interrupted-save");
+        let path = dir.join("network.yml");
+        let path = path.to_str().unwrap();
+        let original = config();
+        original.save(path).unwrap();
+        let before = std::fs::read(path).unwrap();
+
+        // the temporary file can not be written, so the save fails before the rename
+        let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+        std::fs::create_dir(&tmp_path).unwrap();
+        let mut changed = original.clone();
+        changed.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        assert!(changed.save(path).is_err());
+
+        assert_eq!(std::fs::read(path).unwrap(), before);
+        let loaded = Config::try_from_path(path).unwrap();
+        assert_eq!(loaded.network_id, original.network_id);
+        assert!(loaded.remote_hosts.is_empty());
+
+        // a partial temporary file left by a crashed writer does not get in the way
+        std::fs::remove_dir(&tmp_path).unwrap();
+        std::fs::write(&tmp_path, "host:\n  name: trunc").unwrap();
+        changed.save(path).unwrap();
+        assert_eq!(Config::try_from_path(path).unwrap().remote_hosts.len(), 1);
+        assert!(!Path::new(&tmp_path).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_keeps_the_permissions_of_the_file_it_replaces() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("save-permissions");
+        let path = dir.join("network.yml");
+        let path = path.to_str().unwrap();
+        let mode = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        config().save(path).unwrap();
+        assert_eq!(mode(path), 0o600);
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        config().save(path).unwrap();
+        assert_eq!(mode(path), 0o640);
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1, "temporary file left behind");
+    }
+
+    #[test]
+    fn concurrent_writers_holding_the_lock_lose_no_updates() {
+        let dir = temp_dir("concurrent-writers");
+        let path = dir.join("network.yml").to_string_lossy().into_owned();
+        config().save(&path).unwrap();
+
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for i in 0..10 {
+                        let _lock = ConfigLock::acquire(&path, Duration::from_secs(10)).unwrap();
+                        let mut config = Config::try_from_path(&path).unwrap();
+                        let name = format!("host-{}-{}", writer, i);
+                        let address = format!("10.42.0.{}/24", 10 + writer * 20 + i);
+                        config.add_host(host(&name, &address)).unwrap();
+                        config.save(&path).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(Config::try_from_path(&path).unwrap().remote_hosts.len(), 20);
+    }
+
+    #[test]
+    fn contended_lock_times_out_with_a_clear_error() {
+        let dir = temp_dir("lock-timeout");
+        let path = dir.join("network.yml").to_string_lossy().into_owned();
+        let held = ConfigLock::acquire(&path, Duration::from_secs(1)).unwrap();
+
+        assert!(ConfigLock::try_acquire(&path).unwrap().is_none());
+        let err = ConfigLock::acquire(&path, Duration::from_millis(200)).unwrap_err();
+        assert!(
+            matches!(&err, Error::LockTimeout { path: p, .. } if *p == path),
+            "{:?}",
+            err
+        );
+
+        drop(held);
+        assert!(ConfigLock::try_acquire(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn config_round_trips_through_every_format() {
+        let mut config = config();
+        config.set_dns(vec!["10.42.0.1".parse().unwrap()]);
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("198.51.100.2:51820"));
+        beta.persistent_keepalive = Some(25);
+        beta.last_seen = Some(Utc::now());
+        beta.tags.insert(String::from("env"), String::from("prod"));
+        beta.extra_addresses.push("fc12::2/64".parse().unwrap());
+        config.add_host(beta).unwrap();
+        let expected = serde_json::to_value(&config).unwrap();
+        let dir = temp_dir("formats");
+
+        for (file, format) in &[
+            ("network.yaml", ConfigFormat::Yaml),
+            ("network.yml", ConfigFormat::Yaml),
+            ("network.toml", ConfigFormat::Toml),
+            ("network.json", ConfigFormat::Json),
+            ("network.conf", ConfigFormat::Yaml),
+        ] {
+            let path = dir.join(file).to_string_lossy().into_owned();
+            assert_eq!(ConfigFormat::from_path(&path), *format, "{}", file);
+            config.save(&path).unwrap();
+
+            let text = std::fs::read_to_string(&path).unwrap();
+            match format {
+                ConfigFormat::Yaml => {
+                    assert!(serde_yaml::from_str::<serde_yaml::Value>(&text).is_ok())
+                }
+                ConfigFormat::Toml => assert!(toml::from_str::<toml::Value>(&text).is_ok()),
+                ConfigFormat::Json => {
+                    assert!(serde_json::from_str::<serde_json::Value>(&text).is_ok())
+                }
+            }
+            let loaded = Config::try_from_path(&path).unwrap();
+            assert_eq!(serde_json::to_value(&loaded).unwrap(), expected, "{}", file);
+        }
+    }
+
+    #[test]
+    fn rendered_peers_carry_no_private_keys() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+
+        let rendered = config.render_config().unwrap();
+        let sections = wgconf::parse(&rendered).unwrap();
+        assert_eq!(
+            sections[0].value("PrivateKey"),
+            Some(config.host.private_key.as_str())
+        );
+        assert_eq!(rendered.matches("PrivateKey").count(), 1);
+        for peer in config.remote_hosts.values() {
+            assert!(!rendered.contains(&peer.private_key), "{}", peer.name);
+        }
+    }
+
+    #[test]
+    fn secrets_split_out_and_merge_back() {
+        let mut config = config();
+        config.api_token = generate_api_token();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.preshared_key = Some(generate_preshared_key().unwrap());
+        config.add_host(beta.clone()).unwrap();
+        let dir = temp_dir("secrets");
+        let config_path = dir.join("network.yml").to_string_lossy().into_owned();
+        let secrets_path = dir.join("secrets.yml").to_string_lossy().into_owned();
+
+        config
+            .save_with_secrets(&config_path, &secrets_path)
+            .unwrap();
+
+        let public = std::fs::read_to_string(&config_path).unwrap();
+        let secret = std::fs::read_to_string(&secrets_path).unwrap();
+        let keys = [
+            config.host.private_key.as_str(),
+            beta.private_key.as_str(),
+            beta.preshared_key.as_deref().unwrap(),
+            config.api_token.as_str(),
+        ];
+        for key in &keys {
+            assert!(!public.contains(key), "{} leaked into the config", key);
+            assert!(secret.contains(key), "{} missing from the secrets", key);
+        }
+        let loaded = Config::load_with_secrets(&config_path, &secrets_path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+
+        // without the secrets the keys are left empty
+        std::fs::remove_file(&secrets_path).unwrap();
+        let keyless = Config::load_with_secrets(&config_path, &secrets_path).unwrap();
+        assert!(keyless.host.private_key.is_empty());
+        assert!(keyless
+            .remote_hosts
+            .values()
+            .all(|host| host.private_key.is_empty() && host.preshared_key.is_none()));
+        assert_eq!(keyless.host.public_key, config.host.public_key);
+    }
+
+    /// alpha, beta and gamma with the given topology
+    fn three_hosts(topology: Topology) -> Config {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+        config.set_topology(topology);
+        config
+    }
+
+    fn peer_names(config: &Config) -> Vec<String> {
+        let mut names: Vec<String> = rendered_peers(config).into_keys().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn full_mesh_peers_every_host_with_every_other() {
+        let config = three_hosts(Topology::FullMesh);
+
+        assert_eq!(peer_names(&config), vec!["beta", "gamma"]);
+        assert_eq!(
+            peer_names(&config.for_host("beta").unwrap()),
+            vec!["alpha", "gamma"]
+        );
+        assert_eq!(
+            peer_names(&config.for_host("gamma").unwrap()),
+            vec!["alpha", "beta"]
+        );
+    }
+
+    #[test]
+    fn hub_and_spoke_peers_spokes_only_with_the_hub() {
+        let config = three_hosts(Topology::HubAndSpoke {
+            hub: String::from("beta"),
+        });
+
+        let hub = config.for_host("beta").unwrap();
+        assert_eq!(peer_names(&hub), vec!["alpha", "gamma"]);
+        assert_eq!(peer_names(&config), vec!["beta"]);
+        let gamma = config.for_host("gamma").unwrap();
+        assert_eq!(peer_names(&gamma), vec!["beta"]);
+        // spokes reach each other through the hub
+        let peers = rendered_peers(&gamma);
+        let mut allowed_ips = peers["beta"].list("AllowedIPs");
+        allowed_ips.sort_unstable();
+        assert_eq!(allowed_ips, vec!["10.42.0.1/32", "10.42.0.2/32"]);
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+
+        let missing = three_hosts(Topology::HubAndSpoke {
+            hub: String::from("omega"),
+        });
+        assert!(matches!(
+            missing.render_config(),
+            Err(Error::HubNotFound(hub)) if hub == "omega"
+        ));
+        assert!(missing
+            .validate()
+            .contains(&String::from("hub \"omega\" is not in the network")));
+    }
+
+    #[test]
+    fn dot_has_a_node_per_host_and_an_edge_per_peering() {
+        let edges = |dot: &str| dot.lines().filter(|line| line.contains(" -- ")).count();
+        let mut config = three_hosts(Topology::FullMesh);
+        config.add_host(host("delta", "10.42.0.4/24")).unwrap();
+        config
+            .remote_hosts
+            .values_mut()
+            .find(|h| h.name == "beta")
+            .unwrap()
+            .role = HostRole::Relay;
+
+        let dot = config.to_dot();
+        assert!(dot.starts_with("graph wgmesh {"));
+        for (name, address) in &[
+            ("alpha", "10.42.0.1/24"),
+            ("beta", "10.42.0.2/24"),
+            ("gamma", "10.42.0.3/24"),
+            ("delta", "10.42.0.4/24"),
+        ] {
+            let node = format!("\"{}\" [label=\"{}\\n{}\"", name, name, address);
+            assert_eq!(dot.matches(&node).count(), 1, "{}", dot);
+        }
+        assert!(dot.contains("\"beta\\n10.42.0.2/24\", fillcolor=lightblue"));
+        assert!(dot.contains("\"alpha\\n10.42.0.1/24\", fillcolor=white"));
+        // every pair of the four hosts
+        assert_eq!(edges(&dot), 6);
+
+        config.set_topology(Topology::HubAndSpoke {
+            hub: String::from("beta"),
+        });
+        let dot = config.to_dot();
+        assert_eq!(edges(&dot), 3);
+        assert!(dot
+            .lines()
+            .filter(|line| line.contains(" -- "))
+            .all(|line| line.contains("\"beta\"")));
+    }
+
+    #[test]
+    fn validate_reports_hosts_cut_off_from_the_mesh() {
+        assert!(three_hosts(Topology::FullMesh).isolated_hosts().is_empty());
+        let hub = three_hosts(Topology::HubAndSpoke {
+            hub: String::from("gamma"),
+        });
+        assert!(hub.isolated_hosts().is_empty());
+
+        // without its hub no spoke peers with anything
+        let config = three_hosts(Topology::HubAndSpoke {
+            hub: String::from("omega"),
+        });
+        assert!(config.peerings().is_empty());
+        assert_eq!(config.isolated_hosts(), vec!["beta", "gamma"]);
+        let problems = config.validate();
+        for name in &["beta", "gamma"] {
+            let problem = format!("host \"{}\": can not reach the rest of the network", name);
+            assert!(problems.contains(&problem), "{:?}", problems);
+        }
+    }
+
+    #[test]
+    fn gateway_routes_its_site_lan() {
+        let args = [
+            "wgmesh",
+            "add-host",
+            "office",
+            "-a",
+            "10.42.0.2/24",
+            "--route",
+            "192.168.1.0/24",
+            "-u",
+            "dGVzdA==",
+        ];
+        let matches = cli().try_get_matches_from(args).unwrap();
+        let office = Host::try_from(matches.subcommand_matches("add-host").unwrap()).unwrap();
+        assert_eq!(
+            office.allowed_subnets,
+            vec!["192.168.1.0/24".parse::<IpNet>().unwrap()]
+        );
+        let mut config = config();
+        config.add_host(office).unwrap();
+
+        assert_eq!(
+            rendered_peers(&config)["office"].list("AllowedIPs"),
+            vec!["10.42.0.2/32", "192.168.1.0/24"]
+        );
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+
+        // a second site claiming part of the same LAN can not be routed
+        let mut branch = host("branch", "10.42.0.3/24");
+        branch
+            .allowed_subnets
+            .push("192.168.1.128/25".parse().unwrap());
+        config.add_host(branch).unwrap();
+        assert_eq!(
+            config.validate(),
+            vec!["hosts \"branch\" and \"office\" route overlapping networks 192.168.1.128/25 and 192.168.1.0/24"]
+        );
+        assert!(matches!(
+            config.render_config(),
+            Err(Error::OverlappingAllowedIps { .. })
+        ));
+    }
+
+    #[test]
+    fn ansible_inventory_reaches_every_host() {
+        let mut config = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("beta.example.com:51820"));
+        beta.role = HostRole::Relay;
+        beta.tags.insert(String::from("dc"), String::from("ams-1"));
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.endpoint = Some(String::from("[2001:db8::3]:51820"));
+        config.add_host(beta).unwrap();
+        config.add_host(gamma).unwrap();
+        config.add_host(host("delta", "10.42.0.4/24")).unwrap();
+
+        let inventory: serde_yaml::Value =
+            serde_yaml::from_str(&config.to_ansible_inventory().unwrap()).unwrap();
+
+        let hosts = &inventory["all"]["hosts"];
+        for (name, ansible_host) in &[
+            ("alpha", "10.42.0.1"),
+            ("beta", "beta.example.com"),
+            ("gamma", "2001:db8::3"),
+            ("delta", "10.42.0.4"),
+        ] {
+            assert_eq!(
+                hosts[*name]["ansible_host"].as_str(),
+                Some(*ansible_host),
+                "{}",
+                name
+            );
+        }
+        let members = |group: &str| -> Vec<String> {
+            inventory["all"]["children"][group]["hosts"]
+                .as_mapping()
+                .unwrap()
+                .iter()
+                .map(|(key, _)| key.as_str().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(members("role_client"), vec!["alpha", "delta", "gamma"]);
+        assert_eq!(members("role_relay"), vec!["beta"]);
+        assert_eq!(members("tag_dc_ams_1"), vec!["beta"]);
+    }
+
+    #[test]
+    fn hosts_file_parses_back_into_every_address() {
+        let mut config = config();
+        config
+            .host
+            .extra_addresses
+            .push("fc12::1/64".parse().unwrap());
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.extra_addresses.push("fc12::3/64".parse().unwrap());
+        config.add_host(gamma).unwrap();
+
+        let parse = |text: &str| -> Vec<(IpAddr, Vec<String>)> {
+            text.lines()
+                .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+                .map(|line| {
+                    let mut fields = line.split_whitespace();
+                    let address = fields.next().unwrap().parse().unwrap();
+                    (address, fields.map(String::from).collect())
+                })
+                .collect()
+        };
+        let names = |name: &str, domain: &str| vec![format!("{}.{}", name, domain), name.into()];
+
+        let entries = parse(&config.to_hosts_file(".mesh."));
+        assert_eq!(
+            entries,
+            vec![
+                ("10.42.0.1".parse().unwrap(), names("alpha", "mesh")),
+                ("fc12::1".parse().unwrap(), names("alpha", "mesh")),
+                ("10.42.0.2".parse().unwrap(), names("beta", "mesh")),
+                ("10.42.0.3".parse().unwrap(), names("gamma", "mesh")),
+                ("fc12::3".parse().unwrap(), names("gamma", "mesh")),
+            ]
+        );
+        let corp = parse(&config.to_hosts_file("corp.example"));
+        assert_eq!(corp[2].1, names("beta", "corp.example"));
+        let bare = parse(&config.to_hosts_file(""));
+        assert_eq!(bare[2].1, vec![String::from("beta")]);
+    }
+
+    #[test]
+    fn zone_file_has_a_record_per_address_and_a_serial_that_follows_changes() {
+        let serial = |zone: &str| -> u32 {
+            let soa = zone.lines().find(|line| line.contains(" SOA ")).unwrap();
+            let fields = soa.split('(').nth(1).unwrap();
+            fields.split_whitespace().next().unwrap().parse().unwrap()
+        };
+        let mut config = config();
+        config
+            .host
+            .extra_addresses
+            .push("fc12::1/64".parse().unwrap());
+        let zone = config.to_zone_file("mesh.example.");
+        assert!(zone.starts_with("$ORIGIN mesh.example.\n"));
+        assert_eq!(serial(&zone), serial(&config.to_zone_file("mesh.example")));
+
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.name = String::from("web_01");
+        config.remote_hosts.insert(beta.wireguard_address, beta);
+        // nothing usable as a label is left of this name
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.name = String::from("__");
+        config.remote_hosts.insert(gamma.wireguard_address, gamma);
+        let changed = config.to_zone_file("mesh.example");
+
+        let records: Vec<Vec<&str>> = changed
+            .lines()
+            .filter(|line| line.contains(" IN A"))
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                vec!["alpha", "IN", "A", "10.42.0.1"],
+                vec!["alpha", "IN", "AAAA", "fc12::1"],
+                vec!["web-01", "IN", "A", "10.42.0.2"],
+            ]
+        );
+        assert_ne!(serial(&changed), serial(&zone));
+        assert_ne!(serial(&changed), 0);
+        config.remove_host_by_name("web_01").unwrap();
+        let reverted = config.to_zone_file("mesh.example");
+        assert_ne!(serial(&reverted), serial(&changed));
+    }
+
+    #[test]
+    fn single_network_files_load_and_convert_when_a_network_is_added() {
+        let dir = temp_dir("networks-single");
+        let path = dir.join("network.yml").to_string_lossy().into_owned();
+        let home = config();
+        home.save(&path).unwrap();
+
+        assert!(network_names(&path).unwrap().is_empty());
+        let loaded = Config::load_network(&path, None).unwrap();
+        assert_eq!(loaded.network_id, home.network_id);
+        assert_eq!(network_directory("out", &path, None).unwrap(), "out");
+
+        let office = Config {
+            subnet: "10.43.0.0/24".parse().unwrap(),
+            host: host("office", "10.43.0.1/24"),
+            ..Config::default()
+        };
+        office.save_network(&path, Some("office")).unwrap();
+
+        assert_eq!(network_names(&path).unwrap(), vec!["default", "office"]);
+        let default = Config::load_network(&path, Some(DEFAULT_NETWORK)).unwrap();
+        assert_eq!(default.network_id, home.network_id);
+        let loaded = Config::load_network(&path, Some("office")).unwrap();
+        assert_eq!(loaded.network_id, office.network_id);
+        assert_eq!(loaded.subnet, office.subnet);
+    }
+
+    #[test]
+    fn multi_network_files_need_a_network_chosen() {
+        let dir = temp_dir("networks-multi");
+        let path = dir.join("networks.yml").to_string_lossy().into_owned();
+        let home = config();
+        home.save_network(&path, Some("home")).unwrap();
+
+        // a single network does not need to be named
+        assert_eq!(network_names(&path).unwrap(), vec!["home"]);
+        let loaded = Config::try_from_path(&path).unwrap();
+        assert_eq!(loaded.network_id, home.network_id);
+
+        let lab = Config {
+            subnet: "10.44.0.0/24".parse().unwrap(),
+            ..config()
+        };
+        lab.save_network(&path, Some("lab")).unwrap();
+        match Config::try_from_path(&path) {
+            Err(Error::NetworkNotChosen { networks, .. }) => {
+                assert_eq!(networks, vec!["home", "lab"])
+            }
+            other => panic!(
+                "expected the network to be asked for, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+        assert!(matches!(
+            Config::load_network(&path, Some("work")),
+            Err(Error::NetworkNotFound { network, .. }) if network == "work"
+        ));
+        let loaded = Config::load_network(&path, Some("lab")).unwrap();
+        assert_eq!(loaded.subnet, lab.subnet);
+
+        // saving one network leaves the other alone
+        let mut changed = loaded;
+        changed.add_host(host("beta", "10.44.0.2/24")).unwrap();
+        changed.save_network(&path, Some("lab")).unwrap();
+        let home_loaded = Config::load_network(&path, Some("home")).unwrap();
+        assert_eq!(home_loaded.network_id, home.network_id);
+        assert!(home_loaded.remote_hosts.is_empty());
+
+        let out = dir.join("out").to_string_lossy().into_owned();
+        let lab_dir = network_directory(&out, &path, Some("lab")).unwrap();
+        assert_eq!(Path::new(&lab_dir), dir.join("out").join("lab"));
+        assert!(Path::new(&lab_dir).is_dir());
+    }
+
+    #[test]
+    fn library_failures_are_distinct_error_variants() {
+        let dir = temp_dir("error-variants");
+        let missing = dir.join("missing.yml");
+        let garbled = dir.join("garbled.yml");
+        std::fs::write(&garbled, "host: [unclosed").unwrap();
+        let mut config = config();
+        let beta = host("beta", "10.42.0.2/24");
+        let mut twin = host("gamma", "10.42.0.3/24");
+        twin.public_key = beta.public_key.clone();
+        config.add_host(beta).unwrap();
+
+        assert!(matches!(
+            Config::load_network(missing.to_str().unwrap(), None),
+            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(matches!(
+            Config::load_network(garbled.to_str().unwrap(), None),
+            Err(Error::Yaml(_))
+        ));
+        assert!(matches!(
+            config.add_host(twin),
+            Err(Error::PublicKeyCollision { host, existing }) if host == "gamma" && existing == "beta"
+        ));
+        assert!(matches!(
+            config.add_host(host("bad name", "10.42.0.4/24")),
+            Err(Error::InvalidName { .. })
+        ));
+        assert!(matches!(
+            generate_public_key("not a key"),
+            Err(Error::InvalidKey)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn default_config_is_built_in_memory() {
+        // nothing random or read from the machine, so every default is the same
+        let first = serde_json::to_value(Config::default()).unwrap();
+        let second = serde_json::to_value(Config::default()).unwrap();
+        assert_eq!(first, second);
+
+        let mut config = Config::default();
+        assert_eq!(config.network_id, Uuid::nil());
+        assert_eq!(config.host.name, "");
+        assert_eq!(first["host"]["interfaces"], serde_json::json!([]));
+        assert!(config
+            .subnet
+            .contains(&config.host.wireguard_address.addr()));
+        let address = config.next_available_address().unwrap();
+        config.add_host(host("beta", &address.to_string())).unwrap();
+        assert_eq!(rendered_peers(&config).len(), 1);
+    }
+
+    #[test]
+    fn hosts_behind_nat_reach_each_other_through_the_relay() {
+        let mut config = config();
+        let mut relay = host("relay", "10.42.0.2/24");
+        relay.role = HostRole::Relay;
+        relay.endpoint = Some(String::from("relay.example.com:51820"));
+        let mut public = host("public", "10.42.0.3/24");
+        public.endpoint = Some(String::from("public.example.com:51820"));
+        config.add_host(relay).unwrap();
+        config.add_host(public).unwrap();
+        config.add_host(host("beta", "10.42.0.4/24")).unwrap();
+        config.set_prefer_relay_for_nat(true);
+
+        // alpha and beta are both without an endpoint
+        for (client, other) in &[("alpha", "10.42.0.4/32"), ("beta", "10.42.0.1/32")] {
+            let view = if *client == "alpha" {
+                config.clone()
+            } else {
+                config.for_host(client).unwrap()
+            };
+            let peers = rendered_peers(&view);
+            let mut names: Vec<&String> = peers.keys().collect();
+            names.sort();
+            assert_eq!(names, ["public", "relay"], "{}", client);
+            assert!(
+                peers["relay"].list("AllowedIPs").contains(other),
+                "{}: {:?}",
+                client,
+                peers["relay"].list("AllowedIPs")
+            );
+        }
+
+        config.set_prefer_relay_for_nat(false);
+        assert_eq!(rendered_peers(&config).len(), 3);
+    }
+
+    #[test]
+    fn imported_configs_match_what_was_rendered() {
+        let dir = temp_dir("import-round-trip");
+        let mut config = config();
+        config.dns = vec!["10.42.0.53".parse().unwrap()];
+        config.listen_port = 51821;
+        config.host.mtu = Some(1380);
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("198.51.100.2:51820"));
+        beta.persistent_keepalive = Some(25);
+        beta.allowed_subnets = vec!["192.168.1.0/24".parse().unwrap()];
+        config.add_host(beta).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+        config.render(dir.to_str().unwrap()).unwrap();
+
+        let imported = Config::import_wg_conf(dir.to_str().unwrap(), "wg0").unwrap();
+
+        assert_eq!(imported.subnet, config.subnet);
+        assert_eq!(imported.dns, config.dns);
+        assert_eq!(imported.listen_port, 51821);
+        assert_eq!(imported.host.private_key, config.host.private_key);
+        assert_eq!(imported.host.public_key, config.host.public_key);
+        assert_eq!(
+            imported.host.wireguard_address,
+            config.host.wireguard_address
+        );
+        assert_eq!(imported.host.mtu, Some(1380));
+        let (original, imported) = (config.hosts_by_name(), imported.hosts_by_name());
+        assert_eq!(imported.len(), 2);
+        for (name, host) in original {
+            let peer = &imported[&name];
+            assert_eq!(peer.public_key, host.public_key, "{}", name);
+            assert_eq!(peer.wireguard_address, host.wireguard_address, "{}", name);
+            assert_eq!(peer.endpoint, host.endpoint, "{}", name);
+            assert_eq!(peer.allowed_subnets, host.allowed_subnets, "{}", name);
+            assert_eq!(
+                peer.persistent_keepalive, host.persistent_keepalive,
+                "{}",
+                name
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interfaces_import_despite_comments_and_odd_spacing() {
+        let private_key = generate_private_key().unwrap();
+        let text = format!(
+            "# managed by hand\n\n  [Interface]  \nPrivateKey={}   \n  Address = 10.42.0.1/24 , fc12::1/64 # both\n; MTU = 9000\nMTU=1420\n",
+            private_key
+        );
+
+        let host = Host::from_wg_conf(&text).unwrap();
+
+        assert_eq!(host.private_key, private_key);
+        assert_eq!(host.public_key, generate_public_key(&private_key).unwrap());
+        assert_eq!(host.wireguard_address, "10.42.0.1/24".parse().unwrap());
+        assert_eq!(host.extra_addresses, vec!["fc12::1/64".parse().unwrap()]);
+        assert_eq!(host.mtu, Some(1420));
+        assert!(matches!(
+            Host::from_wg_conf("[Peer]\nPublicKey = x\n"),
+            Err(Error::MissingSection("Interface"))
+        ));
+    }
+
+    #[test]
+    fn syncconf_command_names_the_interface_and_file() {
+        let cmd = wg_syncconf_command("wg0", Path::new("/run/wgmesh/wg0.conf"));
+
+        assert_eq!(cmd.get_program(), "wg");
+        let args: Vec<&std::ffi::OsStr> = cmd.get_args().collect();
+        assert_eq!(args, ["syncconf", "wg0", "/run/wgmesh/wg0.conf"]);
+    }
+
+    #[test]
+    fn drift_lists_extra_missing_and_changed_peers() {
+        let mut config = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("198.51.100.2:51820"));
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.endpoint = Some(String::from("gamma.example.com:51820"));
+        let delta = host("delta", "10.42.0.4/24");
+        let stranger = host("stranger", "10.42.0.5/24").public_key;
+        let dump = format!(
+            "{private}\t{public}\t51820\toff\n\
+             {beta}\t(none)\t198.51.100.9:51820\t10.42.0.2/32\t1600000000\t1024\t2048\toff\n\
+             {gamma}\t(none)\t203.0.113.7:40000\t10.42.0.3/32\t1600000000\t10\t20\toff\n\
+             {stranger}\t(none)\t(none)\t10.42.0.5/32\t0\t0\t0\toff\n",
+            private = config.host.private_key,
+            public = config.host.public_key,
+            beta = beta.public_key,
+            gamma = gamma.public_key,
+            stranger = stranger,
+        );
+        config.add_host(beta).unwrap();
+        config.add_host(gamma).unwrap();
+        config.add_host(delta).unwrap();
+
+        let running = host::parse_wg_dump(&dump).unwrap();
+        let drift = config.drift(&running).unwrap();
+
+        assert_eq!(running.len(), 3);
+        assert_eq!(drift.added_hosts, vec![stranger]);
+        assert_eq!(drift.removed_hosts, vec!["delta"]);
+        // gamma roams, only beta's fixed endpoint is expected to match
+        assert_eq!(drift.modified_hosts.len(), 1);
+        let beta = &drift.modified_hosts[0];
+        assert_eq!(beta.name, "beta");
+        assert_eq!(beta.changes.len(), 1);
+        assert_eq!(beta.changes[0].field, "endpoint");
+        assert!(beta.changes[0].new.contains("198.51.100.9"));
+        assert!(config
+            .drift(&config.expected_peers().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn duplicate_public_keys_are_rejected_and_empty_ones_reported() {
+        let mut config = config();
+        let beta = host("beta", "10.42.0.2/24");
+        let mut copy = host("copy", "10.42.0.3/24");
+        copy.public_key = beta.public_key.clone();
+        let mut local_copy = host("local-copy", "10.42.0.3/24");
+        local_copy.public_key = config.host.public_key.clone();
+        config.add_host(beta).unwrap();
+
+        let err = config.add_host(copy.clone()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "host \"copy\" has the same public key as host \"beta\""
+        );
+        assert!(matches!(
+            config.add_host(local_copy),
+            Err(Error::PublicKeyCollision { existing, .. }) if existing == "alpha"
+        ));
+        assert_eq!(config.remote_hosts.len(), 1);
+
+        // hosts added without keys are given them later, so they do not collide
+        for (name, address) in &[("keyless", "10.42.0.4/24"), ("other", "10.42.0.5/24")] {
+            let mut keyless = host(name, address);
+            keyless.public_key = String::new();
+            config.add_host(keyless).unwrap();
+        }
+        config.remote_hosts.insert(copy.wireguard_address, copy);
+        let problems = config.validate();
+        for expected in &[
+            "host \"keyless\": public key is empty",
+            "host \"other\": public key is empty",
+            "hosts \"beta\", \"copy\" share a public key",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem == expected),
+                "missing {:?} in {:?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn custom_interface_names_are_rendered_imported_and_saved() {
+        let dir = temp_dir("custom-interface");
+        let directory = dir.to_str().unwrap();
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        assert_eq!(config.interface_name(), "wg0");
+
+        config.set_interface_name("wgmesh0").unwrap();
+        config.render(directory).unwrap();
+        config
+            .render_systemd(directory, config.interface_name())
+            .unwrap();
+
+        assert!(dir.join("wgmesh0.conf").exists());
+        assert!(!dir.join("wg0.conf").exists());
+        let unit = std::fs::read_to_string(dir.join("wg-quick@wgmesh0.service")).unwrap();
+        assert!(unit.contains("wgmesh0"), "{}", unit);
+        let imported = Config::import_wg_conf(directory, "wgmesh0").unwrap();
+        assert_eq!(imported.interface_name(), "wgmesh0");
+        assert_eq!(imported.hosts_by_name().len(), 1);
+        let path = dir.join("network.yml");
+        config.save(path.to_str().unwrap()).unwrap();
+        let loaded = Config::load_network(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(loaded.interface_name(), "wgmesh0");
+
+        for invalid in &["", "sixteen-chars-xx", "wg 0", "wg/0"] {
+            assert!(
+                matches!(
+                    config.set_interface_name(invalid),
+                    Err(Error::InvalidInterfaceName(name)) if name == *invalid
+                ),
+                "{:?}",
+                invalid
+            );
+        }
+        assert_eq!(config.interface_name(), "wgmesh0");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn listen_ports_are_rendered_and_fill_in_endpoint_ports() {
+        let mut config = config();
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("beta.example.com"));
+        beta.listen_port = Some(51821);
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.endpoint = Some(String::from("198.51.100.3"));
+        let mut delta = host("delta", "10.42.0.4/24");
+        delta.endpoint = Some(String::from("delta.example.com:4500"));
+        delta.listen_port = Some(51822);
+        config.add_host(beta).unwrap();
+        config.add_host(gamma).unwrap();
+        config.add_host(delta).unwrap();
+
+        let interface = &wgconf::parse(&config.render_config().unwrap()).unwrap()[0];
+        let peers = rendered_peers(&config);
+
+        assert_eq!(interface.value("ListenPort"), Some("51820"));
+        assert_eq!(
+            peers["beta"].value("Endpoint"),
+            Some("beta.example.com:51821")
+        );
+        assert_eq!(peers["gamma"].value("Endpoint"), Some("198.51.100.3:51820"));
+        // an explicit port wins over the listen port
+        assert_eq!(
+            peers["delta"].value("Endpoint"),
+            Some("delta.example.com:4500")
+        );
+        let beta = config.for_host("beta").unwrap();
+        let interface = &wgconf::parse(&beta.render_config().unwrap()).unwrap()[0];
+        assert_eq!(interface.value("ListenPort"), Some("51821"));
+    }
+
+    #[test]
+    fn wireguard_port_argument_sets_the_listen_port() {
+        let add = |flags: &[&str]| {
+            let args = [
+                "wgmesh",
+                "add-host",
+                "beta",
+                "-a",
+                "10.42.0.2/24",
+                "-u",
+                "dGVzdA==",
+            ];
+            let matches = cli()
+                .try_get_matches_from(args.iter().chain(flags))
+                .unwrap();
+            Host::try_from(matches.subcommand_matches("add-host").unwrap())
+        };
+
+        assert_eq!(
+            add(&["--wireguard-port", "51820"]).unwrap().listen_port,
+            Some(51820)
+        );
+        assert_eq!(add(&["--port", "51821"]).unwrap().listen_port, Some(51821));
+        assert_eq!(add(&[]).unwrap().listen_port, None);
+        for invalid in &["port", "65536", "0x1F"] {
+            assert!(
+                matches!(
+                    add(&["--wireguard-port", invalid]),
+                    Err(Error::InvalidArgument {
+                        name: "wireguard_port",
+                        ..
+                    })
+                ),
+                "{}",
+                invalid
+            );
+        }
+
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        edit_beta(&mut config, &["--wireguard-port", "51822"]).unwrap();
+        assert_eq!(config.hosts_by_name()["beta"].listen_port, Some(51822));
+        edit_beta(&mut config, &["--mtu", "1380"]).unwrap();
+        assert_eq!(config.hosts_by_name()["beta"].listen_port, Some(51822));
+    }
+
+    #[test]
+    fn signatures_only_verify_the_signed_config_with_the_right_key() {
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+        let signing_key = generate_signing_key();
+        let public_key = signing_public_key(&signing_key).unwrap();
+        let signed = config.without_secrets();
+
+        let signature = signed.sign(&signing_key).unwrap();
+
+        assert!(signed.verify(&signature, &public_key));
+        // the signature covers the content, not the order hosts happen to serialize in
+        let copy: Config = serde_yaml::from_str(&serde_yaml::to_string(&signed).unwrap()).unwrap();
+        assert!(copy.verify(&signature, &public_key));
+        let mut tampered = signed.clone();
+        tampered
+            .add_host(host("intruder", "10.42.0.66/24"))
+            .unwrap();
+        assert!(!tampered.verify(&signature, &public_key));
+        let mut tampered = signed.clone();
+        tampered.remote_hosts.values_mut().next().unwrap().endpoint =
+            Some(String::from("203.0.113.66:51820"));
+        assert!(!tampered.verify(&signature, &public_key));
+        let other_key = signing_public_key(&generate_signing_key()).unwrap();
+        assert!(!signed.verify(&signature, &other_key));
+        assert!(!signed.verify("not base64!", &public_key));
+        assert!(!signed.verify(&signature, "not a key"));
+        assert!(matches!(signed.sign("short"), Err(Error::InvalidKey)));
+    }
+
+    #[test]
+    fn routed_subnets_may_hold_other_peers_addresses() {
+        let mut config = config();
+        let mut office = host("office", "10.42.0.2/24");
+        office.allowed_subnets.push("10.0.0.0/8".parse().unwrap());
+        config.add_host(office).unwrap();
+        config.add_host(host("beta", "10.42.0.3/24")).unwrap();
+
+        let peers = rendered_peers(&config);
+
+        assert_eq!(
+            peers["office"].list("AllowedIPs"),
+            vec!["10.42.0.2/32", "10.0.0.0/8"]
+        );
+        assert_eq!(peers["beta"].list("AllowedIPs"), vec!["10.42.0.3/32"]);
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+
+        // routing a peer's own address elsewhere is still ambiguous
+        let mut thief = host("thief", "10.42.0.4/24");
+        thief.allowed_subnets.push("10.42.0.3/32".parse().unwrap());
+        config.add_host(thief).unwrap();
+        assert!(matches!(
+            config.render_config(),
+            Err(Error::OverlappingAllowedIps { .. })
+        ));
+        let problem =
+            "hosts \"beta\" and \"thief\" route overlapping networks 10.42.0.3/32 and 10.42.0.3/32";
+        assert!(
+            config.validate().iter().any(|p| p == problem),
+            "{:?}",
+            config.validate()
+        );
+    }
+
+    #[test]
+    fn runtime_configs_get_a_fresh_private_directory_that_is_removed() {
+        let config = config();
+
+        let (first, contents) = config
+            .with_runtime_config(|path| {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = std::fs::metadata(path.parent().unwrap())
+                        .unwrap()
+                        .permissions()
+                        .mode();
+                    assert_eq!(mode & 0o777, 0o700);
+                }
+                Ok((path.to_owned(), std::fs::read_to_string(path)?))
+            })
+            .unwrap();
+        let second = config
+            .with_runtime_config(|path| Ok(path.to_owned()))
+            .unwrap();
+
+        assert_eq!(contents, config.render_config().unwrap());
+        assert_eq!(first.file_name().unwrap(), "wg0.conf");
+        assert_ne!(first.parent(), second.parent());
+        assert!(!first.parent().unwrap().exists());
+        assert!(!second.parent().unwrap().exists());
+        // failures still clean up
+        let mut kept = None;
+        let failed: Result<()> = config.with_runtime_config(|path| {
+            kept = Some(path.to_owned());
+            Err(Error::MissingSection("Interface"))
+        });
+        assert!(failed.is_err());
+        assert!(!kept.unwrap().parent().unwrap().exists());
+    }
 }
@@ -0,0 +1,248 @@
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use log::{Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+
+/// How log lines are written, picked with the global `--log-format` flag
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(crate::Error::UnknownLogFormat(other.to_owned())),
+        }
+    }
+}
+
+/// Target the actix request logger logs under. With JSON logs its messages are JSON objects,
+/// see `server::REQUEST_LOG_FORMAT`, which are nested as the `request` field.
+const REQUEST_LOG_TARGET: &str = "actix_web::middleware::logger";
+
+/// File logs are copied to, as given with `--log-file`
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Bytes the file grows to before it is rotated
+    pub max_size: u64,
+    /// Rotated files to keep, as `<path>.1` (the newest) up to `<path>.<keep>`
+    pub keep: usize,
+}
+
+/// Log file that is moved aside once it reaches its maximum size
+struct RotatingFile {
+    settings: LogFile,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open the file for appending. Fails if it can not be written to.
+    fn open(settings: LogFile) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.path)
+            .map_err(|source| crate::Error::LogFile {
+                path: settings.path.clone(),
+                source,
+            })?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            settings,
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.settings.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.settings.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.settings.keep > 0 {
+            for n in (1..self.settings.keep).rev() {
+                match std::fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.settings.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.settings.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Logs to the console, as text or JSON, and optionally copies every line to a rotating file
+struct Logger {
+    format: LogFormat,
+    console: SimpleLogger,
+    level: log::LevelFilter,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Logger {
+    /// Record as a JSON object with its timestamp, level, target and message
+    fn json_line(record: &Record) -> String {
+        let message = record.args().to_string();
+        let mut line = serde_json::json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": message,
+        });
+        if record.target() == REQUEST_LOG_TARGET {
+            if let Ok(request @ serde_json::Value::Object(_)) = serde_json::from_str(&message) {
+                line["request"] = request;
+            }
+        }
+        line.to_string()
+    }
+
+    fn text_line(record: &Record) -> String {
+        format!(
+            "{} {:<5} [{}] {}",
+            Utc::now().format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            record.target(),
+            record.args()
+        )
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let json = match self.format {
+            LogFormat::Text => {
+                self.console.log(record);
+                None
+            }
+            LogFormat::Json => {
+                let line = Logger::json_line(record);
+                eprintln!("{}", line);
+                Some(line)
+            }
+        };
+        if let Some(file) = &self.file {
+            let line = json.unwrap_or_else(|| Logger::text_line(record));
+            if let Ok(mut file) = file.lock() {
+                // there is nowhere left to report a failing log file
+                let _ = file.write_line(&line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+/// Install the global logger. Logs go to the console in `format` and, with a `log_file`, are
+/// copied to that file too.
+pub fn configure_logging(
+    log_level: &str,
+    format: LogFormat,
+    log_file: Option<LogFile>,
+) -> crate::Result<()> {
+    let level = log::LevelFilter::from_str(log_level)?;
+    let file = log_file
+        .map(RotatingFile::open)
+        .transpose()?
+        .map(Mutex::new);
+    log::set_boxed_logger(Box::new(Logger {
+        format,
+        console: SimpleLogger::new().with_level(level),
+        level,
+        file,
+    }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_dir;
+
+    #[test]
+    fn full_log_files_are_rotated_keeping_the_newest() {
+        let dir = temp_dir("log-rotation");
+        let path = dir.join("wgmesh.log");
+        let mut file = RotatingFile::open(LogFile {
+            path: path.clone(),
+            max_size: 100,
+            keep: 2,
+        })
+        .unwrap();
+
+        // 50 byte lines, two fit in a file
+        for i in 0..7 {
+            file.write_line(&format!("{:<49}", i)).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("wgmesh.log").trim_end(), "6");
+        assert!(read("wgmesh.log.1").starts_with("4 "));
+        assert!(read("wgmesh.log.2").starts_with("2 "));
+        assert!(!dir.join("wgmesh.log.3").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unwritable_log_file_is_an_error() {
+        let dir = temp_dir("log-unwritable");
+
+        let result = RotatingFile::open(LogFile {
+            path: dir.join("missing").join("wgmesh.log"),
+            max_size: 100,
+            keep: 1,
+        });
+
+        assert!(matches!(result, Err(crate::Error::LogFile { .. })));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,36 +1,503 @@
 use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use actix;
-use wgmesh::{cli, configure_logging, generate_private_key, Config, Host};
+use wgmesh::{
+    cli, configure_logging, AuditOperation, Config, ConfigLock, Host, KeyPair, LogFile,
+    OutputFormat,
+};
 
 fn main() {
-    let localhost = Host::local().unwrap();
-    let private_key = generate_private_key().unwrap();
     let args = cli().get_matches();
-    configure_logging(args.value_of("log_level").unwrap_or("info"));
+    let log_format = args
+        .value_of("log_format")
+        .unwrap_or("text")
+        .parse()
+        .expect("invalid log format");
+    let log_file = args.value_of("log_file").map(|path| LogFile {
+        path: path.into(),
+        max_size: args
+            .value_of("log_file_size")
+            .and_then(|size| size.parse::<u64>().ok())
+            .expect("invalid log file size")
+            * 1024
+            * 1024,
+        keep: args
+            .value_of("log_file_keep")
+            .and_then(|keep| keep.parse().ok())
+            .expect("invalid log file count"),
+    });
+    configure_logging(
+        args.value_of("log_level").unwrap_or("info"),
+        log_format,
+        log_file,
+    )
+    .expect("could not configure logging");
     let config_path = args.value_of("config").unwrap();
-    let mut config = match Config::try_from_path(&config_path) {
-        Ok(config) => config,
-        Err(_) => Config::default(),
+    let network = args.value_of("network");
+    if let Some(("genkey", m)) = args.subcommand() {
+        let keys = if m.is_present("signing") {
+            let signing_key = wgmesh::generate_signing_key();
+            KeyPair {
+                public_key: wgmesh::signing_public_key(&signing_key)
+                    .expect("could not derive public key"),
+                private_key: signing_key,
+                preshared_key: None,
+            }
+        } else {
+            KeyPair::generate(m.is_present("preshared_key")).expect("could not generate keys")
+        };
+        let format = OutputFormat::from_matches(m).expect("invalid output format");
+        let output = format
+            .render(&keys, |keys| {
+                let mut out = format!(
+                    "PrivateKey = {}\nPublicKey = {}\n",
+                    keys.private_key, keys.public_key
+                );
+                if let Some(preshared_key) = &keys.preshared_key {
+                    out.push_str(&format!("PresharedKey = {}\n", preshared_key));
+                }
+                out
+            })
+            .expect("could not render keys");
+        print!("{}", output);
+        return;
+    }
+    // the first run of any command starts a new network, which is saved like any other change
+    let creating = !Path::new(config_path).exists();
+    let mutating = matches!(
+        args.subcommand_name(),
+        Some(
+            "init"
+                | "import"
+                | "join"
+                | "add-host"
+                | "edit-host"
+                | "remove-host"
+                | "set-dns"
+                | "set-audit-log"
+                | "set-interface"
+        )
+    );
+    // held until the command finishes so concurrent commands do not lose each other's changes,
+    // read-only commands never save and do not need it
+    let lock = if mutating || creating {
+        Some(
+            ConfigLock::acquire(config_path, wgmesh::CONFIG_LOCK_TIMEOUT)
+                .expect("could not lock config"),
+        )
+    } else {
+        None
+    };
+    if let Some(("init", m)) = args.subcommand() {
+        let config = Config::init(config_path, network, m.is_present("force"))
+            .expect("could not create config");
+        println!("Created network {} in {}", config.network_id(), config_path);
+        println!(
+            "{} is {}",
+            config.local_host().name,
+            config.local_host().wireguard_address
+        );
+        return;
+    }
+    if let Some(("import", m)) = args.subcommand() {
+        if !m.is_present("force") && Path::new(config_path).exists() {
+            panic!(
+                "{} already exists, use --force to overwrite it",
+                config_path
+            );
+        }
+        let config = Config::import_wg_conf(
+            m.value_of("directory").unwrap(),
+            m.value_of("interface").unwrap(),
+        )
+        .expect("could not import wireguard config");
+        config
+            .save_network(config_path, network)
+            .expect("could not save config");
+        println!(
+            "Imported {} with {} peer(s) into {}",
+            config.local_host().name,
+            config.sorted_hosts().len(),
+            config_path
+        );
+        return;
+    }
+    if let Some(("join", m)) = args.subcommand() {
+        let exists = match network {
+            Some(name) => wgmesh::network_names(config_path)
+                .expect("could not read config")
+                .iter()
+                .any(|existing| existing == name),
+            None => Path::new(config_path).exists(),
+        };
+        if !m.is_present("force") && exists {
+            panic!(
+                "{} already exists, use --force to overwrite it",
+                network.unwrap_or(config_path)
+            );
+        }
+        let url = m.value_of("url").unwrap().to_owned();
+        let token = m.value_of("token").unwrap().to_owned();
+        let config = actix::System::new("join")
+            .block_on(async move { wgmesh::client::join(&url, &token).await })
+            .expect("could not join network");
+        config
+            .save_network(config_path, network)
+            .expect("could not save config");
+        let directory =
+            wgmesh::network_directory(m.value_of("directory").unwrap(), config_path, network)
+                .expect("could not create directory");
+        config.render(&directory).expect("could not render config");
+        println!(
+            "Joined network {} as {}",
+            config.network_id(),
+            config.local_host().wireguard_address
+        );
+        return;
+    }
+    // only start a new network when there is no config, never overwrite one that fails to load
+    let mut config = if creating {
+        let config = Config::new().expect("could not create config");
+        config
+            .save_network(config_path, network)
+            .expect("could not save config");
+        config
+    } else {
+        Config::load_network(config_path, network).expect("could not load config")
+    };
+    // each network of a file holding several renders to its own directory
+    let network_directory = |m: &clap::ArgMatches| {
+        wgmesh::network_directory(m.value_of("directory").unwrap(), config_path, network)
+            .expect("could not create directory")
     };
-    config.save(&config_path).expect("could not save config");
     match args.subcommand() {
         Some(("add-host", m)) => {
-            let host = Host::try_from(m).unwrap();
+            let mut host = Host::try_from(m).unwrap();
+            if !m.is_present("wireguard_address") {
+                host.wireguard_address = config
+                    .next_available_address()
+                    .expect("could not assign wireguard address");
+            }
+            if m.value_of("endpoint") == Some("auto") {
+                let stun_server = m.value_of("stun_server").unwrap();
+                let public = wgmesh::host::discover_public_endpoint(stun_server)
+                    .expect("could not discover public endpoint");
+                // the STUN port belongs to a throwaway socket, peers connect to wireguard's port
+                let port = host.listen_port.unwrap_or_else(|| config.listen_port());
+                host.endpoint = Some(SocketAddr::new(public.ip(), port).to_string());
+            }
+            println!("Adding {} as {}", &host.name, &host.wireguard_address);
+            let name = host.name.clone();
             config.add_host(host).unwrap();
+            config
+                .save_network(config_path, network)
+                .expect("could not save config");
+            config
+                .audit(AuditOperation::AddHost, &name, &wgmesh::local_actor())
+                .expect("could not write audit log");
+        }
+        Some(("edit-host", m)) => {
+            let name = m.value_of("name").expect("host name not provided");
+            let mut host = config
+                .hosts_by_name()
+                .get(name)
+                .map(|host| (*host).clone())
+                .unwrap_or_else(|| panic!("no host named \"{}\"", name));
+            host.update_from_matches(m).expect("invalid host settings");
+            config
+                .update_host(name, host)
+                .expect("could not update host");
+            config
+                .save_network(config_path, network)
+                .expect("could not save config");
+            config
+                .audit(AuditOperation::EditHost, name, &wgmesh::local_actor())
+                .expect("could not write audit log");
+            println!("Updated {}", name);
         }
         Some(("server", m)) => {
             println!("server");
-            let bind = m.value_of("bind").and_then(|b| b.parse().ok()).unwrap();
+            let seconds = |name: &str| {
+                m.value_of(name)
+                    .and_then(|t| t.parse().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| panic!("invalid {}", name))
+            };
+            let host_expiry = seconds("host_expiry");
+            let resolve_interval = seconds("resolve_interval");
+            let options = wgmesh::server::ServerOptions {
+                bind: m.value_of("bind").and_then(|b| b.parse().ok()).unwrap(),
+                config_path: config_path.to_string(),
+                network: network.map(String::from),
+                log_format,
+                token: m
+                    .value_of("token")
+                    .map(String::from)
+                    .unwrap_or_else(|| config.api_token().to_string()),
+                heartbeat_timeout: seconds("heartbeat_timeout"),
+                save_interval: seconds("save_interval"),
+                host_expiry: Some(host_expiry).filter(|expiry| *expiry > Duration::from_secs(0)),
+                resolve_interval: Some(resolve_interval)
+                    .filter(|interval| *interval > Duration::from_secs(0)),
+                signing_key: m.value_of("signing_key").map(String::from),
+            };
+            // the server takes the lock itself whenever it saves
+            drop(lock);
             actix::run(async move {
-                wgmesh::server::server(bind, config).await.unwrap();
+                wgmesh::server::server(config, options).await.unwrap();
             })
             .unwrap();
         }
+        Some(("validate", m)) => {
+            let problems = config.validate();
+            let format = OutputFormat::from_matches(m).expect("invalid output format");
+            let output = format
+                .render(&problems, |problems| {
+                    if problems.is_empty() {
+                        format!("{} is valid\n", config_path)
+                    } else {
+                        let mut out: String = problems.iter().map(|p| format!("{}\n", p)).collect();
+                        out.push_str(&format!(
+                            "found {} problem(s) in {}\n",
+                            problems.len(),
+                            config_path
+                        ));
+                        out
+                    }
+                })
+                .expect("could not render problems");
+            print!("{}", output);
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Some(("diff", m)) => {
+            let other_path = m.value_of("other").unwrap();
+            let other = Config::try_from_path(other_path).expect("could not load other config");
+            let diff = config.diff(&other).expect("could not compare configs");
+            let format = OutputFormat::from_matches(m).expect("invalid output format");
+            let output = format
+                .render(&diff, |diff| {
+                    if diff.is_empty() {
+                        return format!("{} and {} are the same\n", config_path, other_path);
+                    }
+                    let mut out = String::new();
+                    for change in &diff.changed_fields {
+                        out.push_str(&format!(
+                            "~ {}: {} -> {}\n",
+                            change.field, change.old, change.new
+                        ));
+                    }
+                    for name in &diff.added_hosts {
+                        out.push_str(&format!("+ host {}\n", name));
+                    }
+                    for name in &diff.removed_hosts {
+                        out.push_str(&format!("- host {}\n", name));
+                    }
+                    for host in &diff.modified_hosts {
+                        out.push_str(&format!("~ host {}\n", host.name));
+                        for change in &host.changes {
+                            out.push_str(&format!(
+                                "    {}: {} -> {}\n",
+                                change.field, change.old, change.new
+                            ));
+                        }
+                    }
+                    out
+                })
+                .expect("could not render diff");
+            print!("{}", output);
+        }
+        Some(("status", m)) => {
+            let dump = wgmesh::host::wg_dump(config.interface_name())
+                .expect("could not read interface state");
+            let running = wgmesh::host::parse_wg_dump(&dump).expect("could not parse wg output");
+            let drift = config.drift(&running).expect("could not compare peers");
+            let format = OutputFormat::from_matches(m).expect("invalid output format");
+            let output = format
+                .render(&drift, |drift| {
+                    if drift.is_empty() {
+                        return format!("{} matches {}\n", config.interface_name(), config_path);
+                    }
+                    let mut out = String::new();
+                    for key in &drift.added_hosts {
+                        out.push_str(&format!("+ peer {} is not in the config\n", key));
+                    }
+                    for name in &drift.removed_hosts {
+                        out.push_str(&format!("- host {} is not on the interface\n", name));
+                    }
+                    for host in &drift.modified_hosts {
+                        out.push_str(&format!("~ host {}\n", host.name));
+                        for change in &host.changes {
+                            out.push_str(&format!(
+                                "    {}: {} -> {}\n",
+                                change.field, change.old, change.new
+                            ));
+                        }
+                    }
+                    out
+                })
+                .expect("could not render status");
+            print!("{}", output);
+            if !drift.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Some(("list-hosts", m)) => {
+            let filters: Vec<(String, String)> = m
+                .values_of("filter")
+                .map(|values| values.map(wgmesh::host::parse_tag).collect())
+                .unwrap_or_else(|| Ok(Vec::new()))
+                .expect("invalid filter");
+            let hosts: Vec<&Host> = config
+                .sorted_hosts()
+                .into_iter()
+                .filter(|host| host.has_tags(&filters))
+                .collect();
+            let format = OutputFormat::from_matches(m).expect("invalid output format");
+            let output = format
+                .render(&hosts, |hosts| {
+                    let mut out = format!(
+                        "{:<20} {:<40} {:<12} {}\n",
+                        "NAME", "ADDRESS", "PUBLIC KEY", "LAST SEEN"
+                    );
+                    for host in hosts {
+                        let mut public_key: String = host.public_key.chars().take(8).collect();
+                        if public_key.len() < host.public_key.len() {
+                            public_key.push_str("...");
+                        }
+                        out.push_str(&format!(
+                            "{:<20} {:<40} {:<12} {}\n",
+                            host.name,
+                            host.wireguard_address.to_string(),
+                            public_key,
+                            host.last_seen
+                                .map(|t| t.to_rfc3339())
+                                .unwrap_or_else(|| String::from("never"))
+                        ));
+                    }
+                    out
+                })
+                .expect("could not render hosts");
+            print!("{}", output);
+        }
+        Some(("set-dns", m)) => {
+            let dns = m
+                .values_of("dns")
+                .map(|values| values.map(|v| v.parse()).collect())
+                .unwrap_or_else(|| Ok(Vec::new()))
+                .expect("invalid dns address");
+            config.set_dns(dns);
+            config
+                .save_network(config_path, network)
+                .expect("could not save config");
+        }
+        Some(("set-audit-log", m)) => {
+            config.set_audit_log(m.value_of("path").map(PathBuf::from));
+            config
+                .save_network(config_path, network)
+                .expect("could not save config");
+        }
+        Some(("set-interface", m)) => {
+            config
+                .set_interface_name(m.value_of("name").unwrap())
+                .expect("invalid interface name");
+            config
+                .save_network(config_path, network)
+                .expect("could not save config");
+        }
+        Some(("render", m)) => {
+            let systemd = m.is_present("systemd");
+            if m.is_present("stdout") {
+                config
+                    .render_to_writer(&mut std::io::stdout(), systemd)
+                    .expect("could not render config");
+            } else if m.is_present("dry_run") {
+                let directory = network_directory(m);
+                let diff = config
+                    .render_diff(&directory, systemd)
+                    .expect("could not render config");
+                if diff.is_empty() {
+                    println!("{} is up to date", directory);
+                } else {
+                    print!("{}", diff);
+                }
+            } else {
+                let directory = network_directory(m);
+                config.render(&directory).expect("could not render config");
+                if systemd {
+                    config
+                        .render_systemd(&directory, config.interface_name())
+                        .expect("could not render systemd unit");
+                }
+            }
+        }
+        Some(("up", _)) => {
+            config
+                .up()
+                .unwrap_or_else(|err| panic!("could not bring the interface up: {}", err));
+            println!("{} is up", config.interface_name());
+        }
+        Some(("apply", _)) => {
+            config
+                .apply()
+                .unwrap_or_else(|err| panic!("could not apply config: {}", err));
+            println!("{} is up to date", config.interface_name());
+        }
+        Some(("down", _)) => {
+            config
+                .down()
+                .unwrap_or_else(|err| panic!("could not take the interface down: {}", err));
+            println!("{} is down", config.interface_name());
+        }
+        Some(("render-qr", m)) => {
+            let name = m.value_of("name").unwrap();
+            match m.value_of("png") {
+                Some(path) => config
+                    .render_qr(name, path)
+                    .expect("could not render qr code"),
+                None => println!(
+                    "{}",
+                    config
+                        .render_qr_terminal(name)
+                        .expect("could not render qr code")
+                ),
+            }
+        }
+        Some(("export", m)) => match m.value_of("format") {
+            Some("ansible") => print!(
+                "{}",
+                config
+                    .to_ansible_inventory()
+                    .expect("could not export inventory")
+            ),
+            Some("hosts") => print!("{}", config.to_hosts_file(m.value_of("domain").unwrap())),
+            Some("zone") => print!("{}", config.to_zone_file(m.value_of("domain").unwrap())),
+            _ => unreachable!(),
+        },
+        Some(("topology", m)) => {
+            if m.is_present("dot") {
+                print!("{}", config.to_dot());
+            } else {
+                for (a, b) in config.peerings() {
+                    println!("{} <-> {}", a.name, b.name);
+                }
+            }
+        }
         Some(("remove-host", m)) => {
             let name = m.value_of("name").expect("host name not provided");
-            //config.remove_host(&name);
-            println!("Removed {} from network", &name);
+            config
+                .remove_host_by_name(name)
+                .expect("could not remove host");
+            config
+                .save_network(config_path, network)
+                .expect("could not save config");
+            config
+                .audit(AuditOperation::RemoveHost, name, &wgmesh::local_actor())
+                .expect("could not write audit log");
+            println!("Removed 1 host ({}) from network", &name);
         }
         _ => unreachable!(),
     }
@@ -0,0 +1,452 @@
+use serde_json::{json, Map, Value};
+
+/// Reference to a schema under `components`
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// JSON response with the given schema
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+/// JSON request body with the given schema
+fn json_body(schema: Value) -> Value {
+    json!({
+        "required": true,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+fn error(description: &str) -> Value {
+    json!({ "description": description })
+}
+
+/// Path parameter naming a host
+fn host_name_parameter() -> Value {
+    json!({ "name": "name", "in": "path", "required": true, "schema": { "type": "string" } })
+}
+
+/// OpenAPI 3 document describing the routes of the server daemon, served at `/openapi.json`
+pub fn document() -> Value {
+    let host_list = json!({ "type": "array", "items": schema("Host") });
+    let event_list = json!({ "type": "array", "items": schema("Event") });
+    let mut paths = Map::new();
+    paths.insert(
+        "/ping".into(),
+        json!({
+            "get": {
+                "summary": "Check the server is up",
+                "security": [],
+                "responses": { "200": { "description": "pong" } }
+            }
+        }),
+    );
+    paths.insert(
+        "/".into(),
+        json!({
+            "get": {
+                "summary": "Network config with its secrets blanked",
+                "responses": {
+                    "200": json_response("Network config", schema("Config")),
+                    "304": error("Config unchanged since the ETag in If-None-Match")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/connect".into(),
+        json!({
+            "post": {
+                "summary": "Mark a host online, adding it to the network if it is new",
+                "requestBody": json_body(schema("Host")),
+                "responses": {
+                    "200": { "description": "Host connected" },
+                    "409": error("Host name or address already in use")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/disconnect".into(),
+        json!({
+            "post": {
+                "summary": "Mark a host offline",
+                "requestBody": json_body(schema("Host")),
+                "responses": {
+                    "200": { "description": "Host disconnected" },
+                    "404": error("Unknown host")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/discover".into(),
+        json!({
+            "get": {
+                "summary": "Every host in the network with their secrets blanked",
+                "responses": { "200": json_response("Hosts", host_list) }
+            }
+        }),
+    );
+    paths.insert(
+        "/hosts".into(),
+        json!({
+            "post": {
+                "summary": "Add a host",
+                "requestBody": json_body(schema("Host")),
+                "responses": {
+                    "201": json_response("Added host", schema("Host")),
+                    "400": error("Host does not fit in the network"),
+                    "409": error("Host name or address already in use")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/hosts/{name}".into(),
+        json!({
+            "parameters": [host_name_parameter()],
+            "get": {
+                "summary": "Fetch a host",
+                "responses": {
+                    "200": json_response("Host", schema("Host")),
+                    "404": error("Unknown host")
+                }
+            },
+            "put": {
+                "summary": "Replace a host",
+                "requestBody": json_body(schema("Host")),
+                "responses": {
+                    "200": json_response("Updated host", schema("Host")),
+                    "404": error("Unknown host"),
+                    "409": error("Host name or address already in use")
+                }
+            },
+            "delete": {
+                "summary": "Remove a host",
+                "responses": {
+                    "200": json_response("Removed host", schema("Host")),
+                    "404": error("Unknown host")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/events".into(),
+        json!({
+            "get": {
+                "summary": "Recorded events, newest first",
+                "parameters": [
+                    { "name": "limit", "in": "query", "schema": { "type": "integer" } },
+                    { "name": "offset", "in": "query", "schema": { "type": "integer" } },
+                    {
+                        "name": "since",
+                        "in": "query",
+                        "schema": { "type": "string", "format": "date-time" }
+                    },
+                    {
+                        "name": "until",
+                        "in": "query",
+                        "schema": { "type": "string", "format": "date-time" }
+                    },
+                    {
+                        "name": "type",
+                        "in": "query",
+                        "schema": {
+                            "type": "string",
+                            "enum": ["connect", "disconnect", "endpoint_changed"]
+                        }
+                    }
+                ],
+                "responses": {
+                    "200": json_response("Events", event_list.clone()),
+                    "400": error("Invalid timestamp or event type")
+                }
+            },
+            "post": {
+                "summary": "Record an event and apply it to the network",
+                "requestBody": json_body(schema("Event")),
+                "responses": {
+                    "201": json_response("Recorded event", schema("Event")),
+                    "409": error("Event already recorded")
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/events/batch".into(),
+        json!({
+            "post": {
+                "summary": "Record several events at once",
+                "requestBody": json_body(event_list),
+                "responses": {
+                    "200": json_response("Accepted and rejected events", schema("BatchReport"))
+                }
+            }
+        }),
+    );
+    paths.insert(
+        "/events/stream".into(),
+        json!({
+            "get": {
+                "summary": "WebSocket pushing every event as it is recorded",
+                "responses": { "101": { "description": "Switching to the WebSocket protocol" } }
+            }
+        }),
+    );
+    paths.insert(
+        "/health".into(),
+        json!({
+            "get": {
+                "summary": "Server status",
+                "responses": { "200": json_response("Health", schema("Health")) }
+            }
+        }),
+    );
+    paths.insert(
+        "/openapi.json".into(),
+        json!({
+            "get": {
+                "summary": "This document",
+                "responses": { "200": { "description": "OpenAPI 3 document" } }
+            }
+        }),
+    );
+    paths.insert(
+        "/metrics".into(),
+        json!({
+            "get": {
+                "summary": "Prometheus metrics",
+                "responses": {
+                    "200": {
+                        "description": "Metrics in the Prometheus text format",
+                        "content": { "text/plain": { "schema": { "type": "string" } } }
+                    }
+                }
+            }
+        }),
+    );
+    let mut schemas = Map::new();
+    schemas.insert(
+        "Host".into(),
+        json!({
+            "type": "object",
+            "required": ["name", "wireguard_address", "public_key", "private_key"],
+            "properties": {
+                "name": { "type": "string" },
+                "last_seen": { "type": "string", "format": "date-time", "nullable": true },
+                "status": { "type": "string", "enum": ["Online", "Offline"] },
+                "wireguard_address": { "type": "string", "example": "10.42.0.2/24" },
+                "extra_addresses": { "type": "array", "items": { "type": "string" } },
+                "public_key": { "type": "string" },
+                "private_key": { "type": "string" },
+                "interfaces": { "type": "array", "items": { "type": "object" } },
+                "allowed_subnets": { "type": "array", "items": { "type": "string" } },
+                "persistent_keepalive": { "type": "integer", "nullable": true },
+                "mtu": { "type": "integer", "nullable": true },
+                "post_up": { "type": "array", "items": { "type": "string" } },
+                "post_down": { "type": "array", "items": { "type": "string" } },
+                "role": schema("HostRole"),
+                "endpoint": { "type": "string", "nullable": true },
+                "resolved_endpoint": { "type": "string" },
+                "listen_port": { "type": "integer", "nullable": true },
+                "preshared_key": { "type": "string", "nullable": true },
+                "dynamic": { "type": "boolean" },
+                "tags": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" }
+                }
+            }
+        }),
+    );
+    schemas.insert(
+        "HostRole".into(),
+        json!({
+            "oneOf": [
+                { "type": "string", "enum": ["Client", "Relay", "DnsServer"] },
+                {
+                    "type": "object",
+                    "properties": {
+                        "Gateway": {
+                            "type": "object",
+                            "properties": { "out_interface": { "type": "string" } }
+                        }
+                    }
+                }
+            ]
+        }),
+    );
+    schemas.insert(
+        "Event".into(),
+        json!({
+            "type": "object",
+            "required": ["id", "created_at", "data"],
+            "properties": {
+                "id": { "type": "string", "format": "uuid" },
+                "created_at": { "type": "string", "format": "date-time" },
+                "data": schema("EventData")
+            }
+        }),
+    );
+    schemas.insert(
+        "EventData".into(),
+        json!({
+            "type": "object",
+            "description": "Exactly one of the properties is set",
+            "properties": {
+                "Connect": { "type": "object", "properties": { "host": schema("Host") } },
+                "Disconnect": {
+                    "type": "object",
+                    "properties": { "host": schema("Host") }
+                },
+                "EndpointChanged": {
+                    "type": "object",
+                    "properties": { "host": schema("Host") }
+                }
+            }
+        }),
+    );
+    schemas.insert(
+        "Config".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "version": { "type": "string" },
+                "network_id": { "type": "string", "format": "uuid" },
+                "subnet": { "type": "string" },
+                "global_id": { "type": "integer" },
+                "listen_port": { "type": "integer" },
+                "dns": { "type": "array", "items": { "type": "string" } },
+                "api_token": { "type": "string" },
+                "topology": {},
+                "prefer_relay_for_nat": { "type": "boolean" },
+                "interface_name": { "type": "string", "example": "wg0" },
+                "audit_log": { "type": "string", "nullable": true },
+                "host": schema("Host"),
+                "remote_hosts": {
+                    "type": "object",
+                    "description": "Hosts keyed by their wireguard address",
+                    "additionalProperties": schema("Host")
+                }
+            }
+        }),
+    );
+    schemas.insert(
+        "BatchReport".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "accepted": {
+                    "type": "array",
+                    "items": { "type": "string", "format": "uuid" }
+                },
+                "rejected": {
+                    "type": "array",
+                    "items": { "type": "string", "format": "uuid" }
+                }
+            }
+        }),
+    );
+    schemas.insert(
+        "Health".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "version": { "type": "string" },
+                "uptime_seconds": { "type": "integer" },
+                "hosts": { "type": "integer" },
+                "hosts_online": { "type": "integer" },
+                "events": { "type": "integer" },
+                "event_capacity": { "type": "integer" }
+            }
+        }),
+    );
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "wgmesh",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Coordinates the hosts of a wireguard mesh network"
+        },
+        "security": [{ "bearer": [] }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" }
+            },
+            "schemas": schemas
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, host};
+    use crate::{BatchReport, Event};
+
+    /// Every `$ref` in the value
+    fn refs(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(reference)) = map.get("$ref") {
+                    out.push(reference.clone());
+                }
+                map.values().for_each(|value| refs(value, out));
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, out)),
+            _ => {}
+        }
+    }
+
+    fn keys(value: &Value) -> Vec<String> {
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn every_reference_resolves() {
+        let document = document();
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                document["components"]["schemas"].get(name).is_some(),
+                "{} does not resolve",
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn schemas_list_every_serialized_field() {
+        let document = document();
+        let schemas = &document["components"]["schemas"];
+        let mut config = config();
+        config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+        config.set_audit_log(Some("audit.log".into()));
+        let event = Event::connect(host("gamma", "10.42.0.3/24"));
+        // optional fields are left out when unset
+        let mut full = host("delta", "10.42.0.4/24");
+        full.listen_port = Some(51820);
+        full.resolved_endpoint = Some("192.0.2.1:51820".parse().unwrap());
+        for (name, value) in &[
+            ("Host", serde_json::to_value(&full).unwrap()),
+            ("Config", serde_json::to_value(&config).unwrap()),
+            ("Event", serde_json::to_value(&event).unwrap()),
+            (
+                "BatchReport",
+                serde_json::to_value(BatchReport::default()).unwrap(),
+            ),
+        ] {
+            assert_eq!(keys(&schemas[name]["properties"]), keys(value), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use qrcode::render::unicode::Dense1x2;
+use qrcode::{Color, QrCode};
+
+/// Size of each QR code module in pixels
+const MODULE_SIZE: usize = 8;
+/// Width of the blank border around the code in modules, as required by the QR spec
+const QUIET_ZONE: usize = 4;
+
+/// Encode data as a QR code in PNG format
+pub fn png(data: &str) -> crate::Result<Vec<u8>> {
+    let code = QrCode::new(data.as_bytes())?;
+    let colors = code.to_colors();
+    let modules = code.width() + 2 * QUIET_ZONE;
+    let size = modules * MODULE_SIZE;
+    let mut pixels = vec![0xff_u8; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let x = (i % code.width() + QUIET_ZONE) * MODULE_SIZE;
+        let y = (i / code.width() + QUIET_ZONE) * MODULE_SIZE;
+        for row in y..y + MODULE_SIZE {
+            for pixel in &mut pixels[row * size + x..row * size + x + MODULE_SIZE] {
+                *pixel = 0;
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, size as u32, size as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)?;
+    }
+    Ok(out)
+}
+
+/// Encode data as a QR code drawn with unicode block characters for display in a terminal
+pub fn terminal(data: &str) -> crate::Result<String> {
+    let code = QrCode::new(data.as_bytes())?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_decodes_back_to_the_data() {
+        let data = "[Interface]\nPrivateKey = abc\nAddress = 10.42.0.2/24\n";
+
+        let image = png(data).unwrap();
+
+        assert!(!image.is_empty());
+        let decoder = ::png::Decoder::new(image.as_slice());
+        let (info, mut reader) = decoder.read_info().unwrap();
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        let (width, height) = (info.width as usize, info.height as usize);
+        let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(width, height, |x, y| {
+            pixels[y * width + x]
+        });
+        let grids = prepared.detect_grids();
+        assert_eq!(grids.len(), 1);
+        let (_, content) = grids[0].decode().unwrap();
+        assert_eq!(content, data);
+    }
+}
@@ -1,17 +1,48 @@
+use std::collections::HashMap;
 use std::fmt;
-use std::net::SocketAddr;
-use std::sync::{Arc, MutexGuard};
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use actix::{Actor, ActorContext, Handler, Message, Recipient, StreamHandler};
 use actix_web::{
-    dev::HttpResponseBuilder, error, get, http::header, http::StatusCode, middleware, post, web,
-    App, HttpResponse, HttpServer, Responder,
+    delete, dev::BodyEncoding, dev::BodySize, dev::HttpResponseBuilder, dev::MessageBody,
+    dev::Service, dev::ServiceRequest, error, get, http::header, http::ContentEncoding,
+    http::StatusCode, middleware, post, put, web, App, HttpRequest, HttpResponse, HttpServer,
+    Responder,
 };
-use chrono::Utc;
+use actix_web_actors::ws;
+use chrono::{DateTime, Utc};
 use lru::LruCache;
-use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::{Config, Event, EventData, Host};
+use crate::{
+    write_atomic, AuditOperation, BatchReport, Config, ConfigLock, Error, Event, EventData, Host,
+    HostStatus, LogFormat, CONFIG_LOCK_RETRY, CONFIG_LOCK_TIMEOUT,
+};
+
+/// Responses smaller than this many bytes are sent uncompressed, gzip would barely shrink them
+const COMPRESS_MIN_SIZE: u64 = 1024;
+
+/// Header carrying the id that ties a request to its log lines
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header carrying the base64 ed25519 signature of the config or host list in the body, see
+/// `Config::sign`
+pub const SIGNATURE_HEADER: &str = "x-wgmesh-signature";
+
+/// Request log line written as text, actix's default with the request id appended
+const REQUEST_LOG_TEXT_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#;
+
+/// Request log line written as a JSON object when logging JSON. The request line is the only
+/// field with client controlled text and it is percent encoded, so it can not break the quoting.
+/// Request ids are checked by `request_id` to not need escaping either.
+pub const REQUEST_LOG_FORMAT: &str = r#"{"remote_addr":"%a","request":"%r","status":%s,"bytes":%b,"seconds":%T,"request_id":"%{x-request-id}o"}"#;
 
 /// Quickly return a web service error with a status code and message
 #[derive(Debug, Clone)]
@@ -20,9 +51,229 @@ struct ServiceError(u16, &'static str);
 struct AppState {
     network_config: Config,
     events: LruCache<Uuid, Event>,
+    /// Open `/events/stream` sessions
+    subscribers: Vec<Recipient<StreamedEvent>>,
+    /// Requests served by each endpoint, reported by `/metrics`. Behind its own lock so read
+    /// only handlers can count themselves.
+    requests: Mutex<HashMap<&'static str, u64>>,
+    started_at: DateTime<Utc>,
+    /// Config served by `/`, serialized once per change instead of once per request. Anything
+    /// that changes the config, events and host edits alike, rebuilds it with `config_changed`.
+    config_json: web::Bytes,
+    /// `ETag` of `config_json`
+    config_etag: String,
+    /// Key the served config and host list are signed with, see `ServerOptions::signing_key`
+    signing_key: Option<String>,
+    /// Signature of the config served by `/`, rebuilt along with `config_json`
+    config_signature: Option<String>,
 }
 
-type State = web::Data<Arc<Mutex<AppState>>>;
+/// JSON body and quoted `ETag` of the config as served by `/`. The tag hashes the JSON value,
+/// whose maps are sorted, so it does not depend on hash map iteration order.
+fn serialize_config(config: &Config) -> (web::Bytes, String) {
+    let config = config.without_secrets();
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    let json = match (serde_json::to_vec(&config), serde_json::to_value(&config)) {
+        (Ok(json), Ok(value)) => {
+            value.to_string().hash(&mut hasher);
+            json
+        }
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("unable to serialize config: {}", err);
+            Vec::new()
+        }
+    };
+    (
+        web::Bytes::from(json),
+        format!("\"{:016x}\"", hasher.finish()),
+    )
+}
+
+/// Response of the `/health` endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Health {
+    pub version: String,
+    pub uptime_seconds: i64,
+    pub hosts: usize,
+    pub hosts_online: usize,
+    pub events: usize,
+    pub event_capacity: usize,
+}
+
+impl AppState {
+    fn count_request(&self, endpoint: &'static str) {
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests.entry(endpoint).or_insert(0) += 1;
+        }
+    }
+
+    fn online_hosts(&self) -> usize {
+        self.network_config
+            .remote_hosts
+            .values()
+            .filter(|host| host.status == HostStatus::Online)
+            .count()
+    }
+
+    /// Store an event in the cache and push it to every open event stream. Events are listed
+    /// to every client, so the keys of their hosts are dropped first.
+    fn record_event(&mut self, event: Event) {
+        let event = event.without_secrets();
+        match serde_json::to_string(&event) {
+            Ok(json) => {
+                let message = StreamedEvent(json);
+                // Sending fails once a session has stopped, which is when it gets dropped
+                self.subscribers
+                    .retain(|subscriber| subscriber.do_send(message.clone()).is_ok());
+            }
+            Err(err) => log::error!("unable to serialize event {}: {}", event.id, err),
+        }
+        self.events.put(event.id, event);
+        self.config_changed();
+    }
+
+    /// Rebuild what is cached from the config after changing it
+    fn config_changed(&mut self) {
+        let (json, etag) = serialize_config(&self.network_config);
+        self.config_json = json;
+        self.config_etag = etag;
+        self.config_signature = self.sign(&self.network_config.without_secrets());
+    }
+
+    /// Signature of a response body with the signing key, if the server has one
+    fn sign<T: Serialize>(&self, value: &T) -> Option<String> {
+        let signing_key = self.signing_key.as_deref()?;
+        match crate::sign_canonical(value, signing_key) {
+            Ok(signature) => Some(signature),
+            Err(err) => {
+                log::error!("unable to sign response: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Mark online hosts not seen within `timeout` as offline, recording a disconnect event for
+    /// each
+    fn expire_hosts(&mut self, timeout: Duration) {
+        let timeout = match chrono::Duration::from_std(timeout) {
+            Ok(timeout) => timeout,
+            Err(_) => return,
+        };
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        for host in self.network_config.remote_hosts.values_mut() {
+            let stale = host
+                .last_seen
+                .map(|last_seen| now - last_seen > timeout)
+                .unwrap_or(true);
+            if host.status == HostStatus::Online && stale {
+                host.status = HostStatus::Offline;
+                expired.push(host.clone());
+            }
+        }
+        for host in expired {
+            log::info!("{} missed its heartbeat, marking it offline", host.name);
+            self.record_event(Event::disconnect(host));
+        }
+    }
+
+    /// Record the addresses hostname endpoints resolved to, keyed by endpoint, and an event for
+    /// each host whose endpoint now points somewhere else
+    fn update_resolved_endpoints(&mut self, resolved: &HashMap<String, SocketAddr>) {
+        let mut changed = Vec::new();
+        for host in self.network_config.remote_hosts.values_mut() {
+            let (endpoint, addr) = match host
+                .endpoint_hostname()
+                .and_then(|endpoint| Some((endpoint, *resolved.get(endpoint)?)))
+            {
+                Some(resolution) => resolution,
+                None => continue,
+            };
+            if host.resolved_endpoint != Some(addr) {
+                log::info!(
+                    "{} endpoint {} now resolves to {}",
+                    host.name,
+                    endpoint,
+                    addr
+                );
+                host.resolved_endpoint = Some(addr);
+                changed.push(host.clone());
+            }
+        }
+        for host in changed {
+            self.record_event(Event::endpoint_changed(host));
+        }
+    }
+
+    /// Remove dynamic hosts not seen within `expiry`, recording a disconnect event for each.
+    /// Returns whether any host was removed.
+    fn remove_expired_hosts(&mut self, expiry: Duration) -> bool {
+        let expiry = match chrono::Duration::from_std(expiry) {
+            Ok(expiry) => expiry,
+            Err(_) => return false,
+        };
+        let now = Utc::now();
+        let expired: Vec<Host> = self
+            .network_config
+            .remote_hosts
+            .values()
+            .filter(|host| host.dynamic)
+            .filter(|host| matches!(host.last_seen, Some(last_seen) if now - last_seen > expiry))
+            .cloned()
+            .collect();
+        for host in &expired {
+            log::info!("{} expired, removing it from the network", host.name);
+            self.network_config.remove_host(&host.wireguard_address);
+            audit(
+                &self.network_config,
+                AuditOperation::RemoveHost,
+                &host.name,
+                "server",
+            );
+        }
+        let removed = !expired.is_empty();
+        for host in expired {
+            self.record_event(Event::disconnect(host));
+        }
+        removed
+    }
+}
+
+/// JSON encoded event pushed to an event stream session
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+struct StreamedEvent(String);
+
+/// WebSocket session forwarding recorded events to the client
+struct EventStream;
+
+impl Actor for EventStream {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+impl Handler<StreamedEvent> for EventStream {
+    type Result = ();
+
+    fn handle(&mut self, msg: StreamedEvent, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for EventStream {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
+
+type State = web::Data<Arc<RwLock<AppState>>>;
 
 impl fmt::Display for ServiceError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -42,83 +293,1130 @@ impl error::ResponseError for ServiceError {
     }
 }
 
+/// Check the request carries `Authorization: Bearer <token>`
+fn is_authorized(req: &ServiceRequest, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Compare without returning early so response timing does not leak how much of the token
+/// matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Id of the request: the one the client sent in `X-Request-Id` when it is short and plain
+/// enough to log as is, otherwise a new v1 uuid
+fn request_id(req: &ServiceRequest, node_id: &str) -> Option<header::HeaderValue> {
+    let supplied = req.headers().get(REQUEST_ID_HEADER).filter(|value| {
+        let value = value.as_bytes();
+        !value.is_empty()
+            && value.len() <= 64
+            && value
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(b))
+    });
+    match supplied {
+        Some(value) => Some(value.clone()),
+        None => crate::uuidv1(Some(node_id))
+            .ok()
+            .and_then(|id| header::HeaderValue::from_str(&id.to_string()).ok()),
+    }
+}
+
 #[get("/ping")]
-async fn ping() -> impl Responder {
-    "pong"
+async fn ping(state: State) -> error::Result<impl Responder> {
+    let state = state.read().await;
+    state.count_request("ping");
+    Ok("pong")
 }
 
 #[post("/connect")]
 async fn connect(state: State, host: web::Json<Host>) -> error::Result<impl Responder> {
-    let mut state = state
-        .lock()
-        .map_err(|_| ServiceError(500, "Unable to access app state"))?;
-    let mut host = host.into_inner();
+    let mut state = state.write().await;
+    state.count_request("connect");
+    let host = host.into_inner();
     let output = format!("connect {}: {}", &host.name, &host.wireguard_address);
 
-    let event = Event::connect(host.clone());
-    state.events.put(event.id, event);
+    // unknown hosts are joining the network and get added to it
+    let data = EventData::Connect { host: host.clone() };
+    apply_event(&mut state.network_config, &data)?;
+    state.record_event(Event::connect(host));
 
-    match state
+    Ok(output)
+}
+
+#[post("/disconnect")]
+async fn disconnect(state: State, host: web::Json<Host>) -> error::Result<impl Responder> {
+    let mut state = state.write().await;
+    state.count_request("disconnect");
+    let host = host.into_inner();
+    let output = format!("disconnect {}: {}", &host.name, &host.wireguard_address);
+
+    let entry = state
         .network_config
         .remote_hosts
         .get_mut(&host.wireguard_address)
-    {
-        Some(entry) => {
+        .ok_or(ServiceError(404, "Unknown host"))?;
+    entry.last_seen = Some(Utc::now());
+    entry.status = HostStatus::Offline;
+
+    state.record_event(Event::disconnect(host));
+
+    Ok(output)
+}
+
+#[get("/discover")]
+async fn discover(state: State) -> error::Result<impl Responder> {
+    let state = state.read().await;
+    state.count_request("discover");
+    let config = &state.network_config;
+    let roster: Vec<Host> = std::iter::once(&config.host)
+        .chain(config.remote_hosts.values())
+        .map(Host::without_secrets)
+        .collect();
+    let mut response = HttpResponse::Ok();
+    if let Some(signature) = state.sign(&roster) {
+        response.header(SIGNATURE_HEADER, signature);
+    }
+    Ok(response.json(roster))
+}
+
+#[get("/")]
+async fn info(req: HttpRequest, state: State) -> error::Result<impl Responder> {
+    let state = state.read().await;
+    state.count_request("info");
+    let etag = state.config_etag.as_str();
+    let unchanged = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        })
+        .unwrap_or(false);
+    if unchanged {
+        return Ok(HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .finish());
+    }
+    let mut response = HttpResponse::Ok();
+    if let Some(signature) = &state.config_signature {
+        response.header(SIGNATURE_HEADER, signature.as_str());
+    }
+    Ok(response
+        .header(header::ETAG, etag)
+        .content_type("application/json")
+        .body(state.config_json.clone()))
+}
+
+/// Whether a new host would take the name or any address of an existing one
+fn conflicts(config: &Config, host: &Host) -> bool {
+    let taken: Vec<IpAddr> = std::iter::once(&config.host)
+        .chain(config.remote_hosts.values())
+        .flat_map(|existing| existing.addresses())
+        .map(|address| address.addr())
+        .collect();
+    let address_taken = host
+        .addresses()
+        .iter()
+        .any(|address| taken.contains(&address.addr()));
+    let name_taken =
+        config.host.name == host.name || config.hosts_by_name().contains_key(&host.name);
+    address_taken || name_taken
+}
+
+/// Record a change made by the server in the audit log. The change has already been made, so a
+/// failing log is only reported.
+fn audit(config: &Config, operation: AuditOperation, host: &str, actor: &str) {
+    if let Err(err) = config.audit(operation, host, actor) {
+        log::error!("unable to write audit log: {}", err);
+    }
+}
+
+/// Actor of a change made through the API, by the address of the client
+fn api_actor(req: &HttpRequest) -> String {
+    format!(
+        "api:{}",
+        req.connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+    )
+}
+
+/// Status and message reported when the network config refuses a change
+fn config_error(err: Error) -> ServiceError {
+    match err {
+        Error::NameCollision(_) | Error::AddressCollision { .. } => {
+            ServiceError(409, "Host name or address already in use")
+        }
+        Error::PublicKeyCollision { .. } => ServiceError(409, "Public key already in use"),
+        Error::HostNotFound(_) => ServiceError(404, "Unknown host"),
+        Error::InvalidName { .. } => ServiceError(400, "Host name is not a valid DNS label"),
+        Error::OutOfSubnet { .. } => ServiceError(400, "Address is outside of the subnet"),
+        Error::SubnetFull(_) => ServiceError(409, "No addresses left in the subnet"),
+        err => {
+            log::error!("unable to change the network config: {}", err);
+            ServiceError(500, "Unable to change the network config")
+        }
+    }
+}
+
+/// Update the network config with the change an event describes
+fn apply_event(config: &mut Config, data: &EventData) -> Result<(), ServiceError> {
+    match data {
+        EventData::Connect { host } => {
+            let mut host = host.clone();
             host.last_seen = Some(Utc::now());
-            *entry = host;
+            host.status = HostStatus::Online;
+            match config.remote_hosts.get_mut(&host.wireguard_address) {
+                Some(entry) if entry.name == host.name => *entry = host,
+                Some(_) => return Err(ServiceError(409, "Address belongs to another host")),
+                None => {
+                    if conflicts(config, &host) {
+                        return Err(ServiceError(409, "Host name or address already in use"));
+                    }
+                    let name = host.name.clone();
+                    config.add_host(host).map_err(config_error)?;
+                    // hosts joining through the server add themselves
+                    audit(
+                        config,
+                        AuditOperation::AddHost,
+                        &name,
+                        &format!("host:{}", name),
+                    );
+                }
+            }
+        }
+        EventData::Disconnect { host } => {
+            if let Some(entry) = config.remote_hosts.get_mut(&host.wireguard_address) {
+                entry.last_seen = Some(Utc::now());
+                entry.status = HostStatus::Offline;
+            }
+        }
+        EventData::EndpointChanged { host } => {
+            if let Some(entry) = config.remote_hosts.get_mut(&host.wireguard_address) {
+                entry.resolved_endpoint = host.resolved_endpoint;
+            }
         }
-        None => {}
     }
+    Ok(())
+}
 
-    Ok(output)
+#[post("/hosts")]
+async fn create_host(
+    req: HttpRequest,
+    state: State,
+    options: web::Data<ServerOptions>,
+    host: web::Json<Host>,
+) -> error::Result<impl Responder> {
+    let host = host.into_inner();
+    {
+        let mut state = state.write().await;
+        state.count_request("create_host");
+        if conflicts(&state.network_config, &host) {
+            return Err(ServiceError(409, "Host name or address already in use").into());
+        }
+        state
+            .network_config
+            .add_host(host.clone())
+            .map_err(config_error)?;
+        audit(
+            &state.network_config,
+            AuditOperation::AddHost,
+            &host.name,
+            &api_actor(&req),
+        );
+        state.config_changed();
+    }
+    persist(&state, &options).await;
+    Ok(HttpResponse::Created().json(host.without_secrets()))
 }
 
-#[post("/disconnect")]
-async fn disconnect() -> impl Responder {
-    "disconnect"
+#[get("/hosts/{name}")]
+async fn get_host(state: State, name: web::Path<String>) -> error::Result<impl Responder> {
+    let state = state.read().await;
+    state.count_request("get_host");
+    let hosts = state.network_config.hosts_by_name();
+    let host = hosts
+        .get(name.as_str())
+        .ok_or(ServiceError(404, "Unknown host"))?;
+    Ok(web::Json(host.without_secrets()))
 }
 
-#[get("/discover")]
-async fn discover() -> impl Responder {
-    "discover"
+#[put("/hosts/{name}")]
+async fn update_host(
+    req: HttpRequest,
+    state: State,
+    options: web::Data<ServerOptions>,
+    name: web::Path<String>,
+    host: web::Json<Host>,
+) -> error::Result<impl Responder> {
+    let host = host.into_inner();
+    {
+        let mut state = state.write().await;
+        state.count_request("update_host");
+        if !state
+            .network_config
+            .hosts_by_name()
+            .contains_key(name.as_str())
+        {
+            return Err(ServiceError(404, "Unknown host").into());
+        }
+        state
+            .network_config
+            .update_host(&name, host.clone())
+            .map_err(config_error)?;
+        audit(
+            &state.network_config,
+            AuditOperation::EditHost,
+            &name,
+            &api_actor(&req),
+        );
+        state.config_changed();
+    }
+    persist(&state, &options).await;
+    Ok(web::Json(host.without_secrets()))
 }
 
-#[get("/")]
-async fn info(state: State) -> error::Result<impl Responder> {
-    let state = state
-        .lock()
-        .map_err(|_| ServiceError(500, "unable to get app state"))?;
-    Ok(web::Json(state.network_config.clone()))
+#[delete("/hosts/{name}")]
+async fn delete_host(
+    req: HttpRequest,
+    state: State,
+    options: web::Data<ServerOptions>,
+    name: web::Path<String>,
+) -> error::Result<impl Responder> {
+    let host = {
+        let mut state = state.write().await;
+        state.count_request("delete_host");
+        let host = state
+            .network_config
+            .hosts_by_name()
+            .get(name.as_str())
+            .map(|host| (*host).clone())
+            .ok_or(ServiceError(404, "Unknown host"))?;
+        state
+            .network_config
+            .remove_host_by_name(&name)
+            .map_err(config_error)?;
+        audit(
+            &state.network_config,
+            AuditOperation::RemoveHost,
+            &name,
+            &api_actor(&req),
+        );
+        state.config_changed();
+        host
+    };
+    persist(&state, &options).await;
+    Ok(web::Json(host.without_secrets()))
+}
+
+/// Save the state right away after a change made through the API. Failures are logged, the
+/// periodic save tries again later.
+async fn persist(state: &RwLock<AppState>, options: &ServerOptions) {
+    if let Err(err) = save_state(state, options).await {
+        log::error!("unable to save server state: {}", err);
+    }
 }
 
 #[post("/events")]
-async fn new_event() -> impl Responder {
-    "new_event"
+async fn new_event(state: State, event: web::Json<Event>) -> error::Result<impl Responder> {
+    let mut state = state.write().await;
+    state.count_request("new_event");
+    let event = event.into_inner();
+    if state.events.contains(&event.id) {
+        return Err(ServiceError(409, "Event already recorded").into());
+    }
+    apply_event(&mut state.network_config, &event.data)?;
+    let event = event.without_secrets();
+    state.record_event(event.clone());
+    Ok(HttpResponse::Created().json(event))
+}
+
+#[post("/events/batch")]
+async fn new_event_batch(
+    state: State,
+    events: web::Json<Vec<Event>>,
+) -> error::Result<impl Responder> {
+    let mut state = state.write().await;
+    state.count_request("new_event_batch");
+    let mut events = events.into_inner();
+    events.sort_by_key(|event| event.created_at);
+    let mut report = BatchReport::default();
+    for event in events {
+        // Events are immutable once created so a repeated id is a replay, not an update
+        if state.events.contains(&event.id)
+            || apply_event(&mut state.network_config, &event.data).is_err()
+        {
+            report.rejected.push(event.id);
+        } else {
+            report.accepted.push(event.id);
+            state.record_event(event);
+        }
+    }
+    Ok(web::Json(report))
+}
+
+/// Pagination and time window for `list_events`
+#[derive(Deserialize, Debug)]
+struct Page {
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    /// RFC 3339 timestamps bounding `created_at`, both inclusive
+    since: Option<String>,
+    until: Option<String>,
+    /// Only list events of this kind, eg. `connect`
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+/// Parse an optional RFC 3339 query parameter
+fn parse_time(value: &Option<String>) -> Result<Option<DateTime<Utc>>, ServiceError> {
+    value
+        .as_ref()
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| ServiceError(400, "Timestamps must be RFC 3339"))
+        })
+        .transpose()
 }
 
 #[get("/events")]
-async fn list_events() -> impl Responder {
-    "list_events"
+async fn list_events(state: State, page: web::Query<Page>) -> error::Result<impl Responder> {
+    let since = parse_time(&page.since)?;
+    let until = parse_time(&page.until)?;
+    let kind = page.kind.as_ref().map(|kind| kind.to_lowercase());
+    if let Some(kind) = &kind {
+        if !EventData::KINDS.contains(&kind.as_str()) {
+            return Err(ServiceError(400, "Unknown event type").into());
+        }
+    }
+    let state = state.read().await;
+    state.count_request("list_events");
+    let mut events: Vec<Event> = state
+        .events
+        .iter()
+        .map(|(_, event)| event)
+        .filter(|event| since.map(|since| event.created_at >= since).unwrap_or(true))
+        .filter(|event| until.map(|until| event.created_at <= until).unwrap_or(true))
+        .filter(|event| {
+            kind.as_ref()
+                .map(|kind| event.data.kind() == kind)
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect();
+    events.sort_by_key(|event| std::cmp::Reverse(event.created_at));
+    let events: Vec<Event> = events
+        .into_iter()
+        .skip(page.offset)
+        .take(page.limit.unwrap_or(usize::MAX))
+        .collect();
+    Ok(web::Json(events))
+}
+
+#[get("/openapi.json")]
+async fn openapi(state: State) -> error::Result<impl Responder> {
+    let state = state.read().await;
+    state.count_request("openapi");
+    Ok(web::Json(crate::openapi::document()))
+}
+
+#[get("/health")]
+async fn health(state: State) -> error::Result<impl Responder> {
+    let state = state.read().await;
+    state.count_request("health");
+    Ok(web::Json(Health {
+        version: String::from(clap::crate_version!()),
+        uptime_seconds: (Utc::now() - state.started_at).num_seconds(),
+        hosts: state.network_config.remote_hosts.len(),
+        hosts_online: state.online_hosts(),
+        events: state.events.len(),
+        event_capacity: state.events.cap(),
+    }))
+}
+
+#[get("/metrics")]
+async fn metrics(state: State) -> error::Result<impl Responder> {
+    let interface_name = state
+        .read()
+        .await
+        .network_config
+        .interface_name()
+        .to_owned();
+    // shells out to `wg`, so read before taking the lock for the rest of the request
+    let peers = web::block(move || crate::host::wg_stats(&interface_name))
+        .await
+        .unwrap_or_else(|err| {
+            log::warn!("unable to read wireguard stats: {}", err);
+            Vec::new()
+        });
+    let state = state.read().await;
+    state.count_request("metrics");
+    let render = |state: &AppState| -> Result<String, fmt::Error> {
+        let hosts = &state.network_config.remote_hosts;
+        let online = state.online_hosts();
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP wgmesh_hosts Remote hosts in the network config"
+        )?;
+        writeln!(out, "# TYPE wgmesh_hosts gauge")?;
+        writeln!(out, "wgmesh_hosts {}", hosts.len())?;
+        writeln!(
+            out,
+            "# HELP wgmesh_hosts_online Remote hosts currently online"
+        )?;
+        writeln!(out, "# TYPE wgmesh_hosts_online gauge")?;
+        writeln!(out, "wgmesh_hosts_online {}", online)?;
+        writeln!(out, "# HELP wgmesh_events Events held in the event cache")?;
+        writeln!(out, "# TYPE wgmesh_events gauge")?;
+        writeln!(out, "wgmesh_events {}", state.events.len())?;
+        writeln!(
+            out,
+            "# HELP wgmesh_requests_total Requests served by endpoint"
+        )?;
+        writeln!(out, "# TYPE wgmesh_requests_total counter")?;
+        let mut requests: Vec<_> = state
+            .requests
+            .lock()
+            .map_err(|_| fmt::Error)?
+            .iter()
+            .map(|(endpoint, count)| (*endpoint, *count))
+            .collect();
+        requests.sort();
+        for (endpoint, count) in requests {
+            writeln!(
+                out,
+                "wgmesh_requests_total{{endpoint=\"{}\"}} {}",
+                endpoint, count
+            )?;
+        }
+        let names: HashMap<&str, &str> = hosts
+            .values()
+            .map(|host| (host.public_key.as_str(), host.name.as_str()))
+            .collect();
+        // public keys are base64, host names may need escaping
+        let labels = |peer: &crate::host::PeerStat| {
+            let name = names.get(peer.public_key.as_str()).copied().unwrap_or("");
+            format!(
+                "public_key=\"{}\",host=\"{}\"",
+                peer.public_key,
+                name.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        };
+        writeln!(
+            out,
+            "# HELP wgmesh_peer_receive_bytes_total Bytes received from each wireguard peer"
+        )?;
+        writeln!(out, "# TYPE wgmesh_peer_receive_bytes_total counter")?;
+        for peer in &peers {
+            writeln!(
+                out,
+                "wgmesh_peer_receive_bytes_total{{{}}} {}",
+                labels(peer),
+                peer.transfer_rx
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP wgmesh_peer_transmit_bytes_total Bytes sent to each wireguard peer"
+        )?;
+        writeln!(out, "# TYPE wgmesh_peer_transmit_bytes_total counter")?;
+        for peer in &peers {
+            writeln!(
+                out,
+                "wgmesh_peer_transmit_bytes_total{{{}}} {}",
+                labels(peer),
+                peer.transfer_tx
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP wgmesh_peer_last_handshake_seconds Unix time of the latest handshake with \
+             each wireguard peer, 0 if there was none"
+        )?;
+        writeln!(out, "# TYPE wgmesh_peer_last_handshake_seconds gauge")?;
+        for peer in &peers {
+            writeln!(
+                out,
+                "wgmesh_peer_last_handshake_seconds{{{}}} {}",
+                labels(peer),
+                peer.latest_handshake.map(|t| t.timestamp()).unwrap_or(0)
+            )?;
+        }
+        Ok(out)
+    };
+    let body = render(&state).map_err(|_| ServiceError(500, "Unable to render metrics"))?;
+    // scraped often from close by, compressing is not worth the cpu
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .encoding(ContentEncoding::Identity)
+        .body(body))
+}
+
+#[get("/events/stream")]
+async fn stream_events(
+    state: State,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> error::Result<HttpResponse> {
+    let (session, response) = ws::start_with_addr(EventStream, &req, stream)?;
+    let mut state = state.write().await;
+    state.count_request("stream_events");
+    state.subscribers.push(session.recipient());
+    Ok(response)
+}
+
+/// Sidecar file the event cache is persisted to, next to the config. Each network of a file
+/// holding several has its own.
+fn events_path(options: &ServerOptions) -> String {
+    match &options.network {
+        Some(network) => format!("{}.{}.events.json", options.config_path, network),
+        None => format!("{}.events.json", options.config_path),
+    }
+}
+
+/// Read events persisted by `save_state`. A missing file means there are no events yet.
+fn load_events(options: &ServerOptions) -> crate::Result<Vec<Event>> {
+    match std::fs::read(events_path(options)) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write the network config and event cache to disk
+async fn save_state(state: &RwLock<AppState>, options: &ServerOptions) -> crate::Result<()> {
+    // Copy the state out so the lock is not held during disk io
+    let (network_config, mut events) = {
+        let state = state.read().await;
+        let events: Vec<Event> = state.events.iter().map(|(_, e)| e.clone()).collect();
+        (state.network_config.clone(), events)
+    };
+    events.sort_by_key(|event| event.created_at);
+    let _lock = lock_config(&options.config_path).await?;
+    network_config.save_network(&options.config_path, options.network.as_deref())?;
+    write_atomic(&events_path(options), &serde_json::to_vec(&events)?)?;
+    Ok(())
+}
+
+/// Take the config lock without blocking the runtime while another process holds it
+async fn lock_config(config_path: &str) -> crate::Result<ConfigLock> {
+    let started = std::time::Instant::now();
+    loop {
+        if let Some(lock) = ConfigLock::try_acquire(config_path)? {
+            return Ok(lock);
+        }
+        if started.elapsed() >= CONFIG_LOCK_TIMEOUT {
+            return Err(Error::LockTimeout {
+                path: config_path.to_owned(),
+                timeout: CONFIG_LOCK_TIMEOUT,
+            });
+        }
+        actix_web::rt::time::delay_for(CONFIG_LOCK_RETRY).await;
+    }
+}
+
+/// Periodically save the server state so a restart does not lose it
+async fn persist_state(state: Arc<RwLock<AppState>>, options: ServerOptions) {
+    let mut interval = actix_web::rt::time::interval(options.save_interval);
+    loop {
+        interval.tick().await;
+        if let Err(err) = save_state(&state, &options).await {
+            log::error!("unable to save server state: {}", err);
+        }
+    }
+}
+
+/// Periodically mark hosts that stopped connecting as offline and remove expired dynamic hosts
+async fn watch_heartbeats(state: Arc<RwLock<AppState>>, options: ServerOptions) {
+    let period = (options.heartbeat_timeout / 2).max(Duration::from_secs(1));
+    let mut interval = actix_web::rt::time::interval(period);
+    loop {
+        interval.tick().await;
+        let removed = {
+            let mut state = state.write().await;
+            state.expire_hosts(options.heartbeat_timeout);
+            options
+                .host_expiry
+                .map(|expiry| state.remove_expired_hosts(expiry))
+                .unwrap_or(false)
+        };
+        if removed {
+            if let Err(err) = save_state(&state, &options).await {
+                log::error!("unable to save server state: {}", err);
+            }
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT and return the name of the one received
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
 }
 
-pub async fn server(bind: SocketAddr, network_config: Config) -> std::io::Result<()> {
-    let state = Arc::new(Mutex::new(AppState {
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("ctrl-c")
+}
+
+/// Stop the server gracefully, finishing in flight requests, when the process is asked to exit
+async fn stop_on_signal(server: actix_web::dev::Server) {
+    match shutdown_signal().await {
+        Ok(reason) => log::info!("received {}, shutting down", reason),
+        Err(err) => {
+            log::error!("unable to listen for shutdown signals: {}", err);
+            return;
+        }
+    }
+    server.stop(true).await;
+}
+
+/// Periodically resolve hostname endpoints again, recording an event for each host whose
+/// endpoint now points somewhere else
+async fn resolve_endpoints(state: Arc<RwLock<AppState>>, period: Duration) {
+    let mut interval = actix_web::rt::time::interval(period);
+    loop {
+        interval.tick().await;
+        let endpoints: Vec<String> = {
+            let state = state.read().await;
+            state
+                .network_config
+                .remote_hosts
+                .values()
+                .filter_map(|host| host.endpoint_hostname().map(String::from))
+                .collect()
+        };
+        // hosts often share a name, only ask the resolver once per pass
+        let mut resolved: HashMap<String, SocketAddr> = HashMap::new();
+        for endpoint in endpoints {
+            if resolved.contains_key(&endpoint) {
+                continue;
+            }
+            let lookup = endpoint.clone();
+            let addr = web::block(move || lookup.to_socket_addrs().map(|mut addrs| addrs.next()));
+            match addr.await {
+                Ok(Some(addr)) => {
+                    resolved.insert(endpoint, addr);
+                }
+                Ok(None) => log::warn!("{} did not resolve to any address", endpoint),
+                Err(err) => log::warn!("unable to resolve {}: {}", endpoint, err),
+            }
+        }
+        state.write().await.update_resolved_endpoints(&resolved);
+    }
+}
+
+/// Settings of the API server
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub bind: SocketAddr,
+    /// Config file the network config and event cache are saved back to
+    pub config_path: String,
+    /// Network of the config file the server runs, for files holding several
+    pub network: Option<String>,
+    /// Bearer token required by every route except `/ping`
+    pub token: String,
+    /// Hosts that do not connect for this long are marked offline
+    pub heartbeat_timeout: Duration,
+    pub save_interval: Duration,
+    /// Dynamic hosts that do not connect for this long are removed. `None` keeps them forever.
+    pub host_expiry: Option<Duration>,
+    /// How often hostname endpoints are resolved again. `None` never resolves them.
+    pub resolve_interval: Option<Duration>,
+    /// Request logs are written as JSON objects when logging JSON
+    pub log_format: LogFormat,
+    /// Base64 ed25519 key signing `/` and `/discover` responses in the `X-Wgmesh-Signature`
+    /// header. `None` sends them unsigned.
+    pub signing_key: Option<String>,
+}
+
+/// Run the API server. The config and event cache are saved back to the config path every
+/// `save_interval` and on shutdown.
+pub async fn server(network_config: Config, options: ServerOptions) -> std::io::Result<()> {
+    let mut events = LruCache::new(1000);
+    match load_events(&options) {
+        Ok(saved) => {
+            for event in saved {
+                events.put(event.id, event);
+            }
+        }
+        Err(err) => log::warn!("unable to load saved events: {}", err),
+    }
+    if let Some(signing_key) = &options.signing_key {
+        let public_key = crate::signing_public_key(signing_key).map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())
+        })?;
+        log::info!(
+            "signing responses, verify them with public key {}",
+            public_key
+        );
+    }
+    // the cached config and its signature are built by `config_changed`
+    let mut state = AppState {
+        config_json: web::Bytes::new(),
+        config_etag: String::new(),
         network_config,
-        events: LruCache::new(1000),
-    }));
-    HttpServer::new(move || {
+        events,
+        subscribers: Vec::new(),
+        requests: Mutex::new(HashMap::new()),
+        started_at: Utc::now(),
+        signing_key: options.signing_key.clone(),
+        config_signature: None,
+    };
+    state.config_changed();
+    let state = Arc::new(RwLock::new(state));
+    actix_web::rt::spawn(watch_heartbeats(state.clone(), options.clone()));
+    if let Some(period) = options.resolve_interval {
+        actix_web::rt::spawn(resolve_endpoints(state.clone(), period));
+    }
+    actix_web::rt::spawn(persist_state(state.clone(), options.clone()));
+    let app_state = state.clone();
+    let app_options = options.clone();
+    let log_format = options.log_format;
+    let token = options.token.clone();
+    let node_id = crate::host::local_hostname().unwrap_or_else(|_| String::from("wgmesh"));
+    let http_server = HttpServer::new(move || {
+        let token = token.clone();
+        let node_id = node_id.clone();
         App::new()
-            .wrap(middleware::Logger::default())
-            .data(state.clone())
+            .wrap_fn(move |req, srv| {
+                let authorized = req.path() == "/ping" || is_authorized(&req, &token);
+                // rejected with a response rather than an error so the request id is still added
+                let response = if authorized {
+                    Ok(srv.call(req))
+                } else {
+                    Err(req.error_response(ServiceError(401, "Unauthorized")))
+                };
+                async move {
+                    match response {
+                        Ok(response) => response.await,
+                        Err(rejected) => Ok(rejected),
+                    }
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    let small = match response.response().body().size() {
+                        BodySize::None | BodySize::Empty => true,
+                        BodySize::Sized(size) => size < COMPRESS_MIN_SIZE,
+                        BodySize::Stream => false,
+                    };
+                    if small {
+                        response.response_mut().encoding(ContentEncoding::Identity);
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap(middleware::Compress::default())
+            // inside the logger so it sees the id, outside auth so rejected requests get one too
+            .wrap_fn(move |mut req, srv| {
+                let id = request_id(&req, &node_id);
+                if let Some(id) = &id {
+                    req.headers_mut().insert(
+                        header::HeaderName::from_static(REQUEST_ID_HEADER),
+                        id.clone(),
+                    );
+                }
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if let Some(id) = id {
+                        response
+                            .headers_mut()
+                            .insert(header::HeaderName::from_static(REQUEST_ID_HEADER), id);
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap(match log_format {
+                LogFormat::Text => middleware::Logger::new(REQUEST_LOG_TEXT_FORMAT),
+                LogFormat::Json => middleware::Logger::new(REQUEST_LOG_FORMAT),
+            })
+            .data(app_state.clone())
+            .data(app_options.clone())
             .service(info)
             .service(ping)
             .service(connect)
             .service(disconnect)
             .service(discover)
+            .service(create_host)
+            .service(get_host)
+            .service(update_host)
+            .service(delete_host)
             .service(new_event)
+            .service(new_event_batch)
             .service(list_events)
+            .service(stream_events)
+            .service(metrics)
+            .service(health)
+            .service(openapi)
     })
-    .bind(bind.to_string().as_str())?
-    .run()
-    .await
+    // signals are handled by stop_on_signal so the shutdown reason can be logged
+    .disable_signals()
+    .bind(options.bind.to_string().as_str())?
+    .run();
+    actix_web::rt::spawn(stop_on_signal(http_server.clone()));
+    http_server.await?;
+    log::info!("server stopped, saving state");
+    save_state(&state, &options)
+        .await
+        .map_err(|err| std::io::Error::other(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, host};
+
+    /// Server state for a network of alpha and the given remote hosts
+    fn state(hosts: Vec<Host>) -> AppState {
+        let mut network_config = config();
+        for host in hosts {
+            network_config.add_host(host).unwrap();
+        }
+        let mut state = AppState {
+            config_json: web::Bytes::new(),
+            config_etag: String::new(),
+            network_config,
+            events: LruCache::new(100),
+            subscribers: Vec::new(),
+            requests: Mutex::new(HashMap::new()),
+            started_at: Utc::now(),
+            signing_key: None,
+            config_signature: None,
+        };
+        state.config_changed();
+        state
+    }
+
+    /// Host last seen the given number of seconds ago
+    fn seen(mut host: Host, seconds_ago: i64) -> Host {
+        host.last_seen = Some(Utc::now() - chrono::Duration::seconds(seconds_ago));
+        host.status = HostStatus::Online;
+        host
+    }
+
+    fn host_named<'a>(state: &'a AppState, name: &str) -> Option<&'a Host> {
+        state
+            .network_config
+            .remote_hosts
+            .values()
+            .find(|host| host.name == name)
+    }
+
+    #[test]
+    fn stale_hosts_are_marked_offline() {
+        let mut state = state(vec![
+            seen(host("beta", "10.42.0.2/24"), 10),
+            seen(host("gamma", "10.42.0.3/24"), 0),
+        ]);
+
+        state.expire_hosts(Duration::from_secs(5));
+
+        assert_eq!(
+            host_named(&state, "beta").unwrap().status,
+            HostStatus::Offline
+        );
+        assert_eq!(
+            host_named(&state, "gamma").unwrap().status,
+            HostStatus::Online
+        );
+        let events: Vec<&Event> = state.events.iter().map(|(_, event)| event).collect();
+        assert_eq!(events.len(), 1);
+        // the stored host's key stays out of the event
+        assert!(matches!(
+            events[0].data(),
+            EventData::Disconnect { host } if host.name == "beta" && host.private_key.is_empty()
+        ));
+        assert!(!host_named(&state, "beta").unwrap().private_key.is_empty());
+
+        // hosts already offline are not disconnected again
+        state.expire_hosts(Duration::from_secs(5));
+        assert_eq!(state.events.len(), 1);
+    }
+
+    #[test]
+    fn expired_dynamic_hosts_are_removed_and_static_ones_kept() {
+        let dynamic = |mut host: Host| {
+            host.dynamic = true;
+            host
+        };
+        let mut state = state(vec![
+            seen(dynamic(host("beta", "10.42.0.2/24")), 600),
+            seen(dynamic(host("gamma", "10.42.0.3/24")), 10),
+            seen(host("delta", "10.42.0.4/24"), 600),
+        ]);
+
+        assert!(state.remove_expired_hosts(Duration::from_secs(60)));
+
+        assert!(host_named(&state, "beta").is_none());
+        assert!(host_named(&state, "gamma").is_some());
+        assert!(host_named(&state, "delta").is_some());
+        let events: Vec<&Event> = state.events.iter().map(|(_, event)| event).collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0].data(),
+            EventData::Disconnect { host } if host.name == "beta"
+        ));
+        // the served config no longer lists the removed host
+        let served: Config = serde_json::from_slice(&state.config_json).unwrap();
+        assert_eq!(served.remote_hosts.len(), 2);
+
+        assert!(!state.remove_expired_hosts(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn resolved_endpoints_follow_dynamic_dns() {
+        let mut beta = host("beta", "10.42.0.2/24");
+        beta.endpoint = Some(String::from("beta.example.test:51820"));
+        let mut gamma = host("gamma", "10.42.0.3/24");
+        gamma.endpoint = Some(String::from("198.51.100.3:51820"));
+        let mut state = state(vec![beta, gamma]);
+        // stub resolver answers, keyed by endpoint
+        let resolves_to = |addr: &str| -> HashMap<String, SocketAddr> {
+            vec![
+                (
+                    String::from("beta.example.test:51820"),
+                    addr.parse().unwrap(),
+                ),
+                (
+                    String::from("198.51.100.3:51820"),
+                    "192.0.2.1:1".parse().unwrap(),
+                ),
+            ]
+            .into_iter()
+            .collect()
+        };
+        let resolved = |state: &AppState| host_named(state, "beta").unwrap().resolved_endpoint;
+
+        state.update_resolved_endpoints(&resolves_to("203.0.113.5:51820"));
+        assert_eq!(resolved(&state), Some("203.0.113.5:51820".parse().unwrap()));
+        assert_eq!(state.events.len(), 1);
+
+        // an unchanged address is not an event
+        state.update_resolved_endpoints(&resolves_to("203.0.113.5:51820"));
+        assert_eq!(state.events.len(), 1);
+
+        state.update_resolved_endpoints(&resolves_to("203.0.113.9:51820"));
+        assert_eq!(resolved(&state), Some("203.0.113.9:51820".parse().unwrap()));
+        let events: Vec<&Event> = state.events.iter().map(|(_, event)| event).collect();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| matches!(
+            event.data(),
+            EventData::EndpointChanged { host }
+                if host.name == "beta" && host.private_key.is_empty()
+        )));
+        // IP endpoints are left alone
+        assert_eq!(host_named(&state, "gamma").unwrap().resolved_endpoint, None);
+        // and peers are rendered with the new address
+        let peers = state.network_config.expected_peers().unwrap();
+        assert!(peers
+            .iter()
+            .any(|peer| peer.endpoint.as_deref() == Some("203.0.113.9:51820")));
+    }
+
+    #[test]
+    fn served_config_matches_serializing_it_on_each_request() {
+        let mut state = state(vec![seen(host("beta", "10.42.0.2/24"), 30)]);
+        // what `/` used to send, serialized from the config on every request. Hosts are kept in
+        // a hash map whose order varies between serializations, so the JSON values are compared.
+        let expected = |state: &AppState| {
+            serde_json::to_value(state.network_config.without_secrets()).unwrap()
+        };
+        let served = |state: &AppState| {
+            serde_json::from_slice::<serde_json::Value>(&state.config_json).unwrap()
+        };
+        assert_eq!(served(&state), expected(&state));
+
+        let gamma = host("gamma", "10.42.0.3/24");
+        apply_event(
+            &mut state.network_config,
+            &EventData::Connect {
+                host: gamma.clone(),
+            },
+        )
+        .unwrap();
+        state.record_event(Event::connect(gamma));
+        assert_eq!(served(&state), expected(&state));
+        let served: Config = serde_json::from_slice(&state.config_json).unwrap();
+        assert_eq!(served.remote_hosts.len(), 2);
+        assert!(served
+            .remote_hosts
+            .values()
+            .all(|host| host.private_key.is_empty()));
+    }
+
+    /// Options saving to `network.yml` in the directory
+    fn options(dir: &std::path::Path) -> ServerOptions {
+        ServerOptions {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            config_path: dir.join("network.yml").to_string_lossy().into_owned(),
+            network: None,
+            token: String::from("token"),
+            heartbeat_timeout: Duration::from_secs(300),
+            save_interval: Duration::from_secs(300),
+            host_expiry: None,
+            resolve_interval: None,
+            log_format: LogFormat::Text,
+            signing_key: None,
+        }
+    }
+
+    #[test]
+    fn saved_state_loads_back() {
+        let dir = crate::tests::temp_dir("save-state");
+        let options = options(&dir);
+        let mut state = state(vec![seen(host("beta", "10.42.0.2/24"), 30)]);
+        let beta = host_named(&state, "beta").unwrap().clone();
+        let ids: Vec<Uuid> = (0..3)
+            .map(|_| {
+                let event = Event::disconnect(beta.clone());
+                let id = event.id;
+                state.record_event(event);
+                id
+            })
+            .collect();
+        let state = Arc::new(RwLock::new(state));
+
+        let saving = options.clone();
+        actix_web::rt::System::new("test")
+            .block_on(async move { save_state(&state, &saving).await })
+            .unwrap();
+
+        let loaded = Config::load_network(&options.config_path, None).unwrap();
+        let loaded_beta = loaded.hosts_by_name()["beta"].clone();
+        assert_eq!(loaded_beta.last_seen, beta.last_seen);
+        assert_eq!(loaded_beta.status, HostStatus::Online);
+        assert_eq!(loaded.local_host().name, "alpha");
+        let events: Vec<Uuid> = load_events(&options)
+            .unwrap()
+            .iter()
+            .map(Event::id)
+            .collect();
+        assert_eq!(events, ids);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
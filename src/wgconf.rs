@@ -0,0 +1,87 @@
+use crate::Error;
+
+/// `[Interface]` or `[Peer]` section of a wg-quick config
+#[derive(Debug, Clone, Default)]
+pub struct Section {
+    /// Name between the brackets, lower cased
+    pub name: String,
+    /// First comment in the section. `Config::render_config` writes the peer's name there.
+    pub comment: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl Section {
+    /// Every value given for the key, which is compared case insensitively
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Last value given for the key, like wg-quick uses
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.values(key).pop()
+    }
+
+    /// Last value given for the key, parsed
+    pub fn parsed<T: std::str::FromStr>(&self, key: &str) -> crate::Result<Option<T>> {
+        self.value(key)
+            .map(|value| {
+                value.parse().map_err(|_| Error::InvalidWgValue {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                })
+            })
+            .transpose()
+    }
+
+    /// Values of a list key such as `Address` or `AllowedIPs`, which may be comma separated,
+    /// repeated, or both
+    pub fn list(&self, key: &str) -> Vec<&str> {
+        self.values(key)
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+}
+
+/// Split a wg-quick config into its sections. Like wg-quick, everything after a `#` is a
+/// comment and whitespace around keys and values does not matter. Lines before the first
+/// section are ignored.
+pub fn parse(text: &str) -> crate::Result<Vec<Section>> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let (line, comment) = match line.split_once('#') {
+            Some((line, comment)) => (line.trim(), Some(comment.trim())),
+            None => (line.trim(), None),
+        };
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push(Section {
+                name: line[1..line.len() - 1].trim().to_lowercase(),
+                ..Section::default()
+            });
+            continue;
+        }
+        let section = match sections.last_mut() {
+            Some(section) => section,
+            None => continue,
+        };
+        if let Some(comment) = comment.filter(|comment| !comment.is_empty()) {
+            section.comment.get_or_insert_with(|| comment.to_owned());
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(Error::WgConfSyntax(number + 1))?;
+        section
+            .entries
+            .push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+    Ok(sections)
+}
@@ -0,0 +1,412 @@
+mod common;
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::time::Duration;
+
+use wgmesh::{Host, HostStatus, KeyPair};
+
+use common::{config, host, temp_path};
+
+/// Run the built binary with the given arguments, expecting it to succeed
+fn wgmesh(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_wgmesh"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "wgmesh {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn genkey_prints_a_matching_key_pair() {
+    let output = wgmesh(&["genkey", "-o", "json", "--preshared-key"]);
+
+    let keys: KeyPair = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        wgmesh::generate_public_key(&keys.private_key).unwrap(),
+        keys.public_key
+    );
+    assert_eq!(
+        base64::decode(keys.preshared_key.unwrap()).unwrap().len(),
+        32
+    );
+}
+
+#[test]
+fn genkey_prints_wg_style_lines() {
+    let output = wgmesh(&["genkey"]);
+
+    let text = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let private_key = lines[0].strip_prefix("PrivateKey = ").unwrap();
+    let public_key = lines[1].strip_prefix("PublicKey = ").unwrap();
+    assert_eq!(
+        wgmesh::generate_public_key(private_key).unwrap(),
+        public_key
+    );
+}
+
+/// Config file of a network of alpha, beta and gamma
+fn network(name: &str) -> String {
+    let path = temp_path(&format!("{}.yml", name));
+    let mut config = config();
+    config.add_host(host("gamma", "10.42.0.3/24")).unwrap();
+    config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+    config.save(path.to_str().unwrap()).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn list_hosts_prints_parseable_json() {
+    let path = network("list-hosts");
+
+    let output = wgmesh(&["-c", &path, "list-hosts", "-o", "json"]);
+
+    let hosts: Vec<Host> = serde_json::from_slice(&output.stdout).unwrap();
+    let names: Vec<&str> = hosts.iter().map(|host| host.name.as_str()).collect();
+    assert_eq!(names, vec!["beta", "gamma"]);
+    let yaml = wgmesh(&["-c", &path, "-o", "yaml", "list-hosts"]);
+    let hosts: Vec<Host> = serde_yaml::from_slice(&yaml.stdout).unwrap();
+    assert_eq!(hosts.len(), 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Directory holding a fake `wg-quick` that logs its arguments to `wg-quick.log` next to it
+/// and keeps a copy of the config it was given as `<action>.conf`. `up` fails like the real one
+/// does for an existing interface once `wg0.up` exists, `strip` prints the config unchanged.
+/// The fake `wg` next to it logs to `wg.log`, only shows the interface once it is up and keeps
+/// the config it syncs as `syncconf.conf`.
+fn fake_wg_quick(name: &str) -> std::path::PathBuf {
+    let dir = temp_path(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = format!(
+        "#!/bin/sh\n\
+         echo \"$@\" >> {dir}/wg-quick.log\n\
+         cp \"$2\" {dir}/\"$1\".conf\n\
+         if [ \"$1\" = up ] && [ -e {dir}/wg0.up ]; then\n\
+         \x20 echo 'wg-quick: `wg0'\"'\"' already exists' >&2\n\
+         \x20 exit 1\n\
+         fi\n\
+         if [ \"$1\" = strip ]; then cat \"$2\"; fi\n",
+        dir = dir.display()
+    );
+    let wg = format!(
+        "#!/bin/sh\n\
+         echo \"$@\" >> {dir}/wg.log\n\
+         if [ \"$1\" = syncconf ]; then cp \"$3\" {dir}/syncconf.conf; fi\n\
+         if [ \"$1\" = show ]; then test -e {dir}/wg0.up; fi\n",
+        dir = dir.display()
+    );
+    for (name, script) in &[("wg-quick", script), ("wg", wg)] {
+        let path = dir.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    dir
+}
+
+/// Run the binary with the fake `wg-quick` first on the PATH and its directory as temp dir
+fn wgmesh_with_fake(dir: &Path, args: &[&str]) -> Output {
+    let path = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    Command::new(env!("CARGO_BIN_EXE_wgmesh"))
+        .args(args)
+        .env("PATH", path)
+        .env("TMPDIR", dir)
+        .output()
+        .unwrap()
+}
+
+/// Action and config path of each line a fake tool logged
+fn logged(dir: &Path, log: &str) -> Vec<(String, std::path::PathBuf)> {
+    std::fs::read_to_string(dir.join(log))
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (action, _) = line.split_once(' ').unwrap();
+            let (_, path) = line.rsplit_once(' ').unwrap();
+            (action.to_owned(), path.into())
+        })
+        .collect()
+}
+
+/// Check the config was rendered to a fresh directory of its own under the temp dir, which
+/// is gone again
+fn assert_runtime_path(dir: &Path, path: &Path) {
+    let directory = path.parent().unwrap();
+    assert_eq!(directory.parent(), Some(dir));
+    assert!(directory
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("wgmesh-00000000-0000-0000-0000-000000000000-"));
+    assert!(
+        !directory.exists(),
+        "{} was left behind",
+        directory.display()
+    );
+}
+
+#[test]
+fn up_and_down_run_wg_quick_on_the_rendered_config() {
+    let config_path = network("up-down");
+    let dir = fake_wg_quick("fake-wg-quick");
+
+    let up = wgmesh_with_fake(&dir, &["-c", &config_path, "up"]);
+    let down = wgmesh_with_fake(&dir, &["-c", &config_path, "down"]);
+
+    assert!(
+        up.status.success(),
+        "{}",
+        String::from_utf8_lossy(&up.stderr)
+    );
+    assert!(down.status.success());
+    let log = logged(&dir, "wg-quick.log");
+    let actions: Vec<&str> = log.iter().map(|(action, _)| action.as_str()).collect();
+    assert_eq!(actions, ["up", "down"]);
+    for (_, path) in &log {
+        assert_runtime_path(&dir, path);
+        assert_eq!(path.file_name().unwrap(), "wg0.conf");
+    }
+    assert_ne!(log[0].1, log[1].1);
+    assert!(std::fs::read_to_string(dir.join("up.conf"))
+        .unwrap()
+        .starts_with("[Interface]\n"));
+
+    std::fs::write(dir.join("wg0.up"), "").unwrap();
+    let already_up = wgmesh_with_fake(&dir, &["-c", &config_path, "up"]);
+
+    assert!(!already_up.status.success());
+    let stderr = String::from_utf8_lossy(&already_up.stderr);
+    assert!(stderr.contains("run `wgmesh down` first"), "{}", stderr);
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// Start the server binary on a free local port, with `args` after the `server` subcommand,
+/// and wait for it to accept connections
+fn spawn_server(path: &str, args: &[&str]) -> (Child, SocketAddr) {
+    let bind = common::free_address();
+    let server = Command::new(env!("CARGO_BIN_EXE_wgmesh"))
+        .args(["-c", path])
+        .args(args)
+        .args(["--bind", &bind.to_string(), "--token", common::TOKEN])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let started = (0..100).any(|_| {
+        std::thread::sleep(Duration::from_millis(50));
+        TcpStream::connect(bind).is_ok()
+    });
+    assert!(started, "server did not start on {}", bind);
+    (server, bind)
+}
+
+/// Send the server SIGTERM and wait for it to exit. Returns its status, stdout and stderr.
+fn stop_server(mut server: Child) -> (ExitStatus, String, String) {
+    let kill = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    let status = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            server.try_wait().unwrap()
+        })
+        .unwrap_or_else(|| {
+            server.kill().unwrap();
+            panic!("server did not exit after SIGTERM");
+        });
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    server
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    server
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    (status, stdout, stderr)
+}
+
+#[test]
+fn server_saves_its_state_and_exits_on_sigterm() {
+    let path = network("server-shutdown");
+    let (server, bind) = spawn_server(&path, &["server", "--save-interval", "3600"]);
+    // only saved on shutdown, given the save interval
+    let disconnect = common::call(
+        reqwest::Client::new()
+            .post(&format!("http://{}/disconnect", bind))
+            .bearer_auth(common::TOKEN)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(&host("beta", "10.42.0.2/24")).unwrap()),
+    );
+    assert_eq!(disconnect.status, 200);
+
+    let (status, stdout, stderr) = stop_server(server);
+
+    assert!(status.success(), "{}", stderr);
+    assert!(
+        stdout.contains("received SIGTERM, shutting down"),
+        "{}",
+        stdout
+    );
+    let saved = wgmesh::Config::load_network(&path, None).unwrap();
+    assert_eq!(saved.hosts_by_name()["beta"].status, HostStatus::Offline);
+    let events = format!("{}.events.json", path);
+    let saved: Vec<wgmesh::Event> =
+        serde_json::from_slice(&std::fs::read(&events).unwrap()).unwrap();
+    assert_eq!(saved.len(), 1);
+    for file in &[path.clone(), events, format!("{}.lock", path)] {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[test]
+fn json_logs_parse_line_by_line() {
+    let path = network("json-logs");
+    let (server, bind) = spawn_server(&path, &["--log-format", "json", "server"]);
+    let ping = common::call(
+        reqwest::Client::new()
+            .get(&format!("http://{}/ping", bind))
+            .header("x-request-id", "trace-42"),
+    );
+    assert_eq!(ping.status, 200);
+
+    let (status, _, stderr) = stop_server(server);
+
+    assert!(status.success(), "{}", stderr);
+    let lines: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|_| panic!("not JSON: {}", line)))
+        .collect();
+    for line in &lines {
+        for field in &["timestamp", "level", "target", "message"] {
+            assert!(line[field].is_string(), "{} lacks {}", line, field);
+        }
+    }
+    let request = lines
+        .iter()
+        .find_map(|line| line.get("request"))
+        .expect("no request line");
+    assert_eq!(request["request"], "GET /ping HTTP/1.1");
+    assert_eq!(request["status"], 200);
+    assert_eq!(request["request_id"], "trace-42");
+    assert!(lines
+        .iter()
+        .any(|line| line["message"] == "received SIGTERM, shutting down"));
+    for file in &[path.clone(), format!("{}.lock", path)] {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+#[test]
+fn apply_syncs_a_running_interface_and_brings_up_a_stopped_one() {
+    let config_path = network("apply");
+    let dir = fake_wg_quick("fake-wg-apply");
+
+    let down = wgmesh_with_fake(&dir, &["-c", &config_path, "apply"]);
+
+    assert!(
+        down.status.success(),
+        "{}",
+        String::from_utf8_lossy(&down.stderr)
+    );
+    let log = logged(&dir, "wg-quick.log");
+    assert_eq!(log.len(), 1);
+    assert_eq!(log[0].0, "up");
+    assert_runtime_path(&dir, &log[0].1);
+
+    std::fs::write(dir.join("wg0.up"), "").unwrap();
+    std::fs::remove_file(dir.join("wg-quick.log")).unwrap();
+    let up = wgmesh_with_fake(&dir, &["-c", &config_path, "apply"]);
+
+    assert!(
+        up.status.success(),
+        "{}",
+        String::from_utf8_lossy(&up.stderr)
+    );
+    let log = logged(&dir, "wg-quick.log");
+    assert_eq!(log.len(), 1);
+    let (action, rendered) = &log[0];
+    assert_eq!(action, "strip");
+    assert_runtime_path(&dir, rendered);
+    let wg = std::fs::read_to_string(dir.join("wg.log")).unwrap();
+    assert_eq!(
+        wg.lines().last(),
+        Some(
+            format!(
+                "syncconf wg0 {}",
+                rendered.with_extension("stripped.conf").display()
+            )
+            .as_str()
+        )
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("syncconf.conf")).unwrap(),
+        std::fs::read_to_string(dir.join("strip.conf")).unwrap()
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_file(config_path).unwrap();
+}
+
+#[test]
+fn host_changes_are_appended_to_the_audit_log_in_order() {
+    let path = network("audit-cli");
+    let audit = temp_path("audit-cli.jsonl");
+    let audit_path = audit.to_str().unwrap();
+
+    wgmesh(&["-c", &path, "set-audit-log", audit_path]);
+    wgmesh(&["-c", &path, "add-host", "delta", "-a", "10.42.0.4/24"]);
+    wgmesh(&["-c", &path, "edit-host", "delta", "--mtu", "1380"]);
+    wgmesh(&["-c", &path, "remove-host", "beta"]);
+    wgmesh(&["-c", &path, "set-audit-log", "--disable"]);
+    wgmesh(&["-c", &path, "remove-host", "delta"]);
+
+    let entries: Vec<wgmesh::AuditEntry> = std::fs::read_to_string(&audit)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let recorded: Vec<(wgmesh::AuditOperation, &str)> = entries
+        .iter()
+        .map(|entry| (entry.operation, entry.host.as_str()))
+        .collect();
+    assert_eq!(
+        recorded,
+        [
+            (wgmesh::AuditOperation::AddHost, "delta"),
+            (wgmesh::AuditOperation::EditHost, "delta"),
+            (wgmesh::AuditOperation::RemoveHost, "beta"),
+        ]
+    );
+    assert!(entries
+        .windows(2)
+        .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    assert!(entries.iter().all(|entry| entry.actor.contains('@')));
+    for file in &[path.clone(), format!("{}.lock", path)] {
+        let _ = std::fs::remove_file(file);
+    }
+    std::fs::remove_file(audit).unwrap();
+}
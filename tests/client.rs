@@ -0,0 +1,125 @@
+mod common;
+
+use wgmesh::client::{join, MeshClient};
+use wgmesh::{Error, Host, HostStatus};
+
+use common::{block_on, call, config, host, options, start_server, TOKEN};
+
+/// Server for a network of alpha and beta at 10.42.0.2
+fn server(name: &str) -> String {
+    let mut config = config();
+    config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+    start_server(config, options(name))
+}
+
+#[test]
+fn join_registers_the_machine_and_peers_with_the_network() {
+    let url = server("join");
+
+    let joining = url.clone();
+    let config = block_on(async move { join(&joining, TOKEN).await }).unwrap();
+
+    let local = config.local_host();
+    assert_eq!(local.wireguard_address, "10.42.0.3/24".parse().unwrap());
+    assert_eq!(
+        wgmesh::generate_public_key(&local.private_key).unwrap(),
+        local.public_key
+    );
+    assert_eq!(config.api_token(), TOKEN);
+    let peers = config.hosts_by_name();
+    let mut names: Vec<&str> = peers.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["alpha", "beta"]);
+    assert!(peers.values().all(|peer| peer.private_key.is_empty()));
+    assert!(config
+        .render_config()
+        .unwrap()
+        .contains("Address = 10.42.0.3/24"));
+
+    // the server now knows the new host, but never saw its private key
+    let roster: Vec<Host> = serde_json::from_slice(
+        &call(
+            reqwest::Client::new()
+                .get(&format!("{}/discover", url))
+                .bearer_auth(TOKEN),
+        )
+        .body,
+    )
+    .unwrap();
+    let joined = roster
+        .iter()
+        .find(|host| host.wireguard_address == local.wireguard_address)
+        .unwrap();
+    assert_eq!(joined.public_key, local.public_key);
+    assert!(joined.private_key.is_empty());
+}
+
+#[test]
+fn join_fails_with_the_wrong_token() {
+    let url = server("join-token");
+
+    let err = block_on(async move { join(&url, "wrong-token").await }).unwrap_err();
+
+    match err {
+        Error::RetriesExhausted { source, .. } => {
+            assert!(matches!(*source, Error::Server { status: 401, .. }))
+        }
+        err => panic!("unexpected error {}", err),
+    }
+}
+
+#[test]
+fn mesh_client_talks_to_the_server() {
+    let url = server("mesh-client");
+    let gamma = host("gamma", "10.42.0.3/24");
+
+    let (info, before, after_connect, after_disconnect, events) = block_on(async move {
+        let client = MeshClient::new(&format!("{}/", url), TOKEN).unwrap();
+        client.ping().await.unwrap();
+        let info = client.info().await.unwrap();
+        let before = client.discover().await.unwrap();
+        client.connect(&gamma.without_secrets()).await.unwrap();
+        let after_connect = client.discover().await.unwrap();
+        client.disconnect(&gamma).await.unwrap();
+        let after_disconnect = client.discover().await.unwrap();
+        let events = client.list_events().await.unwrap();
+        (info, before, after_connect, after_disconnect, events)
+    });
+
+    assert_eq!(info.local_host().name, "alpha");
+    assert!(info.local_host().private_key.is_empty());
+    assert_eq!(before.len(), 2);
+    let status = |hosts: &[Host]| {
+        hosts
+            .iter()
+            .find(|host| host.name == "gamma")
+            .map(|host| host.status)
+    };
+    assert_eq!(status(&after_connect), Some(HostStatus::Online));
+    assert_eq!(status(&after_disconnect), Some(HostStatus::Offline));
+    let kinds: Vec<&str> = events.iter().map(|event| event.data().kind()).collect();
+    assert_eq!(kinds, vec!["disconnect", "connect"]);
+}
+
+#[test]
+fn mesh_client_reports_server_errors() {
+    let url = server("mesh-client-errors");
+
+    let (unauthorized, unknown) = block_on(async move {
+        let wrong = MeshClient::new(&url, "wrong-token").unwrap();
+        let client = MeshClient::new(&url, TOKEN).unwrap();
+        let unknown = client.disconnect(&host("omega", "10.42.0.9/24")).await;
+        (wrong.discover().await.unwrap_err(), unknown.unwrap_err())
+    });
+
+    for (err, expected) in [(unauthorized, 401), (unknown, 404)] {
+        match err {
+            Error::RetriesExhausted { source, .. } => assert!(
+                matches!(*source, Error::Server { status, .. } if status == expected),
+                "{:?}",
+                source
+            ),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+}
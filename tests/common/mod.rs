@@ -0,0 +1,215 @@
+//! Helpers shared by the integration tests: an in-process API server and a minimal HTTP server
+//! that records what it is sent.
+
+// each test binary uses a different part of the helpers
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use wgmesh::server::ServerOptions;
+use wgmesh::{Config, Host, LogFormat};
+
+pub const TOKEN: &str = "test-token";
+
+/// Run a future on a fresh actix system, as the CLI does
+pub fn block_on<F: std::future::Future + 'static>(future: F) -> F::Output {
+    actix::System::new("test").block_on(future)
+}
+
+/// Path under the system temp dir, unique to the test and process
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("wgmesh-it-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Remote host with a fresh key pair
+pub fn host(name: &str, address: &str) -> Host {
+    let private_key = wgmesh::generate_private_key().unwrap();
+    Host::builder()
+        .name(name)
+        .wireguard_address(address.parse().unwrap())
+        .public_key(&wgmesh::generate_public_key(&private_key).unwrap())
+        .private_key(&private_key)
+        .build()
+        .unwrap()
+}
+
+/// Network whose local host is alpha at 10.42.0.1. The local host can only be set by
+/// loading a config, so the default one is patched through its serialized form.
+pub fn config() -> Config {
+    let mut value = serde_json::to_value(Config::default()).unwrap();
+    value["host"] = serde_json::to_value(host("alpha", "10.42.0.1/24")).unwrap();
+    serde_json::from_value(value).unwrap()
+}
+
+/// Address on the loopback interface nothing listens on yet
+pub fn free_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Options of a server on a free local port saving to a temp file named after the test
+pub fn options(name: &str) -> ServerOptions {
+    ServerOptions {
+        bind: free_address(),
+        config_path: temp_path(&format!("{}.yml", name))
+            .to_string_lossy()
+            .into_owned(),
+        network: None,
+        token: String::from(TOKEN),
+        heartbeat_timeout: Duration::from_secs(300),
+        save_interval: Duration::from_secs(300),
+        host_expiry: None,
+        resolve_interval: None,
+        log_format: LogFormat::Text,
+        signing_key: None,
+    }
+}
+
+/// Run the API server on its own thread and wait for it to accept connections. Returns its
+/// base url. The server runs until the test process exits.
+pub fn start_server(config: Config, options: ServerOptions) -> String {
+    let bind = options.bind;
+    std::thread::spawn(move || {
+        actix::System::new("server")
+            .block_on(wgmesh::server::server(config, options))
+            .unwrap();
+    });
+    for _ in 0..100 {
+        if TcpStream::connect(bind).is_ok() {
+            return format!("http://{}", bind);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server did not start on {}", bind);
+}
+
+/// Status, headers and body of a response
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+/// Make a request to the test server
+pub fn call(request: reqwest::RequestBuilder) -> Response {
+    block_on(async move {
+        let response = request.send().await.unwrap();
+        Response {
+            status: response.status().as_u16(),
+            headers: response.headers().clone(),
+            body: response.bytes().await.unwrap().to_vec(),
+        }
+    })
+}
+
+/// Request received by a `MockServer`
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// HTTP server answering each connection's request with the next of a list of responses,
+/// repeating the last one once the list runs out
+pub struct MockServer {
+    pub address: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    /// Serve the given status codes and bodies in order
+    pub fn start(responses: Vec<(u16, &'static str)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let (status, body) = responses[i.min(responses.len() - 1)];
+                if let Some(request) = read_request(&stream) {
+                    recorded.lock().unwrap().push(request);
+                }
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        MockServer { address, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+    }
+    let length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
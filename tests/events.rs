@@ -0,0 +1,164 @@
+mod common;
+
+use std::time::Duration;
+
+use uuid::Uuid;
+use wgmesh::client::MeshClient;
+use wgmesh::{Config, Error, Event, EventData, HostStatus, RetryPolicy};
+
+use common::{block_on, config, host, options, start_server, MockServer, TOKEN};
+
+#[test]
+fn send_posts_the_event_as_json() {
+    let server = MockServer::start(vec![(201, "")]);
+    let event = Event::connect(host("beta", "10.42.0.2/24"));
+    let id = event.id();
+
+    let address = server.address.to_string();
+    block_on(async move { event.send(&address, TOKEN).await }).unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    let request = &requests[0];
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/events");
+    assert_eq!(
+        request.header("authorization"),
+        Some(format!("Bearer {}", TOKEN).as_str())
+    );
+    let posted: Event = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(posted.id(), id);
+    match posted.data() {
+        EventData::Connect { host } => assert_eq!(host.name, "beta"),
+        data => panic!("unexpected event data {:?}", data),
+    }
+}
+
+#[test]
+fn send_reports_the_status_and_body_of_a_failure() {
+    let server = MockServer::start(vec![(500, "broken")]);
+    let event = Event::connect(host("beta", "10.42.0.2/24"));
+
+    let address = server.address.to_string();
+    let err = block_on(async move { event.send(&address, TOKEN).await }).unwrap_err();
+
+    match err {
+        Error::RetriesExhausted {
+            attempts, source, ..
+        } => {
+            assert_eq!(attempts, 1);
+            assert!(matches!(
+                *source,
+                Error::Server { status: 500, ref body, .. } if body == "broken"
+            ));
+        }
+        err => panic!("unexpected error {}", err),
+    }
+}
+
+#[test]
+fn send_with_retry_succeeds_after_transient_failures() {
+    let server = MockServer::start(vec![(503, ""), (503, ""), (201, "")]);
+    let event = Event::connect(host("beta", "10.42.0.2/24"));
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(20),
+    };
+
+    let address = server.address.to_string();
+    block_on(async move { event.send_with_retry(&address, TOKEN, policy).await }).unwrap();
+
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn send_with_retry_reports_the_attempts_made() {
+    let server = MockServer::start(vec![(503, "")]);
+    let event = Event::connect(host("beta", "10.42.0.2/24"));
+    let policy = RetryPolicy {
+        max_attempts: 2,
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(20),
+    };
+
+    let address = server.address.to_string();
+    let err =
+        block_on(async move { event.send_with_retry(&address, TOKEN, policy).await }).unwrap_err();
+
+    assert!(matches!(err, Error::RetriesExhausted { attempts: 2, .. }));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn batch_is_recorded_by_the_server() {
+    let url = start_server(Config::default(), options("batch"));
+    let events = vec![
+        Event::connect(host("beta", "10.42.0.2/24")),
+        Event::connect(host("gamma", "10.42.0.3/24")),
+        Event::disconnect(host("beta", "10.42.0.2/24")),
+    ];
+    let ids: Vec<Uuid> = events.iter().map(Event::id).collect();
+
+    let address = url.trim_start_matches("http://").to_owned();
+    let (report, recorded) = block_on(async move {
+        let report = Event::send_batch(&events, &address, TOKEN).await?;
+        let client = MeshClient::new(&format!("http://{}", address), TOKEN)?;
+        Ok::<_, Error>((report, client.list_events().await?))
+    })
+    .unwrap();
+
+    assert!(report.is_complete());
+    assert_eq!(report.accepted, ids);
+    let mut recorded: Vec<Uuid> = recorded.iter().map(Event::id).collect();
+    recorded.reverse();
+    assert_eq!(recorded, ids);
+}
+
+#[test]
+fn batch_rejects_replayed_and_conflicting_events() {
+    let url = start_server(Config::default(), options("batch-rejected"));
+    let first = Event::connect(host("beta", "10.42.0.2/24"));
+    let replayed = first.clone();
+    // same name as beta at another address
+    let conflicting = Event::connect(host("beta", "10.42.0.3/24"));
+    let accepted = Event::connect(host("gamma", "10.42.0.4/24"));
+    let expected_accepted = vec![accepted.id()];
+    let expected_rejected = vec![replayed.id(), conflicting.id()];
+
+    let address = url.trim_start_matches("http://").to_owned();
+    let report = block_on(async move {
+        first.send(&address, TOKEN).await?;
+        Event::send_batch(&[replayed, conflicting, accepted], &address, TOKEN).await
+    })
+    .unwrap();
+
+    assert!(!report.is_complete());
+    assert_eq!(report.accepted, expected_accepted);
+    assert_eq!(report.rejected, expected_rejected);
+}
+
+#[test]
+fn sent_event_is_applied_by_the_server_once() {
+    let url = start_server(config(), options("new-event"));
+    let event = Event::connect(host("gamma", "10.42.0.3/24"));
+    let replayed = event.clone();
+
+    let address = url.trim_start_matches("http://").to_owned();
+    let (replay, hosts) = block_on(async move {
+        event.send(&address, TOKEN).await?;
+        let replay = replayed.send(&address, TOKEN).await;
+        let client = MeshClient::new(&format!("http://{}", address), TOKEN)?;
+        Ok::<_, Error>((replay, client.discover().await?))
+    })
+    .unwrap();
+
+    let gamma = hosts.iter().find(|host| host.name == "gamma").unwrap();
+    assert_eq!(gamma.status, HostStatus::Online);
+    match replay.unwrap_err() {
+        Error::RetriesExhausted { source, .. } => {
+            assert!(matches!(*source, Error::Server { status: 409, .. }))
+        }
+        err => panic!("unexpected error {}", err),
+    }
+}
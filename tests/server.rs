@@ -0,0 +1,727 @@
+mod common;
+
+use std::time::Duration;
+
+use actix_web::client::Client;
+use actix_web::rt::time::timeout;
+use actix_web_actors::ws::Frame;
+use futures_util::StreamExt;
+use uuid::Uuid;
+use wgmesh::client::MeshClient;
+use wgmesh::server::{Health, SIGNATURE_HEADER};
+use wgmesh::{Event, EventData, Host, HostStatus};
+
+use common::{block_on, call, config, host, options, start_server, TOKEN};
+
+/// Server for a network of alpha and beta at 10.42.0.2
+fn server(name: &str) -> String {
+    let mut config = config();
+    config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+    start_server(config, options(name))
+}
+
+fn post(url: &str, path: &str, body: &impl serde::Serialize) -> common::Response {
+    call(
+        reqwest::Client::new()
+            .post(&format!("{}{}", url, path))
+            .bearer_auth(TOKEN)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(body).unwrap()),
+    )
+}
+
+fn get(url: &str, path: &str) -> common::Response {
+    call(
+        reqwest::Client::new()
+            .get(&format!("{}{}", url, path))
+            .bearer_auth(TOKEN),
+    )
+}
+
+#[test]
+fn disconnect_marks_known_hosts_offline() {
+    let url = server("disconnect");
+
+    let known = post(&url, "/disconnect", &host("beta", "10.42.0.2/24"));
+    let unknown = post(&url, "/disconnect", &host("gamma", "10.42.0.3/24"));
+
+    assert_eq!(known.status, 200);
+    assert_eq!(
+        String::from_utf8_lossy(&known.body),
+        "disconnect beta: 10.42.0.2/24"
+    );
+    assert_eq!(unknown.status, 404);
+    let hosts: Vec<Host> = get(&url, "/discover").json();
+    let beta = hosts.iter().find(|host| host.name == "beta").unwrap();
+    assert_eq!(beta.status, HostStatus::Offline);
+    assert!(beta.last_seen.is_some());
+}
+
+#[test]
+fn connect_adds_a_new_host_to_the_network() {
+    let url = server("connect-new");
+    let gamma = host("gamma", "10.42.0.3/24").without_secrets();
+
+    let response = post(&url, "/connect", &gamma);
+
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        String::from_utf8_lossy(&response.body),
+        "connect gamma: 10.42.0.3/24"
+    );
+    let hosts: Vec<Host> = get(&url, "/discover").json();
+    let added = hosts.iter().find(|host| host.name == "gamma").unwrap();
+    assert_eq!(added.wireguard_address, gamma.wireguard_address);
+    assert_eq!(added.public_key, gamma.public_key);
+    assert_eq!(added.status, HostStatus::Online);
+    assert!(added.last_seen.is_some());
+    let events: Vec<Event> = get(&url, "/events?type=connect").json();
+    assert_eq!(events.len(), 1);
+
+    // connecting again is a heartbeat, not a conflict
+    assert_eq!(post(&url, "/connect", &gamma).status, 200);
+    // but another host can not take its name or address
+    let same_name = host("gamma", "10.42.0.4/24");
+    let same_address = host("delta", "10.42.0.3/24");
+    assert_eq!(post(&url, "/connect", &same_name).status, 409);
+    assert_eq!(post(&url, "/connect", &same_address).status, 409);
+    let hosts: Vec<Host> = get(&url, "/discover").json();
+    assert_eq!(hosts.len(), 3);
+}
+
+#[test]
+fn info_is_not_sent_again_until_the_config_changes() {
+    let url = server("etag");
+    let get_if_none_match = |etag: &str| {
+        call(
+            reqwest::Client::new()
+                .get(&url)
+                .bearer_auth(TOKEN)
+                .header("if-none-match", etag),
+        )
+    };
+
+    let first = get(&url, "/");
+    assert_eq!(first.status, 200);
+    let etag = first.header("etag").unwrap().to_owned();
+    assert!(etag.starts_with('"') && etag.ends_with('"'), "{}", etag);
+    assert_eq!(get(&url, "/").header("etag"), Some(etag.as_str()));
+
+    let cached = get_if_none_match(&etag);
+    assert_eq!(cached.status, 304);
+    assert!(cached.body.is_empty());
+    assert_eq!(cached.header("etag"), Some(etag.as_str()));
+    assert_eq!(
+        get_if_none_match(&format!("\"stale\", {}", etag)).status,
+        304
+    );
+    assert_eq!(get_if_none_match("\"stale\"").status, 200);
+
+    // any change to the network busts the cache
+    let gamma = host("gamma", "10.42.0.3/24").without_secrets();
+    assert_eq!(post(&url, "/connect", &gamma).status, 200);
+    let changed = get_if_none_match(&etag);
+    assert_eq!(changed.status, 200);
+    let new_etag = changed.header("etag").unwrap();
+    assert_ne!(new_etag, etag);
+    assert_eq!(get_if_none_match(new_etag).status, 304);
+}
+
+#[test]
+fn discover_lists_every_host_without_secrets() {
+    let url = server("discover");
+
+    let response = get(&url, "/discover");
+
+    assert_eq!(response.status, 200);
+    let hosts: Vec<Host> = response.json();
+    let mut names: Vec<&str> = hosts.iter().map(|host| host.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["alpha", "beta"]);
+    assert!(hosts.iter().all(|host| host.private_key.is_empty()));
+    assert!(hosts.iter().all(|host| !host.public_key.is_empty()));
+}
+
+#[test]
+fn responses_never_contain_private_keys() {
+    let mut config = config();
+    config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+    let beta = config.hosts_by_name()["beta"].clone();
+    let private_keys = vec![config.local_host().private_key.clone(), beta.private_key];
+    let url = start_server(config, options("private-keys"));
+
+    for path in &["/", "/discover", "/hosts/beta"] {
+        let response = get(&url, path);
+        assert_eq!(response.status, 200, "{}", path);
+        let body = String::from_utf8(response.body).unwrap();
+        for key in &private_keys {
+            assert!(
+                !body.contains(key.as_str()),
+                "{} exposes a private key",
+                path
+            );
+        }
+    }
+}
+
+#[test]
+fn list_events_pages_newest_first() {
+    let url = server("list-events");
+    let events: Vec<Event> = (0..3)
+        .map(|_| Event::disconnect(host("beta", "10.42.0.2/24")))
+        .collect();
+    for event in &events {
+        assert_eq!(post(&url, "/events", event).status, 201);
+    }
+    let newest_first: Vec<Uuid> = events.iter().rev().map(Event::id).collect();
+    let page = |query: &str| -> Vec<Uuid> {
+        let events: Vec<Event> = get(&url, &format!("/events{}", query)).json();
+        events.iter().map(Event::id).collect()
+    };
+
+    assert_eq!(page(""), newest_first);
+    assert_eq!(page("?limit=2"), newest_first[..2]);
+    assert_eq!(page("?offset=1&limit=1"), newest_first[1..2]);
+    assert_eq!(page("?offset=2&limit=5"), newest_first[2..]);
+    assert!(page("?offset=3").is_empty());
+    assert!(page("?offset=10&limit=1").is_empty());
+    assert!(page("?limit=0").is_empty());
+}
+
+/// Disconnect event for beta created at the given RFC 3339 time
+fn event_at(created_at: &str) -> serde_json::Value {
+    let mut event = serde_json::to_value(Event::disconnect(host("beta", "10.42.0.2/24"))).unwrap();
+    event["created_at"] = serde_json::Value::from(created_at);
+    event
+}
+
+#[test]
+fn list_events_filters_by_an_inclusive_time_window() {
+    let url = server("list-events-window");
+    let times = [
+        "2021-01-01T10:00:00Z",
+        "2021-01-01T11:00:00Z",
+        "2021-01-01T12:00:00Z",
+        "2021-01-01T13:00:00Z",
+    ];
+    for time in &times {
+        assert_eq!(post(&url, "/events", &event_at(time)).status, 201);
+    }
+    let window = |query: &str| -> Vec<String> {
+        let events: Vec<Event> = get(&url, &format!("/events{}", query)).json();
+        events
+            .iter()
+            .map(|event| event.created_at().to_rfc3339())
+            .collect()
+    };
+
+    assert_eq!(
+        window("?since=2021-01-01T11:00:00Z&until=2021-01-01T12:00:00Z"),
+        vec!["2021-01-01T12:00:00+00:00", "2021-01-01T11:00:00+00:00"]
+    );
+    assert_eq!(window("?since=2021-01-01T12:00:00Z").len(), 2);
+    assert_eq!(window("?until=2021-01-01T10:00:00Z").len(), 1);
+    // other offsets name the same instant
+    assert_eq!(
+        window("?since=2021-01-01T14:00:00%2B01:00&until=2021-01-01T14:00:00%2B01:00"),
+        vec!["2021-01-01T13:00:00+00:00"]
+    );
+    assert_eq!(
+        window("?since=2021-01-01T11:00:00Z&limit=1&offset=1"),
+        vec!["2021-01-01T12:00:00+00:00"]
+    );
+    assert!(window("?since=2021-01-01T13:00:01Z").is_empty());
+
+    for query in &[
+        "?since=yesterday",
+        "?until=2021-01-01",
+        "?since=2021-13-01T00:00:00Z",
+        "?until=",
+    ] {
+        assert_eq!(
+            get(&url, &format!("/events{}", query)).status,
+            400,
+            "{}",
+            query
+        );
+    }
+}
+
+#[test]
+fn list_events_filters_by_type() {
+    let url = server("list-events-type");
+    let beta = host("beta", "10.42.0.2/24");
+    let events = vec![
+        Event::connect(beta.clone()),
+        Event::disconnect(beta.clone()),
+        Event::connect(beta.clone()),
+        Event::disconnect(beta),
+    ];
+    for event in &events {
+        assert_eq!(post(&url, "/events", event).status, 201);
+    }
+    let ids = |query: &str| -> Vec<Uuid> {
+        let events: Vec<Event> = get(&url, &format!("/events{}", query)).json();
+        events.iter().map(Event::id).collect()
+    };
+
+    assert_eq!(ids("?type=connect"), vec![events[2].id(), events[0].id()]);
+    assert_eq!(
+        ids("?type=Disconnect"),
+        vec![events[3].id(), events[1].id()]
+    );
+    assert_eq!(ids("?type=DISCONNECT&limit=1"), vec![events[3].id()]);
+    assert!(ids("?type=endpoint_changed").is_empty());
+    assert_eq!(get(&url, "/events?type=reboot").status, 400);
+    assert_eq!(get(&url, "/events?type=").status, 400);
+}
+
+#[test]
+fn requests_need_the_bearer_token_except_ping() {
+    let url = server("auth");
+    let client = reqwest::Client::new();
+
+    let authorized = get(&url, "/");
+    let missing = call(client.get(&url));
+    let wrong = call(client.get(&url).bearer_auth("wrong-token"));
+    let basic = call(client.get(&url).basic_auth("user", Some(TOKEN)));
+    let ping = call(client.get(&format!("{}/ping", url)));
+
+    assert_eq!(authorized.status, 200);
+    assert_eq!(missing.status, 401);
+    assert_eq!(wrong.status, 401);
+    assert_eq!(basic.status, 401);
+    assert_eq!(ping.status, 200);
+    assert_eq!(ping.body, b"pong");
+}
+
+#[test]
+fn event_stream_pushes_recorded_events() {
+    let url = server("stream");
+
+    let event = block_on(async move {
+        let (_, mut frames) = Client::new()
+            .ws(format!("{}/events/stream", url))
+            .bearer_auth(TOKEN)
+            .connect()
+            .await
+            .unwrap();
+        MeshClient::new(&url, TOKEN)
+            .unwrap()
+            .connect(&host("gamma", "10.42.0.3/24"))
+            .await
+            .unwrap();
+        let frame = timeout(Duration::from_secs(5), frames.next())
+            .await
+            .expect("no event streamed")
+            .unwrap()
+            .unwrap();
+        match frame {
+            Frame::Text(text) => serde_json::from_slice::<Event>(&text).unwrap(),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    });
+
+    match event.data() {
+        EventData::Connect { host } => assert_eq!(host.name, "gamma"),
+        data => panic!("unexpected event data {:?}", data),
+    }
+}
+
+#[test]
+fn metrics_reflect_connected_hosts() {
+    let url = server("metrics");
+    let client = MeshClient::new(&url, TOKEN).unwrap();
+    block_on(async move {
+        client.connect(&host("beta", "10.42.0.2/24")).await?;
+        client.connect(&host("gamma", "10.42.0.3/24")).await
+    })
+    .unwrap();
+
+    let response = get(&url, "/metrics");
+
+    assert_eq!(response.status, 200);
+    let body = String::from_utf8(response.body).unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    assert!(lines.contains(&"wgmesh_hosts 2"));
+    assert!(lines.contains(&"wgmesh_hosts_online 2"));
+    assert!(lines.contains(&"wgmesh_events 2"));
+    assert!(lines.contains(&"wgmesh_requests_total{endpoint=\"connect\"} 2"));
+}
+
+#[test]
+fn health_reports_the_version_and_hosts() {
+    let url = server("health");
+
+    let response = get(&url, "/health");
+
+    assert_eq!(response.status, 200);
+    let health: Health = response.json();
+    assert_eq!(health.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(health.hosts, 1);
+    assert_eq!(health.hosts_online, 0);
+    assert_eq!(health.events, 0);
+    assert!(health.event_capacity > 0);
+    assert!(health.uptime_seconds >= 0);
+}
+
+#[test]
+fn concurrent_reads_do_not_deadlock() {
+    let url = server("concurrent");
+    let (done, finished) = std::sync::mpsc::channel();
+    for i in 0..32 {
+        let url = url.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            // a few writers among the readers
+            let status = if i % 8 == 0 {
+                let address = format!("10.42.0.{}/24", 10 + i);
+                post(&url, "/connect", &host(&format!("host{}", i), &address)).status
+            } else {
+                get(&url, if i % 2 == 0 { "/" } else { "/discover" }).status
+            };
+            done.send(status).unwrap();
+        });
+    }
+
+    for _ in 0..32 {
+        let status = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(status, 200);
+    }
+}
+
+#[test]
+fn hosts_can_be_managed_over_http() {
+    let options = options("hosts-crud");
+    let config_path = options.config_path.clone();
+    let url = start_server(config(), options);
+    let send = |method: reqwest::Method, path: &str, body: Option<&Host>| {
+        let mut request = reqwest::Client::new()
+            .request(method, &format!("{}{}", url, path))
+            .bearer_auth(TOKEN);
+        if let Some(body) = body {
+            request = request
+                .header("content-type", "application/json")
+                .body(serde_json::to_vec(body).unwrap());
+        }
+        call(request)
+    };
+    let saved = || wgmesh::Config::load_network(&config_path, None).unwrap();
+    let beta = host("beta", "10.42.0.2/24");
+
+    let created = send(reqwest::Method::POST, "/hosts", Some(&beta));
+    assert_eq!(created.status, 201);
+    assert!(created.json::<Host>().private_key.is_empty());
+    assert!(saved().hosts_by_name().contains_key("beta"));
+    let taken_name = host("beta", "10.42.0.3/24");
+    let taken_address = host("gamma", "10.42.0.2/24");
+    assert_eq!(
+        send(reqwest::Method::POST, "/hosts", Some(&taken_name)).status,
+        409
+    );
+    assert_eq!(
+        send(reqwest::Method::POST, "/hosts", Some(&taken_address)).status,
+        409
+    );
+
+    let fetched = get(&url, "/hosts/beta");
+    assert_eq!(fetched.status, 200);
+    assert_eq!(fetched.json::<Host>().public_key, beta.public_key);
+    assert_eq!(get(&url, "/hosts/gamma").status, 404);
+
+    let mut moved = beta.clone();
+    moved.wireguard_address = "10.42.0.5/24".parse().unwrap();
+    moved.persistent_keepalive = Some(25);
+    assert_eq!(
+        send(reqwest::Method::PUT, "/hosts/beta", Some(&moved)).status,
+        200
+    );
+    let updated: Host = get(&url, "/hosts/beta").json();
+    assert_eq!(updated.wireguard_address, moved.wireguard_address);
+    assert_eq!(updated.persistent_keepalive, Some(25));
+    assert_eq!(
+        saved().hosts_by_name()["beta"].wireguard_address,
+        moved.wireguard_address
+    );
+    assert_eq!(
+        send(reqwest::Method::PUT, "/hosts/gamma", Some(&moved)).status,
+        404
+    );
+    let gamma = host("gamma", "10.42.0.3/24");
+    assert_eq!(
+        send(reqwest::Method::POST, "/hosts", Some(&gamma)).status,
+        201
+    );
+    let mut clash = gamma.clone();
+    clash.wireguard_address = moved.wireguard_address;
+    assert_eq!(
+        send(reqwest::Method::PUT, "/hosts/gamma", Some(&clash)).status,
+        409
+    );
+
+    let deleted = send(reqwest::Method::DELETE, "/hosts/beta", None);
+    assert_eq!(deleted.status, 200);
+    assert_eq!(deleted.json::<Host>().name, "beta");
+    assert_eq!(get(&url, "/hosts/beta").status, 404);
+    assert_eq!(
+        send(reqwest::Method::DELETE, "/hosts/beta", None).status,
+        404
+    );
+    let remaining: Vec<String> = saved().hosts_by_name().keys().cloned().collect();
+    assert_eq!(remaining, vec!["gamma"]);
+}
+
+#[test]
+fn openapi_document_lists_every_route() {
+    let url = server("openapi");
+
+    let response = get(&url, "/openapi.json");
+
+    assert_eq!(response.status, 200);
+    let document: serde_json::Value = response.json();
+    assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+    assert_eq!(document["info"]["version"], env!("CARGO_PKG_VERSION"));
+    let mut paths: Vec<&str> = document["paths"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        [
+            "/",
+            "/connect",
+            "/disconnect",
+            "/discover",
+            "/events",
+            "/events/batch",
+            "/events/stream",
+            "/health",
+            "/hosts",
+            "/hosts/{name}",
+            "/metrics",
+            "/openapi.json",
+            "/ping",
+        ]
+    );
+    assert!(document["components"]["securitySchemes"]["bearer"].is_object());
+}
+
+#[test]
+fn large_responses_are_gzipped_and_small_ones_and_metrics_are_not() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let mut config = config();
+    for i in 2..20 {
+        config
+            .add_host(host(&format!("host{}", i), &format!("10.42.0.{}/24", i)))
+            .unwrap();
+    }
+    let url = start_server(config, options("gzip"));
+    let gzipped = |path: &str| {
+        call(
+            reqwest::Client::new()
+                .get(&format!("{}{}", url, path))
+                .bearer_auth(TOKEN)
+                .header("accept-encoding", "gzip"),
+        )
+    };
+
+    let compressed = gzipped("/");
+    let plain = get(&url, "/");
+    let ping = gzipped("/ping");
+    let metrics = gzipped("/metrics");
+
+    assert_eq!(compressed.status, 200);
+    assert_eq!(compressed.header("content-encoding"), Some("gzip"));
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&compressed.body[..])
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    assert!(compressed.body.len() < decompressed.len());
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&decompressed).unwrap(),
+        plain.json::<serde_json::Value>()
+    );
+    assert_eq!(ping.status, 200);
+    assert_eq!(ping.header("content-encoding"), None);
+    assert_eq!(metrics.status, 200);
+    assert_ne!(metrics.header("content-encoding"), Some("gzip"));
+}
+
+#[test]
+fn responses_carry_a_request_id_echoing_the_clients() {
+    let url = server("request-id");
+    let with_id = |id: &str| {
+        call(
+            reqwest::Client::new()
+                .get(&format!("{}/ping", url))
+                .header("x-request-id", id),
+        )
+    };
+
+    let generated = get(&url, "/ping");
+    let other = get(&url, "/ping");
+    let supplied = with_id("trace-42");
+    let unsafe_id = with_id("has \"quotes\"");
+
+    let generated = generated.header("x-request-id").unwrap();
+    assert!(Uuid::parse_str(generated).is_ok(), "{}", generated);
+    assert_ne!(Some(generated), other.header("x-request-id"));
+    assert_eq!(supplied.header("x-request-id"), Some("trace-42"));
+    // ids that could break the log line are replaced
+    let replaced = unsafe_id.header("x-request-id").unwrap();
+    assert!(Uuid::parse_str(replaced).is_ok(), "{}", replaced);
+}
+
+#[test]
+fn config_and_host_list_are_signed_when_a_key_is_given() {
+    let mut config = config();
+    config.add_host(host("beta", "10.42.0.2/24")).unwrap();
+    let signing_key = wgmesh::generate_signing_key();
+    let public_key = wgmesh::signing_public_key(&signing_key).unwrap();
+    let mut signed_options = options("signed");
+    signed_options.signing_key = Some(signing_key);
+    let url = start_server(config, signed_options);
+    let unsigned = server("unsigned");
+
+    let info = get(&url, "/");
+    let discover = get(&url, "/discover");
+
+    let served: wgmesh::Config = info.json();
+    let signature = info.header(SIGNATURE_HEADER).unwrap();
+    assert!(served.verify(signature, &public_key));
+    let hosts: Vec<Host> = discover.json();
+    let signature = discover.header(SIGNATURE_HEADER).unwrap();
+    assert!(wgmesh::verify_canonical(&hosts, signature, &public_key));
+    let mut reordered = hosts.clone();
+    reordered.reverse();
+    assert!(!wgmesh::verify_canonical(
+        &reordered,
+        signature,
+        &public_key
+    ));
+    assert_eq!(get(&unsigned, "/").header(SIGNATURE_HEADER), None);
+    assert_eq!(get(&unsigned, "/discover").header(SIGNATURE_HEADER), None);
+}
+
+#[test]
+fn api_changes_are_audited_with_their_actor() {
+    let audit = common::temp_path("audit-api.jsonl");
+    let mut config = config();
+    config.set_audit_log(Some(audit.clone()));
+    let url = start_server(config, options("audit-api"));
+    let send = |method: reqwest::Method, path: &str, body: &Host| {
+        call(
+            reqwest::Client::new()
+                .request(method, &format!("{}{}", url, path))
+                .bearer_auth(TOKEN)
+                .header("content-type", "application/json")
+                .body(serde_json::to_vec(body).unwrap()),
+        )
+    };
+    let beta = host("beta", "10.42.0.2/24");
+    let mut edited = beta.clone();
+    edited.mtu = Some(1380);
+
+    assert_eq!(send(reqwest::Method::POST, "/hosts", &beta).status, 201);
+    assert_eq!(
+        send(reqwest::Method::PUT, "/hosts/beta", &edited).status,
+        200
+    );
+    assert_eq!(
+        post(&url, "/connect", &host("gamma", "10.42.0.3/24")).status,
+        200
+    );
+    let deleted = call(
+        reqwest::Client::new()
+            .delete(&format!("{}/hosts/beta", url))
+            .bearer_auth(TOKEN),
+    );
+    assert!(deleted.status < 300, "{}", deleted.status);
+
+    let entries: Vec<wgmesh::AuditEntry> = std::fs::read_to_string(&audit)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // clients are recorded by address and port
+    let recorded: Vec<(wgmesh::AuditOperation, &str, &str)> = entries
+        .iter()
+        .map(|entry| {
+            let actor = match entry.actor.strip_prefix("api:127.0.0.1:") {
+                Some(_) => "api:127.0.0.1",
+                None => entry.actor.as_str(),
+            };
+            (entry.operation, entry.host.as_str(), actor)
+        })
+        .collect();
+    assert_eq!(
+        recorded,
+        [
+            (wgmesh::AuditOperation::AddHost, "beta", "api:127.0.0.1"),
+            (wgmesh::AuditOperation::EditHost, "beta", "api:127.0.0.1"),
+            (wgmesh::AuditOperation::AddHost, "gamma", "host:gamma"),
+            (wgmesh::AuditOperation::RemoveHost, "beta", "api:127.0.0.1"),
+        ]
+    );
+    std::fs::remove_file(audit).unwrap();
+}
+
+#[test]
+fn events_never_contain_private_keys() {
+    let url = server("event-keys");
+    let mut gamma = host("gamma", "10.42.0.3/24");
+    gamma.preshared_key = Some(wgmesh::generate_private_key().unwrap());
+    let beta = host("beta", "10.42.0.2/24");
+    let delta = host("delta", "10.42.0.4/24");
+    let secrets = [
+        gamma.private_key.clone(),
+        gamma.preshared_key.clone().unwrap(),
+        beta.private_key.clone(),
+        delta.private_key.clone(),
+    ];
+    let assert_no_secrets = |what: &str, body: &[u8]| {
+        let body = String::from_utf8_lossy(body);
+        for secret in &secrets {
+            assert!(!body.contains(secret.as_str()), "{} exposes a key", what);
+        }
+    };
+
+    let stream_url = url.clone();
+    let streamed = block_on(async move {
+        let (_, mut frames) = Client::new()
+            .ws(format!("{}/events/stream", stream_url))
+            .bearer_auth(TOKEN)
+            .connect()
+            .await
+            .unwrap();
+        MeshClient::new(&stream_url, TOKEN)
+            .unwrap()
+            .connect(&gamma)
+            .await
+            .unwrap();
+        match timeout(Duration::from_secs(5), frames.next())
+            .await
+            .expect("no event streamed")
+            .unwrap()
+            .unwrap()
+        {
+            Frame::Text(text) => text,
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    });
+    let disconnected = post(&url, "/disconnect", &beta);
+    let created = post(&url, "/events", &Event::connect(delta));
+
+    assert_eq!(disconnected.status, 200);
+    assert_eq!(created.status, 201);
+    assert_no_secrets("the event stream", &streamed);
+    assert_no_secrets("POST /events", &created.body);
+    let listed = get(&url, "/events");
+    assert_eq!(listed.json::<Vec<Event>>().len(), 3);
+    assert_no_secrets("GET /events", &listed.body);
+}